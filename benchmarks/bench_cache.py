@@ -0,0 +1,32 @@
+"""Push/pop throughput of transaction cache: single global lock vs sharded cache.
+
+Usage: python benchmarks/bench_cache.py [--threads 1,2,4,8,16] [--iterations 200000]
+
+The core must be built with bench feature: PAMAGENT_BENCH=1 python setup.py develop
+"""
+import argparse
+
+# noinspection PyUnresolvedReferences
+from pamagent import pamagent_core
+
+SINGLE_LOCK = 1
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__, formatter_class=argparse.RawDescriptionHelpFormatter)
+    parser.add_argument("--threads", default="1,2,4,8,16", help="Comma separated counts of threads")
+    parser.add_argument("--iterations", type=int, default=200000, help="Push/pop pairs per thread")
+    parser.add_argument("--shards", type=int, default=None, help="Shards of sharded cache")
+    args = parser.parse_args()
+
+    print("{:>8} {:>16} {:>16} {:>8}".format("threads", "single ops/s", "sharded ops/s", "speedup"))
+    for threads in [int(v) for v in args.threads.split(",")]:
+        single = pamagent_core.benchmark_cache(threads, args.iterations, SINGLE_LOCK)
+        sharded = pamagent_core.benchmark_cache(threads, args.iterations, args.shards)
+        speedup = sharded["ops_per_sec"] / single["ops_per_sec"] if single["ops_per_sec"] else 0.0
+        print("{:>8} {:>16,.0f} {:>16,.0f} {:>7.2f}x".format(
+            threads, single["ops_per_sec"], sharded["ops_per_sec"], speedup))
+
+
+if __name__ == "__main__":
+    main()
@@ -1,5 +1,7 @@
+import atexit
 import logging
 from itertools import count
+from typing import Optional
 
 from pamagent.hooks import requests_hook, django_hook, sqlite_hook, psycopg2_hook, mysql_hook, redis_hook
 # noinspection PyUnresolvedReferences
@@ -7,6 +9,8 @@ from pamagent import pamagent_core
 
 _logger = logging.getLogger(__name__)
 
+_TLS_OPTIONS = ('plaintext', 'tls_hostname', 'ca_file', 'client_cert', 'client_cert_password', 'min_tls_version')
+
 
 def _init_builtin() -> None:
         requests_hook.patch()
@@ -17,9 +21,31 @@ def _init_builtin() -> None:
         redis_hook.path()
 
 
-def init(token: str, collector_host: str="pamcollector.pushamp.com", _count=count()) -> None:
+def init(token: Optional[str]=None, collector_host: Optional[str]=None, config_file: Optional[str]=None,
+         _count=count(), **options) -> None:
+    """
+    Configure and activate agent. Settings passed here take precedence over PAMAGENT_* environment variables
+    and config file.
+
+    :param token: Secret token for auth on PAMCollector.
+    :param collector_host: Address with format host:port of PAMCollector instance.
+    :param config_file: Path to TOML (.toml) or INI config file.
+    :param options: Other settings (output_file, ca_file, queue_max_items, log_level, ...), see README.
+    """
     if next(_count):
         _logger.warning("The PamAgent The was already initialized and activate")
         return
+    pamagent_core.configure(dict(options, token=token, collector_host=collector_host), config_file)
+    config = pamagent_core.get_config(True)
     _init_builtin()
-    pamagent_core.activate(token, collector_host)
+    if config['output_file']:
+        pamagent_core.activate_file(config['output_file'], config['output_file_max_bytes'],
+                                    config['output_file_max_files'])
+    elif config['otlp_endpoint']:
+        pamagent_core.activate_otlp(config['otlp_endpoint'], config['otlp_headers'], config['service_name'])
+    elif config['zipkin_endpoint']:
+        pamagent_core.activate_zipkin(config['zipkin_endpoint'], config['service_name'])
+    else:
+        pamagent_core.activate(config['token'], config['collector_host'],
+                               **{key: config[key] for key in _TLS_OPTIONS})
+    atexit.register(pamagent_core.shutdown, config['shutdown_timeout'])
@@ -14,36 +14,44 @@ class CursorWrapper(WrapperBase):
         self._pam_cursor_params = cursor_params
 
     def execute(self, sql, parameters=DEFAULT, *args, **kwargs):
-        transaction = current_transaction()
+        transaction = current_transaction(sampled_only=False)
         if parameters is not DEFAULT:
             with DatabaseTrace(transaction, sql, self._pam_dbapi2_module, self._pam_connect_params,
                                self._pam_cursor_params, sql_parameters=parameters,
                                host=self._pam_connect_params[1].get('host'),
-                               port=self._pam_connect_params[1].get('port')):
-                return self.__wrapped__.execute(sql, parameters, *args, **kwargs)
+                               port=self._pam_connect_params[1].get('port')) as trace:
+                result = self.__wrapped__.execute(sql, parameters, *args, **kwargs)
+                trace.row_count = getattr(self.__wrapped__, 'rowcount', None)
+                return result
         else:
             with DatabaseTrace(transaction, sql, self._pam_dbapi2_module, self._pam_connect_params,
                                self._pam_cursor_params, host=self._pam_connect_params[1].get('host'),
-                               port=self._pam_connect_params[1].get('port')):
-                return self.__wrapped__.execute(sql, **kwargs)
+                               port=self._pam_connect_params[1].get('port')) as trace:
+                result = self.__wrapped__.execute(sql, **kwargs)
+                trace.row_count = getattr(self.__wrapped__, 'rowcount', None)
+                return result
 
     def executemany(self, sql, seq_of_parameters):
-        transaction = current_transaction()
+        transaction = current_transaction(sampled_only=False)
         try:
             parameters = seq_of_parameters[0]
         except (TypeError, IndexError):
             parameters = DEFAULT
         if parameters is not DEFAULT:
             with DatabaseTrace(transaction, sql, self._pam_dbapi2_module, self._pam_connect_params,
-                               self._pam_cursor_params, parameters):
-                return self.__wrapped__.executemany(sql, seq_of_parameters)
+                               self._pam_cursor_params, parameters) as trace:
+                result = self.__wrapped__.executemany(sql, seq_of_parameters)
+                trace.row_count = getattr(self.__wrapped__, 'rowcount', None)
+                return result
         else:
             with DatabaseTrace(transaction, sql, self._pam_dbapi2_module, self._pam_connect_params,
-                               self._pam_cursor_params):
-                return self.__wrapped__.executemany(sql, seq_of_parameters)
+                               self._pam_cursor_params) as trace:
+                result = self.__wrapped__.executemany(sql, seq_of_parameters)
+                trace.row_count = getattr(self.__wrapped__, 'rowcount', None)
+                return result
 
     def callproc(self, procedure_name, parameters=DEFAULT):
-        transaction = current_transaction()
+        transaction = current_transaction(sampled_only=False)
         with DatabaseTrace(transaction, 'CALL %s' % procedure_name, self._pam_dbapi2_module, self._pam_connect_params):
             if parameters is not DEFAULT:
                 return self.__wrapped__.callproc(procedure_name, parameters)
@@ -64,14 +72,14 @@ class ConnectionWrapper(WrapperBase):
                                        self._pam_connect_params, (args, kwargs))
 
     def commit(self):
-        transaction = current_transaction()
+        transaction = current_transaction(sampled_only=False)
         with DatabaseTrace(transaction, 'COMMIT', self._pam_dbapi2_module,
                            database_name=self._pam_connect_params[1]['database'],
                            host=self._pam_connect_params[1].get('host'), port=self._pam_connect_params[1].get('port')):
             return self.__wrapped__.commit()
 
     def rollback(self):
-        transaction = current_transaction()
+        transaction = current_transaction(sampled_only=False)
         with DatabaseTrace(transaction, 'ROLLBACK', self._pam_dbapi2_module, self._pam_connect_params,
                            database_name=self._pam_connect_params[1]['database'],
                            host=self._pam_connect_params[1].get('host'), port=self._pam_connect_params[1].get('port')):
@@ -35,7 +35,7 @@ def _instance_info(connection):
 def redis_connection_wrapper(wrapped, product):
 
     def dynamic_wrapper(wrapped_func, *args):
-        transaction = current_transaction()
+        transaction = current_transaction(sampled_only=False)
         if transaction is None:
             return wrapped_func(*args[1])
         host, port, db = _instance_info(args[0])
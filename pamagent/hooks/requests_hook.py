@@ -10,7 +10,10 @@ def instrument_requests_sessions(module):
     def url_send(_, request, *__, **___):
         return request.url
 
-    wrap_external_trace(module, 'Session.send', 'requests', url_send, url_method)
+    def inject_headers(headers, _, request, *__, **___):
+        request.headers.update(headers)
+
+    wrap_external_trace(module, 'Session.send', 'requests', url_send, url_method, inject_headers)
 
 
 def instrument_requests_api(module):
@@ -12,7 +12,7 @@ DEFAULT = object()
 
 class CursorWrapper(DBAPI2CursorWrapper):
     def execute(self, sql, parameters=DEFAULT, *args, **kwargs):
-        transaction = current_transaction()
+        transaction = current_transaction(sampled_only=False)
         if parameters is not DEFAULT:
             with DatabaseTrace(transaction, sql, self._pam_dbapi2_module, self._pam_connect_params,
                                self._pam_cursor_params, parameters, (args, kwargs)):
@@ -24,7 +24,7 @@ class CursorWrapper(DBAPI2CursorWrapper):
                 return self.__wrapped__.execute(sql, **kwargs)
 
     def executescript(self, sql_script):
-        transaction = current_transaction()
+        transaction = current_transaction(sampled_only=False)
         with DatabaseTrace(transaction, sql_script, self._pam_dbapi2_module, self._pam_connect_params,
                            database_name=self._pam_connect_params[0]):
             return self.__wrapped__.executescript(sql_script)
@@ -41,7 +41,7 @@ class ConnectionWrapper(DBAPI2ConnectionWrapper):
         return False
 
     def execute(self, sql, parameters=DEFAULT):
-        transaction = current_transaction()
+        transaction = current_transaction(sampled_only=False)
         if parameters is not DEFAULT:
             with DatabaseTrace(transaction, sql, self._pam_dbapi2_module, self._pam_connect_params,
                                database_name=self._pam_connect_params[0]):
@@ -52,25 +52,25 @@ class ConnectionWrapper(DBAPI2ConnectionWrapper):
                 return self.__wrapped__.execute(sql)
 
     def executemany(self, sql, seq_of_parameters):
-        transaction = current_transaction()
+        transaction = current_transaction(sampled_only=False)
         with DatabaseTrace(transaction, sql, self._pam_dbapi2_module, self._pam_connect_params,
                            database_name=self._pam_connect_params[0]):
             return self.__wrapped__.executemany(sql, seq_of_parameters)
 
     def executescript(self, sql_script):
-        transaction = current_transaction()
+        transaction = current_transaction(sampled_only=False)
         with DatabaseTrace(transaction, sql_script, self._pam_dbapi2_module, self._pam_connect_params,
                            database_name=self._pam_connect_params[0]):
             return self.__wrapped__.executescript(sql_script)
 
     def commit(self):
-        transaction = current_transaction()
+        transaction = current_transaction(sampled_only=False)
         with DatabaseTrace(transaction, 'COMMIT', self._pam_dbapi2_module,
                            database_name=self._pam_connect_params[0][0]):
             return self.__wrapped__.commit()
 
     def rollback(self):
-        transaction = current_transaction()
+        transaction = current_transaction(sampled_only=False)
         with DatabaseTrace(transaction, 'ROLLBACK', self._pam_dbapi2_module, self._pam_connect_params,
                            database_name=self._pam_connect_params[0]):
             return self.__wrapped__.rollback()
@@ -0,0 +1,92 @@
+import pytest
+
+# noinspection PyUnresolvedReferences
+from pamagent import pamagent_core
+
+
+@pytest.fixture
+def clean_config(monkeypatch):
+    """Environment and settings passed to configure are restored after test."""
+    yield monkeypatch
+    monkeypatch.undo()
+    pamagent_core.reset_config()
+
+
+def test_configure_dict():
+    config = pamagent_core.configure({"sample_rate": 0.5, "token": "secret", "output_file": None})
+    assert config["sample_rate"] == 0.5
+    assert config["token"] == "********"
+    assert pamagent_core.get_config()["token"] == "********"
+    assert pamagent_core.get_config(True)["token"] == "secret"
+    pamagent_core.configure({"sample_rate": 1.0})
+
+
+@pytest.mark.parametrize("options", [
+    {"unknown_setting": 1},
+    {"sample_rate": 2},
+    {"sample_rate": float("nan")},
+    {"queue_policy": "drop_all"},
+    {"log_level": "verbose"},
+    {"plaintext": "maybe"},
+    {"min_tls_version": "ssl3"},
+    {"socket_timeout": 0},
+    {"n_plus_one_threshold": 1},
+    {"slow_query_threshold": -1},
+    {"metrics_harvest_interval": -1},
+    {"transaction_max_age": -1},
+    {"shutdown_timeout": -1},
+    {"keep_min_duration": float("nan")},
+    {"max_nodes": -1},
+    {"sampling_mode": "always"},
+    {"sampling_target": -1},
+    {"keep_min_duration": -1},
+])
+def test_configure_invalid(options):
+    before = pamagent_core.get_config(True)
+    with pytest.raises(pamagent_core.ConfigurationError):
+        pamagent_core.configure(options)
+    assert pamagent_core.get_config(True) == before
+
+
+def test_configure_unsupported_type():
+    with pytest.raises(TypeError):
+        pamagent_core.configure({"token": object()})
+
+
+def test_config_precedence(tmpdir, clean_config):
+    path = tmpdir.join("pamagent.toml")
+    path.write('[pamagent]\nbatch_max_items = 10\nqueue_max_items = 500\n')
+    clean_config.setenv("PAMAGENT_QUEUE_MAX_ITEMS", "600")
+    config = pamagent_core.configure(None, str(path))
+    assert config["batch_max_items"] == 10
+    assert config["queue_max_items"] == 600
+    config = pamagent_core.configure({"queue_max_items": 700})
+    assert config["batch_max_items"] == 10
+    assert config["queue_max_items"] == 700
+
+
+def test_reset_config(tmpdir, clean_config):
+    path = tmpdir.join("pamagent.toml")
+    path.write('[pamagent]\nbatch_max_items = 10\n')
+    pamagent_core.configure({"queue_max_items": 700}, str(path))
+    clean_config.setenv("PAMAGENT_QUEUE_MAX_ITEMS", "600")
+    config = pamagent_core.reset_config()
+    assert config["queue_max_items"] == 600
+    assert config["batch_max_items"] == 100
+    assert pamagent_core.configure({})["batch_max_items"] == 100
+
+
+def test_shortcuts_are_settings(clean_config):
+    assert pamagent_core.configure_output_queue(500, 1024 * 1024, "drop_newest")
+    assert pamagent_core.configure_batching(10, 2048, 50, "gzip")
+    config = pamagent_core.configure({"log_level": 0})
+    assert (config["queue_max_items"], config["queue_max_bytes"], config["queue_policy"]) == \
+        (500, 1024 * 1024, "drop_newest")
+    assert (config["batch_max_items"], config["batch_max_bytes"], config["batch_max_delay_ms"]) == (10, 2048, 50)
+    assert config["compression"] == "gzip"
+
+
+def test_config_ini(tmpdir, clean_config):
+    path = tmpdir.join("pamagent.ini")
+    path.write("[pamagent]\nbatch_max_bytes = 2048\n\n[other]\nbatch_max_bytes = 1\n")
+    assert pamagent_core.configure(None, str(path))["batch_max_bytes"] == 2048
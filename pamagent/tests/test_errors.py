@@ -0,0 +1,87 @@
+import pytest
+
+# noinspection PyUnresolvedReferences
+from pamagent import pamagent_core
+
+TRANSACTION_ID = 2 * 10 ** 12
+NODE_ID = 1
+
+
+@pytest.fixture
+def transaction():
+    pamagent_core.set_transaction(TRANSACTION_ID, "garbage", "/garbage")
+    pamagent_core.push_current(TRANSACTION_ID, NODE_ID, 1.0, "root")
+    yield TRANSACTION_ID
+    pamagent_core.drop_transaction(TRANSACTION_ID)
+
+
+@pytest.fixture
+def strict():
+    pamagent_core.configure({"strict": True})
+    yield
+    pamagent_core.configure({"strict": False})
+
+
+GARBAGE_CALLS = [
+    lambda tr: pamagent_core.push_current(tr, 2, float("nan"), "f"),
+    lambda tr: pamagent_core.push_current(tr, 2, float("inf"), "f"),
+    lambda tr: pamagent_core.push_current(tr, 2, -1.0, "f"),
+    lambda tr: pamagent_core.push_current_external(tr, 2, 2.0, "not a url", "requests", "GET"),
+    lambda tr: pamagent_core.push_current_external(tr, 2, 2.0, "", "requests", "GET"),
+    lambda tr: pamagent_core.push_current_external(tr, 2, 2.0, "http://[::1", "urllib3", "GET"),
+    lambda tr: pamagent_core.push_current_database(tr, 2, float("nan"), "PostgreSQL", "db", None, None,
+                                                   "SELECT", "users", "SELECT 1"),
+    lambda tr: pamagent_core.push_current_cache(tr, 2, -5.0, "0", "localhost", 6379, "GET", "Redis"),
+    lambda tr: pamagent_core.pop_current(tr, NODE_ID, float("nan")),
+    lambda tr: pamagent_core.configure_output_queue(10, 10, "drop_all"),
+    lambda tr: pamagent_core.configure_batching(10, 10, 10, "zstd"),
+]
+
+
+@pytest.mark.parametrize("call", GARBAGE_CALLS)
+def test_garbage_lenient(transaction, call):
+    assert not call(transaction)
+    # Transaction is left usable
+    assert pamagent_core.get_transaction(transaction) == transaction
+    assert pamagent_core.pop_current(transaction, NODE_ID, 3.0) is None
+    assert pamagent_core.dump_transaction(transaction)
+
+
+@pytest.mark.parametrize("call", GARBAGE_CALLS)
+def test_garbage_strict(transaction, strict, call):
+    with pytest.raises(pamagent_core.InvalidInputError):
+        call(transaction)
+    assert pamagent_core.get_transaction(transaction) == transaction
+
+
+def test_unknown_transaction(strict):
+    unknown = TRANSACTION_ID + 1
+    assert pamagent_core.get_transaction(unknown) is None
+    assert not pamagent_core.push_current(unknown, NODE_ID, 1.0, "f")
+    assert pamagent_core.pop_current(unknown, NODE_ID, 2.0) is None
+    assert not pamagent_core.set_transaction_path(unknown, "/")
+    assert pamagent_core.dump_transaction(unknown) == ""
+    assert not pamagent_core.drop_transaction(unknown)
+
+
+@pytest.mark.parametrize("args", [
+    ("not-an-int", NODE_ID, 1.0, "f"),
+    (-1, NODE_ID, 1.0, "f"),
+    (2 ** 64, NODE_ID, 1.0, "f"),
+    (TRANSACTION_ID, NODE_ID, "now", "f"),
+])
+def test_wrong_types(args):
+    with pytest.raises((TypeError, OverflowError)):
+        pamagent_core.push_current(*args)
+
+
+def test_exception_hierarchy():
+    assert issubclass(pamagent_core.PamAgentError, Exception)
+    for name in ("InvalidInputError", "ConfigurationError", "OutputError", "InternalError"):
+        assert issubclass(getattr(pamagent_core, name), pamagent_core.PamAgentError)
+
+
+def test_configuration_error():
+    with pytest.raises(pamagent_core.PamAgentError):
+        pamagent_core.configure({"strict": "sometimes"})
+    assert pamagent_core.get_config()["strict"] is False
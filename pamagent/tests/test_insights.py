@@ -0,0 +1,66 @@
+import json
+
+import pytest
+
+# noinspection PyUnresolvedReferences
+from pamagent import pamagent_core
+
+AUTHOR_SQL = "SELECT * FROM authors WHERE id = %s"
+
+
+def _n_plus_one_transaction(tr_id, authors):
+    pamagent_core.set_transaction(tr_id, "BookList", "/books")
+    pamagent_core.push_current(tr_id, 1, 10.0, "books.views.book_list")
+    pamagent_core.push_current_database(tr_id, 2, 10.0, "PostgreSQL", "shop", None, None, "select", "books",
+                                        "SELECT * FROM books")
+    pamagent_core.pop_current(tr_id, 2, 10.5)
+    for i in range(authors):
+        node_id = 10 + i
+        start_time = 11.0 + i
+        pamagent_core.push_current_database(tr_id, node_id, start_time, "PostgreSQL", "shop", None, None, "select",
+                                            "authors", AUTHOR_SQL)
+        pamagent_core.pop_current(tr_id, node_id, start_time + 0.25)
+    pamagent_core.pop_current(tr_id, 1, 20.0)
+
+
+def test_n_plus_one():
+    tr_id = 12 * 10 ** 12
+    _n_plus_one_transaction(tr_id, 6)
+    insights = pamagent_core.get_transaction_insights(tr_id)
+    assert len(insights["n_plus_one"]) == 1
+    query = insights["n_plus_one"][0]
+    assert query["parent_name"] == "books.views.book_list"
+    assert query["normalized"] == "select * from authors where id = ?"
+    assert query["target"] == "authors"
+    assert query["count"] == 6
+    assert query["total_time"] == pytest.approx(1.5)
+    dump = json.loads(pamagent_core.dump_transaction(tr_id))
+    assert query["parent_span_id"] == dump["nodes_stack"][0]["span_id"]
+    assert "insights" not in dump
+    assert pamagent_core.drop_transaction(tr_id)
+    assert pamagent_core.get_transaction_insights(tr_id) is None
+
+
+def test_n_plus_one_below_threshold():
+    tr_id = 12 * 10 ** 12 + 1
+    _n_plus_one_transaction(tr_id, 4)
+    assert pamagent_core.get_transaction_insights(tr_id) == {"n_plus_one": []}
+    pamagent_core.configure({"n_plus_one_threshold": 3})
+    assert pamagent_core.get_transaction_insights(tr_id)["n_plus_one"][0]["count"] == 4
+    pamagent_core.configure({"n_plus_one_threshold": 0})
+    assert pamagent_core.get_transaction_insights(tr_id) == {"n_plus_one": []}
+    pamagent_core.configure({"n_plus_one_threshold": 5})
+    assert pamagent_core.drop_transaction(tr_id)
+
+
+def test_insights_in_dump(tmpdir):
+    path = str(tmpdir.join("traces.jsonl"))
+    assert pamagent_core.activate_file(path)
+    tr_id = 12 * 10 ** 12 + 2
+    _n_plus_one_transaction(tr_id, 5)
+    assert pamagent_core.drop_transaction(tr_id)
+    assert pamagent_core.flush(10.0)
+    pamagent_core.shutdown(1.0)
+    with open(path) as f:
+        dump = json.loads(f.readline())
+    assert dump["insights"]["n_plus_one"][0]["count"] == 5
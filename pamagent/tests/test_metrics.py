@@ -0,0 +1,85 @@
+# noinspection PyUnresolvedReferences
+from pamagent import pamagent_core
+
+
+def _by(rows, **fields):
+    return [row for row in rows if all(row[k] == v for k, v in fields.items())]
+
+
+def _run(tr_id, name, duration):
+    pamagent_core.set_transaction(tr_id, name, "/metrics")
+    pamagent_core.push_current(tr_id, 1, 10.0, "view")
+    pamagent_core.push_current_external(tr_id, 2, 10.1, "http://api.example.com/v1/users", "requests", "GET")
+    pamagent_core.pop_current(tr_id, 2, 10.3)
+    pamagent_core.push_current_database(tr_id, 3, 10.3, "PostgreSQL", "shop", None, None, "select", "orders",
+                                        "SELECT * FROM orders")
+    pamagent_core.pop_current(tr_id, 3, 10.4)
+    pamagent_core.push_current_cache(tr_id, 4, 10.4, "0", "localhost", 6379, "GET", "Redis")
+    pamagent_core.pop_current(tr_id, 4, 10.45)
+    pamagent_core.pop_current(tr_id, 1, 10.0 + duration)
+    assert pamagent_core.drop_transaction(tr_id)
+
+
+def test_timeslice_metrics():
+    pamagent_core.get_metrics(True)
+    tr_id = 20 * 10 ** 12
+    _run(tr_id, "orders.views.index", 0.5)
+    _run(tr_id + 1, "orders.views.index", 1.5)
+    _run(tr_id + 2, "orders.views.detail", 1.0)
+    metrics = pamagent_core.get_metrics()
+    assert metrics["type"] == "metrics"
+    index = _by(metrics["transactions"], name="orders.views.index")[0]
+    assert index["count"] == 2
+    assert index["total"] == 2.0
+    assert index["min"] == 0.5 and index["max"] == 1.5
+    assert index["sum_of_squares"] == 2.5
+    assert _by(metrics["externals"], host="api.example.com")[0]["count"] == 3
+    database = _by(metrics["databases"], database_product="PostgreSQL", operation="select", target="orders")
+    assert database[0]["count"] == 3
+    assert _by(metrics["caches"], database_product="Redis", operation="GET")[0]["count"] == 3
+    assert pamagent_core.get_metrics(True)["transactions"]
+    assert pamagent_core.get_metrics()["transactions"] == []
+
+
+def test_unsampled_transactions_are_counted():
+    pamagent_core.get_metrics(True)
+    pamagent_core.configure({"sample_rate": 0.0})
+    try:
+        _run(20 * 10 ** 12 + 10, "orders.views.unsampled", 0.2)
+    finally:
+        pamagent_core.configure({"sample_rate": 1.0})
+    metrics = pamagent_core.get_metrics(True)
+    assert _by(metrics["transactions"], name="orders.views.unsampled")[0]["count"] == 1
+    external = _by(metrics["externals"], host="api.example.com")[0]
+    assert external["count"] == 1
+    assert abs(external["total"] - 0.2) < 1e-9
+    database = _by(metrics["databases"], database_product="PostgreSQL", operation="select", target="orders")
+    assert database[0]["count"] == 1
+    assert _by(metrics["caches"], database_product="Redis", operation="GET")[0]["count"] == 1
+
+
+def test_unsampled_calls_are_not_recorded_as_nodes():
+    pamagent_core.configure({"sample_rate": 0.0})
+    tr_id = 20 * 10 ** 12 + 11
+    try:
+        pamagent_core.set_transaction(tr_id, "orders.views.unsampled", "/metrics")
+    finally:
+        pamagent_core.configure({"sample_rate": 1.0})
+    pamagent_core.push_current(tr_id, 1, 10.0, "view")
+    assert pamagent_core.push_current_database(tr_id, 3, 10.3, "PostgreSQL", "shop", None, None, "select", "orders",
+                                               "SELECT * FROM orders")
+    assert pamagent_core.pop_current(tr_id, 3, 10.4) is None
+    pamagent_core.pop_current(tr_id, 1, 10.5)
+    summary = pamagent_core.get_transaction_summary(tr_id)
+    assert summary["node_count"] == 1 and summary["database_calls"] == 0
+    assert pamagent_core.drop_transaction(tr_id)
+
+
+def test_harvest_metrics():
+    pamagent_core.get_metrics(True)
+    assert not pamagent_core.harvest_metrics()
+    _run(20 * 10 ** 12 + 20, "orders.views.harvested", 0.2)
+    enqueued = pamagent_core.get_output_queue_stats()["enqueued"]
+    assert pamagent_core.harvest_metrics()
+    assert pamagent_core.get_output_queue_stats()["enqueued"] == enqueued + 1
+    assert pamagent_core.get_metrics()["transactions"] == []
@@ -0,0 +1,85 @@
+import json
+
+import pytest
+
+# noinspection PyUnresolvedReferences
+from pamagent import pamagent_core
+
+
+@pytest.fixture
+def limits():
+    def configure(max_nodes, max_depth):
+        pamagent_core.configure({"max_nodes": max_nodes, "max_depth": max_depth})
+
+    yield configure
+    pamagent_core.configure({"max_nodes": 2000, "max_depth": 100})
+
+
+def _dump(tr_id):
+    return json.loads(pamagent_core.dump_transaction(tr_id))
+
+
+def test_node_count_limit(limits):
+    limits(10, 0)
+    tr_id = 17 * 10 ** 12
+    pamagent_core.set_transaction(tr_id, "Queries", "/queries")
+    pamagent_core.push_current(tr_id, 1, 10.0, "view")
+    for i in range(1000):
+        node_id = 10 + i
+        pamagent_core.push_current_database(tr_id, node_id, 11.0, "PostgreSQL", "shop", None, None, "select",
+                                            "orders", "SELECT * FROM orders WHERE id = %s")
+        pamagent_core.pop_current(tr_id, node_id, 11.001)
+    pamagent_core.push_current_cache(tr_id, 5, 12.0, "0", "localhost", 6379, "GET", "Redis")
+    pamagent_core.pop_current(tr_id, 5, 12.5)
+    pamagent_core.pop_current(tr_id, 1, 13.0)
+    dump = _dump(tr_id)
+    root = dump["nodes_stack"][0]
+    assert len(root["childrens"]) == 9
+    assert root["node_count"] == 9
+    assert dump["trace_node_count"] == 1002
+    assert dump["summarized_nodes"] == 992
+    assert dump["truncated"]
+    summaries = {s["node_type"]: s for s in root["summaries"]}
+    assert summaries["Database"]["count"] == 991
+    assert summaries["Database"]["total_time"] == pytest.approx(0.991)
+    assert summaries["Cache"]["count"] == 1
+    assert root["exclusive"] == pytest.approx(1.5)
+    summary = pamagent_core.get_transaction_summary(tr_id)
+    assert summary["node_count"] == 10
+    assert summary["summarized_nodes"] == 992
+    assert pamagent_core.drop_transaction(tr_id)
+
+
+def test_node_depth_limit(limits):
+    limits(0, 3)
+    tr_id = 17 * 10 ** 12 + 1
+    pamagent_core.set_transaction(tr_id, "Recursion", "/recursion")
+    for node_id in range(1, 11):
+        pamagent_core.push_current(tr_id, node_id, 10.0 + node_id, "f%d" % node_id)
+    for node_id in range(10, 0, -1):
+        pamagent_core.pop_current(tr_id, node_id, 30.0 - node_id)
+    dump = _dump(tr_id)
+    f3 = dump["nodes_stack"][0]["childrens"][0]["childrens"][0]
+    assert f3["func_name"] == "f3"
+    assert f3["childrens"] == []
+    assert f3["summaries"] == [{"node_type": "Func", "count": 7, "total_time": pytest.approx(sum(
+        (30.0 - n) - (10.0 + n) for n in range(4, 11)))}]
+    assert dump["summarized_nodes"] == 7
+    assert pamagent_core.get_transaction_summary(tr_id)["max_depth"] == 3
+    assert pamagent_core.drop_transaction(tr_id)
+
+
+def test_no_truncation_under_limits():
+    tr_id = 17 * 10 ** 12 + 2
+    pamagent_core.set_transaction(tr_id, "Small", "/small")
+    pamagent_core.push_current(tr_id, 1, 10.0, "view")
+    for node_id in range(2, 300):
+        pamagent_core.push_current(tr_id, node_id, 11.0, "f")
+        pamagent_core.pop_current(tr_id, node_id, 11.5)
+    pamagent_core.pop_current(tr_id, 1, 12.0)
+    dump = _dump(tr_id)
+    assert dump["trace_node_count"] == 299
+    assert dump["nodes_stack"][0]["node_count"] == 298
+    assert "truncated" not in dump and "summarized_nodes" not in dump
+    assert "summaries" not in dump["nodes_stack"][0]
+    assert pamagent_core.drop_transaction(tr_id)
@@ -0,0 +1,114 @@
+import gzip
+import json
+import threading
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+import pytest
+
+# noinspection PyUnresolvedReferences
+from pamagent import pamagent_core
+
+INBOUND = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+
+
+class _OtlpHandler(BaseHTTPRequestHandler):
+    protocol_version = "HTTP/1.1"
+
+    def do_POST(self):  # noqa: N802
+        body = self.rfile.read(int(self.headers["Content-Length"]))
+        if self.headers.get("Content-Encoding") == "gzip":
+            body = gzip.decompress(body)
+        self.server.requests.append((self.path, dict(self.headers), json.loads(body.decode())))
+        self.send_response(200)
+        self.send_header("Content-Type", "application/json")
+        self.send_header("Content-Length", "2")
+        self.end_headers()
+        self.wfile.write(b"{}")
+
+    def log_message(self, *args):
+        pass
+
+
+@pytest.fixture
+def otlp_server():
+    server = HTTPServer(("127.0.0.1", 0), _OtlpHandler)
+    server.requests = []
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    yield server
+    server.shutdown()
+    pamagent_core.shutdown(1.0)
+
+
+def _attrs(span):
+    return {a["key"]: list(a["value"].values())[0] for a in span["attributes"]}
+
+
+def test_otlp_export(otlp_server):
+    endpoint = "http://127.0.0.1:%d/v1/traces" % otlp_server.server_port
+    assert pamagent_core.configure_batching(10, 1024 * 1024, 50, "gzip")
+    assert pamagent_core.activate_otlp(endpoint, "x-api-key=secret", "checkout")
+    tr_id = 9 * 10 ** 12
+    pamagent_core.set_transaction(tr_id, "OrderView", "/orders", INBOUND)
+    pamagent_core.add_transaction_attribute(tr_id, "tenant", "acme")
+    pamagent_core.push_current(tr_id, 1, 10.0, "orders.views.index")
+    pamagent_core.push_current_database(tr_id, 2, 11.0, "PostgreSQL", "shop", "db.local", 5432, "SELECT", "orders",
+                                        "SELECT * FROM orders WHERE id = ?")
+    pamagent_core.pop_current(tr_id, 2, 12.0)
+    pamagent_core.push_current_cache(tr_id, 3, 12.0, "0", "localhost", 6379, "GET", "Redis")
+    pamagent_core.pop_current(tr_id, 3, 12.5)
+    pamagent_core.push_current_external(tr_id, 4, 13.0, "https://api.example.com/pay", "requests", "post")
+    pamagent_core.record_node_error(tr_id, "requests.HTTPError", "502 Bad Gateway", ["frame 1", "frame 2"])
+    pamagent_core.pop_current(tr_id, 4, 14.0)
+    pamagent_core.pop_current(tr_id, 1, 15.0)
+    assert pamagent_core.drop_transaction(tr_id)
+    assert pamagent_core.flush(10.0)
+    pamagent_core.configure_batching(100, 1024 * 1024, 1000)
+
+    path, headers, request = otlp_server.requests[0]
+    assert path == "/v1/traces"
+    assert headers["x-api-key"] == "secret"
+    assert headers["Content-Type"] == "application/json"
+    resource_spans = request["resourceSpans"][0]
+    assert _attrs(resource_spans["resource"]) == {"service.name": "checkout"}
+    spans = {s["name"]: s for s in resource_spans["scopeSpans"][0]["spans"]}
+    assert set(spans) == {"OrderView", "SELECT orders", "redis GET", "POST api.example.com"}
+
+    root = spans["OrderView"]
+    assert root["traceId"] == "4bf92f3577b34da6a3ce929d0e0e4736"
+    assert root["parentSpanId"] == "00f067aa0ba902b7"
+    assert root["kind"] == 2
+    assert root["startTimeUnixNano"] == "10000000000"
+    assert root["endTimeUnixNano"] == "15000000000"
+    assert _attrs(root)["http.target"] == "/orders"
+    assert _attrs(root)["tenant"] == "acme"
+    assert _attrs(root)["code.function"] == "orders.views.index"
+
+    db = spans["SELECT orders"]
+    assert db["parentSpanId"] == root["spanId"]
+    assert db["kind"] == 3
+    assert _attrs(db) == {"db.system": "postgresql", "db.name": "shop", "db.operation": "SELECT",
+                          "db.sql.table": "orders", "db.statement": "SELECT * FROM orders WHERE id = ?",
+                          "net.peer.name": "db.local", "net.peer.port": "5432"}
+
+    cache = spans["redis GET"]
+    assert _attrs(cache)["db.system"] == "redis"
+    assert _attrs(cache)["db.redis.database_index"] == "0"
+
+    external = spans["POST api.example.com"]
+    assert _attrs(external)["http.method"] == "POST"
+    assert _attrs(external)["net.peer.name"] == "api.example.com"
+    assert external["status"] == {"code": 2, "message": "502 Bad Gateway"}
+    event = external["events"][0]
+    assert event["name"] == "exception"
+    assert _attrs(event)["exception.stacktrace"] == "frame 1\nframe 2"
+
+
+@pytest.mark.parametrize("endpoint, headers", [
+    ("not a url", None),
+    ("ftp://127.0.0.1/v1/traces", None),
+    ("http://example.com:4318/v1/traces", None),
+    ("http://127.0.0.1:4318/v1/traces", "no-value"),
+])
+def test_activate_otlp_invalid(endpoint, headers):
+    with pytest.raises(pamagent_core.ConfigurationError):
+        pamagent_core.activate_otlp(endpoint, headers)
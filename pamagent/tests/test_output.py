@@ -0,0 +1,259 @@
+import gzip
+import json
+import os
+import socket
+import struct
+import threading
+import time
+
+import pytest
+
+# noinspection PyUnresolvedReferences
+from pamagent import pamagent_core
+
+
+def _recv_exact(conn, size):
+    data = b""
+    while len(data) < size:
+        chunk = conn.recv(size - len(data))
+        if not chunk:
+            return None
+        data += chunk
+    return data
+
+
+def _collector_stand_in(sock, frames):
+    conn, _ = sock.accept()
+    with conn:
+        token = b""
+        while not token.endswith(b"\r\n"):
+            token += conn.recv(1)
+        conn.sendall(b"OK")
+        while True:
+            header = _recv_exact(conn, 12)
+            if header is None:
+                return
+            magic, version, compression, count, length = struct.unpack(">2sBBII", header)
+            body = _recv_exact(conn, length)
+            frames.append((magic, version, compression, count, body))
+            conn.sendall(b"ACK")
+
+
+def _send(tr_id, name, path="/"):
+    assert pamagent_core.set_transaction(tr_id, name, path)
+    pamagent_core.push_current(tr_id, 1, time.time())
+    pamagent_core.pop_current(tr_id, 1, time.time())
+    assert pamagent_core.drop_transaction(tr_id)
+
+
+def _names(path):
+    with open(path) as f:
+        rows = [json.loads(line) for line in f]
+    return [row["base_name"] for row in rows if "base_name" in row]
+
+
+def _stop_worker():
+    # Metrics harvested at shutdown would be written as one more line
+    pamagent_core.get_metrics(True)
+    assert pamagent_core.shutdown(5.0)
+
+
+def test_output_queue_stats():
+    stats = pamagent_core.get_output_queue_stats()
+    assert set(stats) == {"size", "bytes", "enqueued", "dropped"}
+
+
+def test_configure_output_queue():
+    assert pamagent_core.configure_output_queue(100, 1024 * 1024, "drop_newest")
+    assert pamagent_core.configure_output_queue(100, 1024 * 1024, "sample")
+    assert not pamagent_core.configure_output_queue(100, 1024 * 1024, "unknown")
+    assert pamagent_core.configure_output_queue(10000, 64 * 1024 * 1024)
+
+
+@pytest.mark.parametrize("policy", ["drop_oldest", "drop_newest", "sample"])
+def test_queue_overflow(tmpdir, policy):
+    # Send what other tests left in the queue. Nothing is sent while worker is stopped, so the
+    # queue fills up
+    assert pamagent_core.activate_file(str(tmpdir.join("drained.jsonl")))
+    assert pamagent_core.flush(5.0)
+    _stop_worker()
+    assert pamagent_core.configure_output_queue(3, 1024 * 1024, policy)
+    try:
+        stats = pamagent_core.get_output_queue_stats()
+        assert stats["size"] == 0
+        sent = ["overflow_%s_%d" % (policy, i) for i in range(5)]
+        for i, name in enumerate(sent):
+            _send(10 ** 12 + 150 + i, name)
+        after = pamagent_core.get_output_queue_stats()
+        assert after["size"] == 3
+        assert after["dropped"] - stats["dropped"] == 2
+        assert after["enqueued"] - stats["enqueued"] == (3 if policy == "drop_newest" else 5)
+
+        path = str(tmpdir.join("overflow.jsonl"))
+        assert pamagent_core.activate_file(path)
+        assert pamagent_core.flush(5.0)
+        _stop_worker()
+    finally:
+        pamagent_core.configure_output_queue(10000, 64 * 1024 * 1024)
+    names = _names(path)
+    if policy == "drop_oldest":
+        assert names == sent[2:]
+    elif policy == "drop_newest":
+        assert names == sent[:3]
+    else:
+        # Random queued transaction is replaced, the newest one is always kept
+        assert len(names) == 3 and names[-1] == sent[-1]
+        assert names == [name for name in sent if name in names]
+
+
+def test_configure_batching():
+    assert not pamagent_core.configure_batching(100, 1024 * 1024, 1000, "lz4")
+    assert pamagent_core.configure_batching(100, 1024 * 1024, 1000)
+
+
+def test_batch_framing():
+    sock = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+    sock.bind(("127.0.0.1", 0))
+    sock.listen(1)
+    frames = []
+    threading.Thread(target=_collector_stand_in, args=(sock, frames), daemon=True).start()
+
+    assert pamagent_core.configure_batching(3, 1024 * 1024, 200, "gzip")
+    assert pamagent_core.activate("token", "127.0.0.1:%d" % sock.getsockname()[1], plaintext=True)
+    names = {"batch_framing_%d" % i for i in range(3)}
+    for i, name in enumerate(sorted(names)):
+        tr_id = 10 ** 12 + i
+        assert pamagent_core.set_transaction(tr_id, name)
+        pamagent_core.push_current(tr_id, 1, time.time())
+        pamagent_core.pop_current(tr_id, 1, time.time())
+        assert pamagent_core.drop_transaction(tr_id)
+
+    seen = set()
+    deadline = time.time() + 10
+    while not names <= seen and time.time() < deadline:
+        while frames:
+            magic, version, compression, count, body = frames.pop(0)
+            assert magic == b"PB"
+            assert version == 1
+            assert compression == 1
+            lines = gzip.decompress(body).decode().splitlines()
+            assert 0 < count <= 3
+            assert len(lines) == count
+            seen.update(json.loads(line)["base_name"] for line in lines)
+        time.sleep(0.05)
+    assert names <= seen
+    pamagent_core.configure_batching(100, 1024 * 1024, 1000)
+
+
+def test_silent_collector_times_out():
+    # Connection is accepted by the backlog, but the token is never answered
+    sock = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+    sock.bind(("127.0.0.1", 0))
+    sock.listen(1)
+    pamagent_core.configure({"socket_timeout": 0.2})
+    try:
+        assert pamagent_core.activate("token", "127.0.0.1:%d" % sock.getsockname()[1], plaintext=True)
+        time.sleep(0.5)
+        _stop_worker()
+    finally:
+        pamagent_core.configure({"socket_timeout": 30.0})
+        sock.close()
+
+
+def test_worker_lifecycle(tmpdir):
+    path = str(tmpdir.join("traces.jsonl"))
+    assert pamagent_core.activate_file(path)
+    assert not pamagent_core.start()
+    tr_id = 10 ** 12 + 100
+    assert pamagent_core.set_transaction(tr_id, "worker_lifecycle")
+    pamagent_core.push_current(tr_id, 1, time.time())
+    pamagent_core.pop_current(tr_id, 1, time.time())
+    assert pamagent_core.drop_transaction(tr_id)
+    assert pamagent_core.flush(5.0)
+    assert pamagent_core.shutdown(5.0)
+    with open(path) as f:
+        names = [json.loads(line)["base_name"] for line in f]
+    assert "worker_lifecycle" in names
+    assert pamagent_core.start()
+    assert pamagent_core.shutdown(5.0)
+
+
+def test_flush_waits_for_delayed_batch(tmpdir):
+    path = str(tmpdir.join("delayed.jsonl"))
+    # Worker holds the transaction for max_delay, waiting for the batch to fill up
+    assert pamagent_core.configure_batching(100, 1024 * 1024, 1000)
+    assert pamagent_core.activate_file(path)
+    try:
+        tr_id = 10 ** 12 + 101
+        assert pamagent_core.set_transaction(tr_id, "delayed_batch")
+        pamagent_core.push_current(tr_id, 1, time.time())
+        pamagent_core.pop_current(tr_id, 1, time.time())
+        assert pamagent_core.drop_transaction(tr_id)
+        time.sleep(0.1)
+        assert pamagent_core.flush(5.0)
+        with open(path) as f:
+            names = [json.loads(line).get("base_name") for line in f]
+        assert "delayed_batch" in names
+    finally:
+        assert pamagent_core.shutdown(5.0)
+
+
+def test_file_rotation(tmpdir):
+    path = str(tmpdir.join("rotated.jsonl"))
+    max_bytes = 2000
+    assert pamagent_core.activate_file(path, max_bytes, 2)
+    try:
+        # Every line is longer than 400 bytes, so a file holds at most 4 of them
+        for i in range(12):
+            _send(10 ** 12 + 110 + i, "rotation_%02d" % i, "/" + "x" * 400)
+        assert pamagent_core.flush(5.0)
+    finally:
+        _stop_worker()
+    files = [path + ".2", path + ".1", path]
+    assert all(os.path.exists(p) for p in files)
+    assert not os.path.exists(path + ".3")
+    assert all(os.path.getsize(p) <= max_bytes for p in files)
+    names = [name for p in files for name in _names(p)]
+    # Oldest transactions are in the rotated file that was removed
+    assert "rotation_00" not in names
+    assert names == sorted(names) and names[-1] == "rotation_11"
+
+    # Size of existing file is counted after reopening, so rotation goes on from where it stopped
+    assert pamagent_core.activate_file(path, max_bytes, 2)
+    try:
+        for i in range(4):
+            _send(10 ** 12 + 130 + i, "rotation_reopened_%d" % i, "/" + "x" * 400)
+        assert pamagent_core.flush(5.0)
+    finally:
+        _stop_worker()
+    assert not os.path.exists(path + ".3")
+    assert all(os.path.getsize(p) <= max_bytes for p in files)
+    names = [name for p in files for name in _names(p)]
+    assert names == sorted(names)
+    assert names[-4:] == ["rotation_reopened_%d" % i for i in range(4)]
+
+
+def test_file_rotation_without_rotated_files(tmpdir):
+    path = str(tmpdir.join("truncated.jsonl"))
+    assert pamagent_core.activate_file(path, 1000, 0)
+    try:
+        for i in range(5):
+            _send(10 ** 12 + 140 + i, "truncated_%d" % i, "/" + "x" * 400)
+        assert pamagent_core.flush(5.0)
+    finally:
+        _stop_worker()
+    assert not os.path.exists(path + ".1")
+    assert os.path.getsize(path) <= 1000
+    assert _names(path)[-1] == "truncated_4"
+
+
+@pytest.mark.parametrize("options,message", [
+    ({"plaintext": True}, "allowed only to localhost"),
+    ({"ca_file": "/nonexistent/ca.pem"}, "Unable to read"),
+    ({"client_cert": "/nonexistent/client.p12"}, "Unable to read"),
+    ({"min_tls_version": "ssl3"}, "Unknown TLS version"),
+])
+def test_activate_invalid_tls(options, message):
+    with pytest.raises(pamagent_core.ConfigurationError) as exc:
+        pamagent_core.activate("token", "10.0.0.1:9000", **options)
+    assert message in str(exc.value)
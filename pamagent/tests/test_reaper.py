@@ -0,0 +1,53 @@
+import json
+import time
+
+# noinspection PyUnresolvedReferences
+from pamagent import pamagent_core
+
+
+def test_reap_abandoned_transaction(tmpdir):
+    path = str(tmpdir.join("traces.jsonl"))
+    assert pamagent_core.activate_file(path)
+    tr_id = 16 * 10 ** 12
+    reaped = pamagent_core.get_reaper_stats()["reaped"]
+    pamagent_core.set_transaction(tr_id, "Killed", "/killed")
+    pamagent_core.push_current(tr_id, 1, time.time(), "view")
+    pamagent_core.push_current(tr_id, 2, time.time(), "render")
+    assert pamagent_core.reap_transactions(60.0) == 0
+    time.sleep(0.2)
+    # Transactions left by other tests are reaped as well
+    count = pamagent_core.reap_transactions(0.1)
+    assert count >= 1
+    assert pamagent_core.get_transaction_summary(tr_id) is None
+    assert pamagent_core.get_reaper_stats()["reaped"] == reaped + count
+    assert pamagent_core.flush(10.0)
+    pamagent_core.shutdown(1.0)
+    with open(path) as f:
+        dump = next(d for d in map(json.loads, f) if d["base_name"] == "Killed")
+    assert dump["truncated"] and dump["reaped"]
+    assert dump["created_at"] <= dump["last_active"]
+    root = dump["nodes_stack"][0]
+    assert root["implicitly_closed"] and root["childrens"][0]["implicitly_closed"]
+    assert root["end_time"] == dump["last_active"]
+
+
+def test_reap_disabled():
+    tr_id = 16 * 10 ** 12 + 1
+    pamagent_core.set_transaction(tr_id, "Idle", "/idle")
+    time.sleep(0.1)
+    assert pamagent_core.reap_transactions(0) == 0
+    assert pamagent_core.drop_transaction(tr_id)
+
+
+def test_reused_id_of_abandoned_transaction():
+    tr_id = 16 * 10 ** 12 + 2
+    pamagent_core.configure({"transaction_max_age": 0.1})
+    try:
+        assert pamagent_core.set_transaction(tr_id, "Killed", "/killed")
+        assert not pamagent_core.set_transaction(tr_id, "Next", "/next")
+        time.sleep(0.2)
+        assert pamagent_core.set_transaction(tr_id, "Next", "/next")
+        assert json.loads(pamagent_core.dump_transaction(tr_id))["base_name"] == "Next"
+        assert pamagent_core.drop_transaction(tr_id)
+    finally:
+        pamagent_core.configure({"transaction_max_age": 600})
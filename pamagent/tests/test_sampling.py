@@ -0,0 +1,117 @@
+import pytest
+
+# noinspection PyUnresolvedReferences
+from pamagent import pamagent_core
+from pamagent.transaction import Transaction
+from pamagent.transaction_cache import current_transaction
+
+INBOUND_TRACE_ID = "4bf92f3577b34da6a3ce929d0e0e4736"
+
+
+@pytest.fixture
+def sampling():
+    def configure(**options):
+        pamagent_core.configure(options)
+
+    yield configure
+    pamagent_core.configure({"sampling_mode": "fixed", "sample_rate": 1.0})
+
+
+def _enqueued():
+    return pamagent_core.get_output_queue_stats()["enqueued"]
+
+
+def test_unsampled_keeps_root_only(sampling):
+    sampling(sample_rate=0.0)
+    tr_id = 18 * 10 ** 12
+    assert pamagent_core.set_transaction(tr_id, "Unsampled", "/unsampled") is True
+    assert pamagent_core.is_sampled(tr_id) is False
+    assert pamagent_core.push_current(tr_id, 1, 10.0, "view")
+    assert pamagent_core.push_current(tr_id, 2, 11.0, "render")
+    assert pamagent_core.push_current_database(tr_id, 3, 11.5, "PostgreSQL", "shop", None, None, "select",
+                                               "orders", "SELECT * FROM orders")
+    assert pamagent_core.pop_current(tr_id, 3, 11.6) is None
+    assert pamagent_core.record_node_error(tr_id, "ValueError", "boom")
+    assert pamagent_core.pop_current(tr_id, 2, 12.0) is None
+    pamagent_core.pop_current(tr_id, 1, 13.0)
+    summary = pamagent_core.get_transaction_summary(tr_id)
+    assert summary["node_count"] == 1
+    assert summary["pop_anomalies"] == 0
+    assert not summary["sampled"] and summary["errored"]
+    headers = pamagent_core.get_outbound_trace_headers(tr_id)
+    assert headers["traceparent"].endswith("-00")
+    enqueued = _enqueued()
+    assert pamagent_core.drop_transaction(tr_id)
+    assert _enqueued() == enqueued
+
+
+def test_current_transaction_skips_unsampled(sampling):
+    sampling(sample_rate=0.0)
+    stats = pamagent_core.get_sampling_stats()
+    tr_id = 18 * 10 ** 12 + 1
+    assert pamagent_core.set_transaction(tr_id, "Skipped", "/skipped") is True
+    assert pamagent_core.is_sampled(tr_id) is False
+    assert pamagent_core.get_transaction(tr_id) == tr_id
+    assert pamagent_core.get_transaction(tr_id, True) is None
+    assert pamagent_core.get_sampling_stats()["not_sampled"] == stats["not_sampled"] + 1
+    assert pamagent_core.drop_transaction(tr_id)
+
+
+def test_inbound_decision_is_followed(sampling):
+    sampling(sample_rate=0.0)
+    tr_id = 18 * 10 ** 12 + 2
+    traceparent = "00-%s-00f067aa0ba902b7-01" % INBOUND_TRACE_ID
+    assert pamagent_core.set_transaction(tr_id, "Inbound", "/inbound", traceparent) is True
+    assert pamagent_core.is_sampled(tr_id) is True
+    assert pamagent_core.drop_transaction(tr_id)
+
+
+def test_rate_limited(sampling):
+    sampling(sampling_mode="rate_limited", sampling_target=3)
+    decisions = []
+    for i in range(10):
+        tr_id = 18 * 10 ** 12 + 10 + i
+        assert pamagent_core.set_transaction(tr_id, "Limited", "/limited")
+        decisions.append(pamagent_core.is_sampled(tr_id))
+        assert pamagent_core.drop_transaction(tr_id)
+    assert decisions.count(True) == 3
+    assert pamagent_core.get_sampling_stats()["probability"] is None
+
+
+def test_adaptive_keeps_errored_and_slow(sampling):
+    sampling(sampling_mode="adaptive", sampling_target=0, slow_transaction_threshold=2.0)
+    stats = pamagent_core.get_sampling_stats()
+    assert stats["mode"] == "adaptive"
+    enqueued = _enqueued()
+    for i, (duration, error) in enumerate([(0.5, False), (0.5, True), (3.0, False)]):
+        tr_id = 18 * 10 ** 12 + 20 + i
+        pamagent_core.set_transaction(tr_id, "Adaptive", "/adaptive")
+        pamagent_core.push_current(tr_id, 1, 10.0, "view")
+        if error:
+            pamagent_core.record_transaction_error(tr_id, "ValueError", "boom")
+        pamagent_core.pop_current(tr_id, 1, 10.0 + duration)
+        assert not pamagent_core.get_transaction_summary(tr_id)["sampled"]
+        assert pamagent_core.drop_transaction(tr_id)
+    assert pamagent_core.get_sampling_stats()["kept"] - stats["kept"] == 2
+    assert _enqueued() - enqueued == 2
+
+
+def test_active_id_is_not_sampled_again(sampling):
+    sampling(sampling_mode="rate_limited", sampling_target=1)
+    tr_id = 18 * 10 ** 12 + 30
+    stats = pamagent_core.get_sampling_stats()
+    assert pamagent_core.set_transaction(tr_id, "Active", "/active") is True
+    assert pamagent_core.set_transaction(tr_id, "Active", "/active") is False
+    after = pamagent_core.get_sampling_stats()
+    assert after["sampled"] + after["not_sampled"] - stats["sampled"] - stats["not_sampled"] == 1
+    assert pamagent_core.is_sampled(tr_id) is True
+    assert pamagent_core.drop_transaction(tr_id)
+    assert pamagent_core.is_sampled(tr_id) is None
+
+
+def test_current_transaction_python(sampling):
+    sampling(sample_rate=0.0)
+    with Transaction(enabled=True) as transaction:
+        assert transaction.sampled is False
+        assert current_transaction() is None
+        assert current_transaction(sampled_only=False) == transaction.thread_id
@@ -0,0 +1,74 @@
+import json
+
+# noinspection PyUnresolvedReferences
+from pamagent import pamagent_core
+
+SQL = "SELECT * FROM orders WHERE total > ?"
+
+
+def _push_query(tr_id, node_id, start_time):
+    pamagent_core.push_current_database(tr_id, node_id, start_time, "PostgreSQL", "shop", "db.local", 5432,
+                                        "select", "orders", SQL)
+
+
+def test_slow_queries():
+    tr_id = 13 * 10 ** 12
+    pamagent_core.set_transaction(tr_id, "Report", "/report")
+    pamagent_core.push_current(tr_id, 1, 10.0, "reports.views.index")
+    _push_query(tr_id, 2, 10.0)
+    assert pamagent_core.set_query_details(tr_id, 2, 120, None, ["app.py:1 in index", "db.py:9 in fetch"])
+    pamagent_core.pop_current(tr_id, 2, 10.1)
+    _push_query(tr_id, 3, 11.0)
+    assert pamagent_core.set_query_details(tr_id, 3, 5000, None, ["app.py:2 in index"])
+    pamagent_core.pop_current(tr_id, 3, 12.5)
+    assert pamagent_core.set_query_details(tr_id, 3, explain_plan="Seq Scan on orders")
+    assert not pamagent_core.set_query_details(tr_id, 2, explain_plan="fast query is not kept")
+    pamagent_core.pop_current(tr_id, 1, 13.0)
+
+    dump = json.loads(pamagent_core.dump_transaction(tr_id))
+    assert len(dump["slow_queries"]) == 1
+    query = dump["slow_queries"][0]
+    assert query["duration"] == 1.5
+    assert query["sql"] == SQL
+    assert query["row_count"] == 5000
+    assert query["stack"] == ["app.py:2 in index"]
+    assert query["explain_plan"] == "Seq Scan on orders"
+    node = dump["nodes_stack"][0]["childrens"][1]
+    assert query["span_id"] == node["span_id"]
+    assert query["sql_fingerprint"] == node["sql_fingerprint"]
+    assert "row_count" not in node and "stack" not in node
+    assert pamagent_core.drop_transaction(tr_id)
+
+
+def test_slow_queries_keep_slowest():
+    pamagent_core.configure({"slow_query_threshold": 0.1})
+    tr_id = 13 * 10 ** 12 + 1
+    pamagent_core.set_transaction(tr_id, "Batch", "/batch")
+    pamagent_core.push_current(tr_id, 1, 0.0, "batch")
+    for i in range(15):
+        _push_query(tr_id, 10 + i, 100.0 + i)
+        pamagent_core.pop_current(tr_id, 10 + i, 100.0 + i + 0.2 + i * 0.01)
+    pamagent_core.pop_current(tr_id, 1, 200.0)
+    durations = [q["duration"] for q in json.loads(pamagent_core.dump_transaction(tr_id))["slow_queries"]]
+    assert len(durations) == 10
+    assert min(durations) > 0.24
+    pamagent_core.configure({"slow_query_threshold": 0})
+    pamagent_core.push_current(tr_id, 99, 300.0, "after")
+    _push_query(tr_id, 100, 300.0)
+    pamagent_core.pop_current(tr_id, 100, 310.0)
+    pamagent_core.pop_current(tr_id, 99, 310.0)
+    assert len(json.loads(pamagent_core.dump_transaction(tr_id))["slow_queries"]) == 10
+    pamagent_core.configure({"slow_query_threshold": 0.5})
+    assert pamagent_core.drop_transaction(tr_id)
+
+
+def test_is_slow_query_follows_configure():
+    assert pamagent_core.is_slow_query(0.5)
+    assert not pamagent_core.is_slow_query(0.4)
+    try:
+        pamagent_core.configure({"slow_query_threshold": 0.2})
+        assert pamagent_core.is_slow_query(0.3)
+        pamagent_core.configure({"slow_query_threshold": 0})
+        assert not pamagent_core.is_slow_query(100.0)
+    finally:
+        pamagent_core.configure({"slow_query_threshold": 0.5})
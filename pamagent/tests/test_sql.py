@@ -0,0 +1,130 @@
+import json
+
+import pytest
+
+# noinspection PyUnresolvedReferences
+from pamagent import pamagent_core
+from pamagent.utils.sql_statement import SQLStatement
+
+
+@pytest.mark.parametrize("sql, quoting_style, expected", [
+    ("SELECT * FROM users WHERE name = 'bob' AND age > 42", None,
+     "SELECT * FROM users WHERE name = ? AND age > ?"),
+    ("SELECT * FROM users WHERE name = 'o''brien'", "single", "SELECT * FROM users WHERE name = ?"),
+    ('SELECT "id" FROM "users" WHERE name = \'bob\'', "single", 'SELECT "id" FROM "users" WHERE name = ?'),
+    ('SELECT `id` FROM users WHERE name = "bob"', "single+double", "SELECT `id` FROM users WHERE name = ?"),
+    ("SELECT $$secret$$, $tag$it's$tag$ FROM t WHERE id = $1", None, "SELECT ?, ? FROM t WHERE id = $1"),
+    ("SELECT * FROM t WHERE a = 0x1F AND b = X'00ff' AND c = 1.5e-3 AND d = .5", None,
+     "SELECT * FROM t WHERE a = ? AND b = ? AND c = ? AND d = ?"),
+    ("UPDATE t SET active = TRUE, deleted = false WHERE id = %s", None,
+     "UPDATE t SET active = ?, deleted = ? WHERE id = %s"),
+    ("SELECT * FROM t WHERE id IN (1, 2, 3) AND x IN (%s, %s) AND y in (?)", None,
+     "SELECT * FROM t WHERE id IN (?) AND x IN (?) AND y in (?)"),
+    ("SELECT * FROM t WHERE id IN (SELECT id FROM u WHERE v = 1)", None,
+     "SELECT * FROM t WHERE id IN (SELECT id FROM u WHERE v = ?)"),
+    ("SELECT /* secret 1 */ a1, b::int FROM t2 -- 'comment'\nWHERE c = :name", None,
+     "SELECT  a1, b::int FROM t2 \nWHERE c = :name"),
+    ("SELECT * FROM t WHERE path = 'C:\\' AND password = 'hunter2'", None,
+     "SELECT * FROM t WHERE path = ? AND password = ?"),
+    ("SELECT * FROM t WHERE a = 'it\\'s' AND b = \"x\\\"y\"", "single+double", "SELECT * FROM t WHERE a = ? AND b = ?"),
+    ("SELECT * FROM t WHERE a = E'it\\'s' AND b = 'c'", None, "SELECT * FROM t WHERE a = ? AND b = ?"),
+])
+def test_obfuscate(sql, quoting_style, expected):
+    assert pamagent_core.parse_sql(sql, quoting_style)[0] == expected
+
+
+@pytest.mark.parametrize("sql, operation, target", [
+    ("SELECT * FROM Users WHERE id = 1", "select", "users"),
+    ('select * from "public"."Orders" o join items i on o.id = i.order_id', "select", "public.orders"),
+    ("SELECT * FROM (SELECT id FROM inner_table) AS x", "select", "inner_table"),
+    ("/* comment */ INSERT INTO `shop`.`orders` (id) VALUES (1)", "insert", "shop.orders"),
+    ("UPDATE [orders] SET paid = 1", "update", "orders"),
+    ("DELETE FROM orders WHERE id = 1", "delete", "orders"),
+    ("CALL refresh_stats(1)", "call", "refresh_stats"),
+    ("COMMIT", "commit", ""),
+    ("VACUUM orders", "", ""),
+    ("", "", ""),
+    ('SELECT * FROM "tablé', "select", "tablé"),
+    ("SELECT * FROM [é", "select", "é"),
+    ('SELECT * FROM "', "select", ""),
+])
+def test_operation_and_target(sql, operation, target):
+    assert pamagent_core.parse_sql(sql)[1:] == (operation, target)
+
+
+def test_cache():
+    sql = "SELECT * FROM cached WHERE id = 1"
+    assert pamagent_core.parse_sql(sql) == pamagent_core.parse_sql(sql)
+    pamagent_core.configure({"sql_cache_size": 0})
+    assert pamagent_core.parse_sql(sql) == ("SELECT * FROM cached WHERE id = ?", "select", "cached")
+    pamagent_core.configure({"sql_cache_size": 1000})
+
+
+def test_sql_statement():
+    statement = SQLStatement("SELECT * FROM t WHERE name = 'x'", "single")
+    assert statement.obfuscated == "SELECT * FROM t WHERE name = ?"
+    assert statement.operation == "select"
+    assert statement.target == "t"
+
+
+def _database_nodes(tr_id):
+    nodes = json.loads(pamagent_core.dump_transaction(tr_id))["nodes_stack"][0]["childrens"]
+    return [n for n in nodes if n["type"] == "Database"]
+
+
+def test_fingerprint_and_query_stats():
+    pamagent_core.get_query_stats(True)
+    tr_id = 11 * 10 ** 12
+    pamagent_core.set_transaction(tr_id, "fingerprint")
+    pamagent_core.push_current(tr_id, 1, 10.0, "root")
+    calls = [
+        ("SELECT * FROM orders WHERE id IN (?, ?)", 11.0, 11.5),
+        ("select *\n  from ORDERS where id in (?)", 12.0, 12.1),
+        ("SELECT * FROM items WHERE id = ?", 13.0, 13.2),
+    ]
+    for node_id, (sql, start_time, end_time) in enumerate(calls, 2):
+        pamagent_core.push_current_database(tr_id, node_id, start_time, "PostgreSQL", "shop", "db.local", 5432,
+                                            "select", "orders", sql)
+        pamagent_core.pop_current(tr_id, node_id, end_time)
+    pamagent_core.pop_current(tr_id, 1, 14.0)
+
+    first, second, third = _database_nodes(tr_id)
+    assert first["sql"] == "SELECT * FROM orders WHERE id IN (?, ?)"
+    assert first["sql_normalized"] == "select * from orders where id in (?)"
+    assert first["sql_fingerprint"] == second["sql_fingerprint"] != third["sql_fingerprint"]
+    assert len(first["sql_fingerprint"]) == 16
+    assert pamagent_core.drop_transaction(tr_id)
+
+    stats = pamagent_core.get_query_stats(True)
+    assert stats["dropped"] == 0
+    orders, items = stats["queries"]
+    assert orders["fingerprint"] == first["sql_fingerprint"]
+    assert orders["normalized"] == "select * from orders where id in (?)"
+    assert orders["database_product"] == "PostgreSQL"
+    assert orders["count"] == 2
+    assert orders["total_time"] == pytest.approx(0.6)
+    assert orders["min_time"] == pytest.approx(0.1)
+    assert orders["max_time"] == pytest.approx(0.5)
+    assert items["count"] == 1
+    assert pamagent_core.get_query_stats()["queries"] == []
+
+
+def test_push_raw_sql_with_quoting_style():
+    tr_id = 11 * 10 ** 12 + 1
+    sql = 'SELECT * FROM users WHERE name = "bob" AND age > 42'
+    obfuscated, operation, target = pamagent_core.parse_sql(sql, "single+double")
+    pamagent_core.set_transaction(tr_id, "raw sql")
+    pamagent_core.push_current(tr_id, 1, 10.0, "root")
+    pamagent_core.push_current_database(tr_id, 2, 11.0, "MySQL", "shop", None, None, operation, target, sql,
+                                        quoting_style="single+double")
+    pamagent_core.pop_current(tr_id, 2, 11.5)
+    pamagent_core.push_current_database(tr_id, 3, 12.0, "MySQL", "shop", None, None, operation, target, obfuscated)
+    pamagent_core.pop_current(tr_id, 3, 12.5)
+    pamagent_core.pop_current(tr_id, 1, 13.0)
+
+    raw, parsed = _database_nodes(tr_id)
+    assert raw["sql"] == obfuscated == "SELECT * FROM users WHERE name = ? AND age > ?"
+    assert raw["sql_normalized"] == parsed["sql_normalized"]
+    assert raw["sql_fingerprint"] == parsed["sql_fingerprint"]
+    assert pamagent_core.drop_transaction(tr_id)
+    pamagent_core.get_query_stats(True)
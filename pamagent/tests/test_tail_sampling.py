@@ -0,0 +1,67 @@
+import pytest
+
+# noinspection PyUnresolvedReferences
+from pamagent import pamagent_core
+
+
+@pytest.fixture
+def rules():
+    def configure(**options):
+        options.setdefault("tail_sampling", True)
+        pamagent_core.configure(options)
+
+    yield configure
+    pamagent_core.configure({"tail_sampling": False, "keep_min_duration": 1.0, "keep_errored": True,
+                             "keep_path_patterns": "", "keep_min_database_calls": 0})
+
+
+def _enqueued():
+    return pamagent_core.get_output_queue_stats()["enqueued"]
+
+
+def _run(tr_id, path, duration=0.1, error=False, queries=0):
+    pamagent_core.set_transaction(tr_id, "Tail", path)
+    pamagent_core.push_current(tr_id, 1, 10.0, "view")
+    for i in range(queries):
+        node_id = 10 + i
+        pamagent_core.push_current_database(tr_id, node_id, 10.0, "PostgreSQL", "shop", None, None, "select",
+                                            "orders", "SELECT * FROM orders WHERE id = %s")
+        pamagent_core.pop_current(tr_id, node_id, 10.001)
+    if error:
+        pamagent_core.record_transaction_error(tr_id, "ValueError", "boom")
+    pamagent_core.pop_current(tr_id, 1, 10.0 + duration)
+    summary = pamagent_core.get_transaction_summary(tr_id)
+    enqueued = _enqueued()
+    assert pamagent_core.drop_transaction(tr_id)
+    return summary, _enqueued() - enqueued == 1
+
+
+def test_keep_rules(rules):
+    rules(keep_min_duration=0.5, keep_path_patterns="/checkout/*, /api/*/pay", keep_min_database_calls=20)
+    stats = pamagent_core.get_sampling_stats()
+    tr_id = 19 * 10 ** 12
+    assert not _run(tr_id, "/orders/")[1]
+    assert _run(tr_id + 1, "/orders/", duration=0.7)[1]
+    assert _run(tr_id + 2, "/orders/", error=True)[1]
+    assert _run(tr_id + 3, "/checkout/42")[1]
+    assert _run(tr_id + 4, "/api/v2/pay")[1]
+    assert not _run(tr_id + 5, "/api/v2/pay/refund")[1]
+    summary, kept = _run(tr_id + 6, "/orders/", queries=25)
+    assert summary["database_calls"] == 25 and kept
+    after = pamagent_core.get_sampling_stats()
+    assert after["tail_kept"] - stats["tail_kept"] == 5
+    assert after["tail_discarded"] - stats["tail_discarded"] == 2
+
+
+def test_discarded_still_counted_in_query_stats(rules):
+    rules(keep_min_duration=0, keep_errored=False)
+    pamagent_core.get_query_stats(True)
+    summary, kept = _run(19 * 10 ** 12 + 10, "/orders/", duration=5.0, error=True, queries=3)
+    assert not kept
+    queries = pamagent_core.get_query_stats()["queries"]
+    assert sum(q["count"] for q in queries) == 3
+
+
+def test_disabled_keeps_all(rules):
+    rules(tail_sampling=False, keep_min_duration=10.0)
+    assert _run(19 * 10 ** 12 + 20, "/orders/")[1]
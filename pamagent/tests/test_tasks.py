@@ -0,0 +1,88 @@
+import json
+
+# noinspection PyUnresolvedReferences
+from pamagent import pamagent_core
+
+TASK_A = 0x7f0000000101
+TASK_B = 0x7f0000000202
+
+
+def _names(node):
+    return [child["func_name"] for child in node["childrens"]]
+
+
+def test_parallel_tasks():
+    tr_id = 14 * 10 ** 12
+    pamagent_core.set_transaction(tr_id, "Gather", "/gather")
+    pamagent_core.push_current(tr_id, 1, 10.0, "handler")
+    pamagent_core.push_current(tr_id, 2, 10.5, "gather")
+    assert pamagent_core.start_task(tr_id, TASK_A)
+    assert pamagent_core.start_task(tr_id, TASK_B)
+    assert not pamagent_core.start_task(tr_id, TASK_A)
+    assert pamagent_core.push_current(tr_id, 3, 11.0, "fetch_a", task_id=TASK_A)
+    assert pamagent_core.push_current(tr_id, 4, 11.0, "fetch_b", task_id=TASK_B)
+    assert pamagent_core.push_current(tr_id, 5, 11.5, "parse_b", task_id=TASK_B)
+    assert pamagent_core.pop_current(tr_id, 3, 12.0, task_id=TASK_A) == 2
+    assert pamagent_core.pop_current(tr_id, 5, 12.5, task_id=TASK_B) == 4
+    assert pamagent_core.pop_current(tr_id, 4, 13.0, task_id=TASK_B) == 2
+    assert pamagent_core.end_task(tr_id, TASK_A, 13.0)
+    assert pamagent_core.end_task(tr_id, TASK_B, 13.0)
+    assert pamagent_core.pop_current(tr_id, 2, 13.5) == 1
+    pamagent_core.pop_current(tr_id, 1, 14.0)
+    dump = json.loads(pamagent_core.dump_transaction(tr_id))
+    gather = dump["nodes_stack"][0]["childrens"][0]
+    assert _names(gather) == ["fetch_a", "fetch_b"]
+    assert _names(gather["childrens"][1]) == ["parse_b"]
+    # Children ran in parallel, their time is longer than of the parent
+    assert gather["exclusive"] == 0.0
+    assert pamagent_core.drop_transaction(tr_id)
+
+
+def test_node_finished_by_other_task():
+    tr_id = 14 * 10 ** 12 + 1
+    pamagent_core.set_transaction(tr_id, "Callback", "/callback")
+    pamagent_core.push_current(tr_id, 1, 10.0, "handler")
+    assert pamagent_core.start_task(tr_id, TASK_A)
+    assert pamagent_core.start_task(tr_id, TASK_B, TASK_A)
+    assert pamagent_core.push_current(tr_id, 2, 11.0, "request", task_id=TASK_A)
+    assert pamagent_core.pop_current(tr_id, 2, 12.0, task_id=TASK_B) == 1
+    assert pamagent_core.pop_current(tr_id, 2, 12.0, task_id=TASK_B) is None
+    pamagent_core.pop_current(tr_id, 1, 13.0)
+    dump = json.loads(pamagent_core.dump_transaction(tr_id))
+    assert _names(dump["nodes_stack"][0]) == ["request"]
+    assert pamagent_core.drop_transaction(tr_id)
+
+
+def test_end_task_closes_open_nodes():
+    tr_id = 14 * 10 ** 12 + 2
+    pamagent_core.set_transaction(tr_id, "Cancel", "/cancel")
+    pamagent_core.push_current(tr_id, 1, 10.0, "handler")
+    assert pamagent_core.start_task(tr_id, TASK_A)
+    assert pamagent_core.push_current(tr_id, 2, 11.0, "outer", task_id=TASK_A)
+    assert pamagent_core.push_current(tr_id, 3, 11.5, "inner", task_id=TASK_A)
+    assert pamagent_core.record_node_error(tr_id, "asyncio.CancelledError", "", task_id=TASK_A)
+    assert pamagent_core.end_task(tr_id, TASK_A, 12.0)
+    assert not pamagent_core.end_task(tr_id, TASK_A, 12.0)
+    assert not pamagent_core.push_current(tr_id, 4, 12.5, "late", task_id=TASK_A)
+    pamagent_core.pop_current(tr_id, 1, 13.0)
+    dump = json.loads(pamagent_core.dump_transaction(tr_id))
+    outer = dump["nodes_stack"][0]["childrens"][0]
+    assert outer["end_time"] == 12.0
+    assert outer["childrens"][0]["error"]["class"] == "asyncio.CancelledError"
+    assert dump["errored"]
+    assert pamagent_core.drop_transaction(tr_id)
+
+
+def test_task_outbound_headers():
+    tr_id = 14 * 10 ** 12 + 3
+    pamagent_core.set_transaction(tr_id, "Outbound", "/outbound")
+    pamagent_core.push_current(tr_id, 1, 10.0, "handler")
+    assert pamagent_core.start_task(tr_id, TASK_A)
+    main = pamagent_core.get_outbound_trace_headers(tr_id)
+    assert pamagent_core.get_outbound_trace_headers(tr_id, TASK_A) == main
+    pamagent_core.push_current_external(tr_id, 2, 11.0, "http://example.com/", "aiohttp", "GET", TASK_A)
+    headers = pamagent_core.get_outbound_trace_headers(tr_id, TASK_A)
+    assert headers["traceparent"] != main["traceparent"]
+    assert pamagent_core.get_outbound_trace_headers(tr_id, TASK_B) is None
+    assert pamagent_core.end_task(tr_id, TASK_A, 12.0)
+    assert pamagent_core.drop_transaction(tr_id)
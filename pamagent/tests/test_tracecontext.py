@@ -0,0 +1,90 @@
+import json
+import re
+
+import pytest
+
+# noinspection PyUnresolvedReferences
+from pamagent import pamagent_core
+from pamagent.trace import ExternalTrace
+
+TRANSACTION_ID = 8 * 10 ** 12
+TRACEPARENT_RE = re.compile(r"^00-([0-9a-f]{32})-([0-9a-f]{16})-0[01]$")
+INBOUND = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+
+
+@pytest.fixture
+def transaction():
+    yield TRANSACTION_ID
+    pamagent_core.drop_transaction(TRANSACTION_ID)
+
+
+def _trace(tr_id):
+    return json.loads(pamagent_core.dump_transaction(tr_id))["trace"]
+
+
+def test_new_trace(transaction):
+    assert pamagent_core.set_transaction(transaction, "trace", "/")
+    assert pamagent_core.get_outbound_trace_headers(transaction) is None
+    pamagent_core.push_current(transaction, 1, 1.0, "root")
+    pamagent_core.push_current_external(transaction, 2, 2.0, "http://example.com/", "requests", "GET")
+    headers = pamagent_core.get_outbound_trace_headers(transaction)
+    match = TRACEPARENT_RE.match(headers["traceparent"])
+    assert match
+    assert "tracestate" not in headers
+    trace = _trace(transaction)
+    assert trace["trace_id"] == match.group(1) == pamagent_core.get_transaction_summary(transaction)["trace_id"]
+    assert "parent_span_id" not in trace
+    external = json.loads(pamagent_core.dump_transaction(transaction))["nodes_stack"][1]
+    assert external["span_id"] == match.group(2)
+
+
+def test_inbound_traceparent(transaction):
+    assert pamagent_core.set_transaction(transaction, "trace", "/", INBOUND, "congo=t61rcWkgMzE")
+    pamagent_core.push_current(transaction, 1, 1.0, "root")
+    headers = pamagent_core.get_outbound_trace_headers(transaction)
+    trace_id, span_id = TRACEPARENT_RE.match(headers["traceparent"]).groups()
+    assert trace_id == "4bf92f3577b34da6a3ce929d0e0e4736"
+    assert span_id != "00f067aa0ba902b7"
+    assert headers["traceparent"].endswith("-01")
+    assert headers["tracestate"] == "congo=t61rcWkgMzE"
+    trace = _trace(transaction)
+    assert trace["parent_span_id"] == "00f067aa0ba902b7"
+    assert trace["sampled"] is True
+
+
+def test_inbound_not_sampled(transaction):
+    pamagent_core.set_transaction(transaction, "trace", "/", INBOUND[:-2] + "00")
+    pamagent_core.push_current(transaction, 1, 1.0, "root")
+    assert pamagent_core.get_outbound_trace_headers(transaction)["traceparent"].endswith("-00")
+
+
+@pytest.mark.parametrize("traceparent", [
+    "",
+    "garbage",
+    "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+    "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+    "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+    "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+    "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+    "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+])
+def test_invalid_traceparent(transaction, traceparent):
+    assert pamagent_core.set_transaction(transaction, "trace", "/", traceparent, "congo=t61rcWkgMzE")
+    trace = _trace(transaction)
+    assert trace["trace_id"] != "4bf92f3577b34da6a3ce929d0e0e4736"
+    assert "parent_span_id" not in trace
+    assert "tracestate" not in trace
+
+
+def test_future_version(transaction):
+    pamagent_core.set_transaction(transaction, "trace", "/", "cc-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-x")
+    assert _trace(transaction)["parent_span_id"] == "00f067aa0ba902b7"
+
+
+def test_external_trace_headers(transaction):
+    pamagent_core.set_transaction(transaction, "trace", "/", INBOUND)
+    pamagent_core.push_current(transaction, 1, 1.0, "root")
+    with ExternalTrace(transaction, "requests", "http://example.com/", "GET") as trace:
+        headers = trace.outbound_headers()
+    assert headers["traceparent"].startswith("00-4bf92f3577b34da6a3ce929d0e0e4736-")
+    assert ExternalTrace(0, "requests", "http://example.com/").outbound_headers() == {}
@@ -1,14 +1,22 @@
+import json
+import sys
+import threading
+
 import pytest
 
+# noinspection PyUnresolvedReferences
+from pamagent import pamagent_core
+from pamagent.trace import FunctionTrace
 from pamagent.transaction import Transaction
+from pamagent.utils.exceptions import exception_info
 
 
 def test_transaction():
     tr = Transaction(enabled=True)
     with tr:
         assert tr.enabled
-        print(tr.start_time)
-        assert 0.0 == tr.start_time
+        assert tr.start_time > 0.0
+        assert tr.end_time == 0.0
 
 
 def test_transaction_disabled():
@@ -33,11 +41,176 @@ def test_drop_transaction():
     tr1 = Transaction(enabled=True)
     with tr:
         assert tr.enabled
-        assert 0.0 == tr1.start_time
+        # Both transactions belong to the current thread
+        assert tr1.start_time == tr.start_time
+    assert 0.0 == tr1.start_time
     with tr1:
         assert tr1.enabled
-        assert 0.0 == tr1.start_time
+        assert tr1.start_time > 0.0
     tr1.enabled = True
     with pytest.raises(RuntimeError) as exc:
         tr1.__exit__(None, None, None)
         assert "No active transaction" in str(exc.value)
+
+
+def test_transactions_in_threads():
+    errors = []
+
+    def worker(n):
+        tr_id = 3 * 10 ** 12 + n
+        if not pamagent_core.set_transaction(tr_id, "thread-{}".format(n), "/"):
+            errors.append(n)
+        pamagent_core.push_current(tr_id, 1, 1.0, "root")
+        for i in range(2, 200):
+            pamagent_core.push_current(tr_id, i, float(i), "f")
+            if pamagent_core.pop_current(tr_id, i, i + 0.5) != 1:
+                errors.append(n)
+        pamagent_core.pop_current(tr_id, 1, 500.0)
+        if '"base_name":"thread-{}"'.format(n) not in pamagent_core.dump_transaction(tr_id):
+            errors.append(n)
+        pamagent_core.drop_transaction(tr_id)
+
+    threads = [threading.Thread(target=worker, args=(n,)) for n in range(16)]
+    for t in threads:
+        t.start()
+    for t in threads:
+        t.join()
+    assert errors == []
+
+
+@pytest.mark.parametrize("shards", [1, None])
+def test_benchmark_cache(shards):
+    metrics = pamagent_core.get_metrics()
+    sampling = pamagent_core.get_sampling_stats()
+    try:
+        result = pamagent_core.benchmark_cache(4, 1000, shards)
+    except pamagent_core.ConfigurationError:
+        pytest.skip("pamagent_core is built without bench feature")
+    assert pamagent_core.get_metrics()["transactions"] == metrics["transactions"]
+    assert pamagent_core.get_sampling_stats() == sampling
+    assert result["ops"] == 4 * 1000 * 2
+    assert result["elapsed"] > 0
+    assert result["ops_per_sec"] > 0
+
+
+def test_transaction_summary():
+    tr_id = 4 * 10 ** 12
+    assert pamagent_core.get_transaction_summary(tr_id) is None
+    pamagent_core.set_transaction(tr_id, "summary", "/")
+    pamagent_core.push_current(tr_id, 1, 10.0, "view")
+    pamagent_core.push_current_database(tr_id, 2, 11.0, "PostgreSQL", "db", None, None, "SELECT", "users",
+                                        "SELECT * FROM users")
+    pamagent_core.push_current_cache(tr_id, 3, 11.5, "0", "localhost", 6379, "GET", "Redis")
+    pamagent_core.pop_current(tr_id, 3, 12.0)
+    pamagent_core.pop_current(tr_id, 2, 13.0)
+    pamagent_core.push_current_external(tr_id, 4, 14.0, "http://example.com/api", "requests", "GET")
+    pamagent_core.pop_current(tr_id, 4, 15.0)
+
+    running = pamagent_core.get_transaction_summary(tr_id)
+    assert running["start_time"] == 10.0
+    assert running["end_time"] == 0.0
+    assert running["node_count"] == 4
+
+    pamagent_core.pop_current(tr_id, 1, 20.0)
+    summary = pamagent_core.get_transaction_summary(tr_id)
+    assert summary["start_time"] == pamagent_core.get_transaction_start_time(tr_id) == 10.0
+    assert summary["end_time"] == pamagent_core.get_transaction_end_time(tr_id) == 20.0
+    assert summary["duration"] == 10.0
+    assert summary["exclusive"] == 7.0
+    assert summary["node_count"] == 4
+    assert summary["max_depth"] == 3
+    assert summary["time_by_type"] == {"func": 7.0, "external": 1.0, "database": 1.5, "cache": 0.5}
+    assert len(summary["guid"]) > 0
+    pamagent_core.drop_transaction(tr_id)
+
+
+def test_error_capture(monkeypatch):
+    dumps = []
+    drop = Transaction.drop_transaction
+
+    def dump_and_drop(self):
+        dumps.append(json.loads(pamagent_core.dump_transaction(self.thread_id)))
+        drop(self)
+
+    monkeypatch.setattr(Transaction, "drop_transaction", dump_and_drop)
+    tr = Transaction(enabled=True)
+    with pytest.raises(KeyError):
+        with tr:
+            with FunctionTrace(tr.thread_id, "view"):
+                raise KeyError("missing")
+    dump, = dumps
+    assert dump["errored"] is True
+    assert dump["error"]["class"] == "KeyError"
+    assert dump["error"]["message"] == "'missing'"
+    node_error = dump["nodes_stack"][0]["childrens"][0]["error"]
+    assert node_error["class"] == "KeyError"
+    assert node_error["message"] == "'missing'"
+
+    # Errors recorded through the core API directly
+    tr_id = 5 * 10 ** 12
+    pamagent_core.set_transaction(tr_id, "errors", "/")
+    pamagent_core.push_current(tr_id, 1, 1.0, "root")
+    pamagent_core.push_current(tr_id, 2, 2.0, "view")
+    try:
+        raise ValueError("bad value")
+    except ValueError:
+        assert pamagent_core.record_node_error(tr_id, *exception_info(*sys.exc_info()))
+    pamagent_core.pop_current(tr_id, 2, 3.0)
+    assert pamagent_core.record_transaction_error(tr_id, "app.Error", "x" * 10000, None)
+    pamagent_core.pop_current(tr_id, 1, 4.0)
+
+    dump = json.loads(pamagent_core.dump_transaction(tr_id))
+    assert dump["errored"] is True
+    assert dump["error"] == {"class": "app.Error", "message": "x" * 4096}
+    node_error = dump["nodes_stack"][0]["childrens"][0]["error"]
+    assert node_error["class"] == "ValueError"
+    assert node_error["message"] == "bad value"
+    assert "test_error_capture" in node_error["frames"][-1]
+    assert pamagent_core.get_transaction_summary(tr_id)["errored"] is True
+    pamagent_core.drop_transaction(tr_id)
+
+
+def test_error_capture_no_transaction():
+    assert not pamagent_core.record_node_error(6 * 10 ** 12, "ValueError", "", None)
+    assert not pamagent_core.record_transaction_error(6 * 10 ** 12, "ValueError", "", None)
+
+
+def test_custom_attributes():
+    tr_id = 7 * 10 ** 12
+    assert not pamagent_core.add_transaction_attribute(tr_id, "tenant", "acme")
+    pamagent_core.set_transaction(tr_id, "attributes", "/")
+    pamagent_core.push_current(tr_id, 1, 1.0, "root")
+    pamagent_core.push_current(tr_id, 2, 2.0, "view")
+    assert pamagent_core.add_transaction_attribute(tr_id, "tenant", "acme")
+    assert pamagent_core.add_transaction_attribute(tr_id, "beta", True)
+    assert pamagent_core.add_transaction_attribute(tr_id, "users", 42)
+    assert pamagent_core.add_transaction_attribute(tr_id, "ratio", 0.5)
+    assert pamagent_core.add_transaction_attribute(tr_id, "release", "x" * 1000)
+    assert pamagent_core.add_node_attribute(tr_id, 2, "flag", "new-checkout")
+    assert not pamagent_core.add_node_attribute(tr_id, 3, "flag", "unknown node")
+    assert not pamagent_core.add_transaction_attribute(tr_id, "k" * 256, "long key")
+    assert not pamagent_core.add_transaction_attribute(tr_id, "", "empty key")
+    assert not pamagent_core.add_transaction_attribute(tr_id, "nan", float("nan"))
+    with pytest.raises(TypeError):
+        pamagent_core.add_transaction_attribute(tr_id, "list", [1])
+    pamagent_core.pop_current(tr_id, 2, 3.0)
+    assert not pamagent_core.add_node_attribute(tr_id, 2, "flag", "popped")
+    pamagent_core.pop_current(tr_id, 1, 4.0)
+
+    dump = json.loads(pamagent_core.dump_transaction(tr_id))
+    assert dump["attributes"] == {"tenant": "acme", "beta": True, "users": 42, "ratio": 0.5, "release": "x" * 255}
+    assert dump["nodes_stack"][0]["childrens"][0]["attributes"] == {"flag": "new-checkout"}
+    assert "attributes" not in dump["nodes_stack"][0]
+    pamagent_core.drop_transaction(tr_id)
+
+
+def test_custom_attributes_count_limit():
+    tr_id = 7 * 10 ** 12 + 1
+    pamagent_core.set_transaction(tr_id, "attributes", "/")
+    for i in range(64):
+        assert pamagent_core.add_transaction_attribute(tr_id, "key{}".format(i), i)
+    assert not pamagent_core.add_transaction_attribute(tr_id, "key64", 64)
+    # Existing attribute can be replaced
+    assert pamagent_core.add_transaction_attribute(tr_id, "key0", "replaced")
+    assert len(json.loads(pamagent_core.dump_transaction(tr_id))["attributes"]) == 64
+    pamagent_core.drop_transaction(tr_id)
@@ -0,0 +1,108 @@
+import json
+
+# noinspection PyUnresolvedReferences
+from pamagent import pamagent_core
+from pamagent.trace import FunctionTrace
+
+
+def _dump(tr_id):
+    return json.loads(pamagent_core.dump_transaction(tr_id))
+
+
+def _push_chain(tr_id, count):
+    for node_id in range(1, count + 1):
+        pamagent_core.push_current(tr_id, node_id, 10.0 + node_id, "f%d" % node_id)
+
+
+def test_pop_skipped_nodes():
+    tr_id = 15 * 10 ** 12
+    pamagent_core.set_transaction(tr_id, "Unwind", "/unwind")
+    _push_chain(tr_id, 4)
+    # __exit__ of f3 and f4 was skipped
+    assert pamagent_core.pop_current(tr_id, 2, 20.0) == 1
+    pamagent_core.pop_current(tr_id, 1, 21.0)
+    dump = _dump(tr_id)
+    assert dump["pop_anomalies"] == 1
+    f2 = dump["nodes_stack"][0]["childrens"][0]
+    f3 = f2["childrens"][0]
+    f4 = f3["childrens"][0]
+    assert "implicitly_closed" not in f2
+    assert f3["implicitly_closed"] and f4["implicitly_closed"]
+    assert f3["end_time"] == f4["end_time"] == 20.0
+    assert f3["exclusive"] == 1.0
+    assert pamagent_core.get_transaction_summary(tr_id)["node_count"] == 4
+    assert pamagent_core.drop_transaction(tr_id)
+
+
+def test_late_pop_of_closed_node():
+    tr_id = 15 * 10 ** 12 + 1
+    pamagent_core.set_transaction(tr_id, "Late", "/late")
+    _push_chain(tr_id, 3)
+    assert pamagent_core.pop_current(tr_id, 2, 20.0) == 1
+    # __exit__ of f3 comes after f2 closed it, nothing is popped
+    assert pamagent_core.pop_current(tr_id, 3, 20.5) is None
+    pamagent_core.push_current(tr_id, 4, 21.0, "f4")
+    assert pamagent_core.pop_current(tr_id, 4, 22.0) == 1
+    pamagent_core.pop_current(tr_id, 1, 23.0)
+    dump = _dump(tr_id)
+    assert dump["pop_anomalies"] == 2
+    assert [n["func_name"] for n in dump["nodes_stack"][0]["childrens"]] == ["f2", "f4"]
+    assert dump["nodes_stack"][0]["childrens"][0]["childrens"][0]["end_time"] == 20.0
+    assert pamagent_core.drop_transaction(tr_id)
+
+
+def test_pop_root_with_open_nodes():
+    tr_id = 15 * 10 ** 12 + 2
+    pamagent_core.set_transaction(tr_id, "Root", "/root")
+    _push_chain(tr_id, 5)
+    pamagent_core.pop_current(tr_id, 1, 30.0)
+    summary = pamagent_core.get_transaction_summary(tr_id)
+    assert summary["end_time"] == 30.0
+    assert summary["max_depth"] == 5
+    assert summary["pop_anomalies"] == 1
+    node = _dump(tr_id)["nodes_stack"][0]
+    for _ in range(4):
+        node = node["childrens"][0]
+        assert node["implicitly_closed"]
+        assert node["end_time"] == 30.0
+    assert pamagent_core.drop_transaction(tr_id)
+
+
+def test_nested_traces_without_exit():
+    tr_id = 15 * 10 ** 12 + 3
+    pamagent_core.set_transaction(tr_id, "Generators", "/generators")
+    with FunctionTrace(tr_id, "view"):
+        with FunctionTrace(tr_id, "render"):
+            # Traces of abandoned generators are entered and never exited
+            abandoned = [FunctionTrace(tr_id, "rows").__enter__(), FunctionTrace(tr_id, "row").__enter__()]
+        with FunctionTrace(tr_id, "respond"):
+            pass
+    assert len(abandoned) == 2
+    dump = _dump(tr_id)
+    assert dump["pop_anomalies"] == 1
+    view = dump["nodes_stack"][0]
+    assert [n["func_name"] for n in view["childrens"]] == ["render", "respond"]
+    rows = view["childrens"][0]["childrens"][0]
+    assert rows["func_name"] == "rows" and rows["implicitly_closed"]
+    assert rows["childrens"][0]["func_name"] == "row" and rows["childrens"][0]["implicitly_closed"]
+    assert "implicitly_closed" not in view["childrens"][1]
+    assert pamagent_core.drop_transaction(tr_id)
+
+
+def test_unwind_task_stack():
+    tr_id = 15 * 10 ** 12 + 4
+    task_id = 0x7f0000000101
+    pamagent_core.set_transaction(tr_id, "Task", "/task")
+    pamagent_core.push_current(tr_id, 1, 10.0, "handler")
+    assert pamagent_core.start_task(tr_id, task_id)
+    pamagent_core.push_current(tr_id, 2, 11.0, "fetch", task_id=task_id)
+    pamagent_core.push_current(tr_id, 3, 12.0, "read", task_id=task_id)
+    assert pamagent_core.pop_current(tr_id, 2, 13.0, task_id=task_id) == 1
+    assert pamagent_core.end_task(tr_id, task_id, 14.0)
+    pamagent_core.pop_current(tr_id, 1, 15.0)
+    dump = _dump(tr_id)
+    assert dump["pop_anomalies"] == 1
+    fetch = dump["nodes_stack"][0]["childrens"][0]
+    assert "implicitly_closed" not in fetch
+    assert fetch["childrens"][0]["implicitly_closed"]
+    assert pamagent_core.drop_transaction(tr_id)
@@ -0,0 +1,100 @@
+import gzip
+import json
+import threading
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+import pytest
+
+# noinspection PyUnresolvedReferences
+from pamagent import pamagent_core
+
+INBOUND = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+
+
+class _ZipkinHandler(BaseHTTPRequestHandler):
+    protocol_version = "HTTP/1.1"
+
+    def do_POST(self):  # noqa: N802
+        body = self.rfile.read(int(self.headers["Content-Length"]))
+        if self.headers.get("Content-Encoding") == "gzip":
+            body = gzip.decompress(body)
+        self.server.requests.append((self.path, dict(self.headers), json.loads(body.decode())))
+        self.send_response(202)
+        self.send_header("Content-Length", "0")
+        self.end_headers()
+
+    def log_message(self, *args):
+        pass
+
+
+@pytest.fixture
+def zipkin_server():
+    server = HTTPServer(("127.0.0.1", 0), _ZipkinHandler)
+    server.requests = []
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    yield server
+    server.shutdown()
+    pamagent_core.shutdown(1.0)
+
+
+def test_zipkin_export(zipkin_server):
+    endpoint = "http://127.0.0.1:%d/api/v2/spans" % zipkin_server.server_port
+    assert pamagent_core.configure_batching(10, 1024 * 1024, 50, "gzip")
+    assert pamagent_core.activate_zipkin(endpoint, "checkout")
+    tr_id = 10 * 10 ** 12
+    pamagent_core.set_transaction(tr_id, "OrderView", "/orders", INBOUND)
+    pamagent_core.add_transaction_attribute(tr_id, "tenant", "acme")
+    pamagent_core.push_current(tr_id, 1, 10.0, "orders.views.index")
+    pamagent_core.push_current_database(tr_id, 2, 11.0, "PostgreSQL", "shop", "10.0.0.5", 5432, "SELECT", "orders",
+                                        "SELECT * FROM orders WHERE id = ?")
+    pamagent_core.pop_current(tr_id, 2, 12.0)
+    pamagent_core.push_current_external(tr_id, 3, 13.0, "https://api.example.com:8443/pay", "requests", "post")
+    pamagent_core.record_node_error(tr_id, "requests.HTTPError", "502 Bad Gateway")
+    pamagent_core.pop_current(tr_id, 3, 14.0)
+    pamagent_core.pop_current(tr_id, 1, 15.0)
+    assert pamagent_core.drop_transaction(tr_id)
+    assert pamagent_core.flush(10.0)
+    pamagent_core.configure_batching(100, 1024 * 1024, 1000)
+
+    path, headers, request = zipkin_server.requests[0]
+    assert path == "/api/v2/spans"
+    assert headers["Content-Type"] == "application/json"
+    spans = {s["name"]: s for s in request}
+    assert set(spans) == {"orderview", "select orders", "post api.example.com"}
+
+    root = spans["orderview"]
+    assert root["traceId"] == "0af7651916cd43dd8448eb211c80319c"
+    assert root["parentId"] == "b7ad6b7169203331"
+    assert root["kind"] == "SERVER"
+    assert root["timestamp"] == 10000000
+    assert root["duration"] == 5000000
+    assert root["localEndpoint"] == {"serviceName": "checkout"}
+    assert "remoteEndpoint" not in root
+    assert root["tags"]["http.path"] == "/orders"
+    assert root["tags"]["tenant"] == "acme"
+
+    db = spans["select orders"]
+    assert db["parentId"] == root["id"]
+    assert db["kind"] == "CLIENT"
+    assert db["timestamp"] == 11000000
+    assert db["duration"] == 1000000
+    assert db["remoteEndpoint"] == {"ipv4": "10.0.0.5", "port": 5432}
+    assert db["tags"] == {"db.type": "postgresql", "db.instance": "shop", "db.operation": "SELECT",
+                          "db.table": "orders", "sql.query": "SELECT * FROM orders WHERE id = ?",
+                          "peer.hostname": "10.0.0.5", "peer.port": "5432"}
+
+    external = spans["post api.example.com"]
+    assert external["parentId"] == db["parentId"]
+    assert external["remoteEndpoint"] == {"serviceName": "api.example.com", "port": 8443}
+    assert external["tags"]["http.method"] == "POST"
+    assert external["tags"]["error"] == "502 Bad Gateway"
+
+
+@pytest.mark.parametrize("endpoint", [
+    "not a url",
+    "ftp://127.0.0.1/api/v2/spans",
+    "http://example.com:9411/api/v2/spans",
+])
+def test_activate_zipkin_invalid(endpoint):
+    with pytest.raises(pamagent_core.ConfigurationError):
+        pamagent_core.activate_zipkin(endpoint)
@@ -1,10 +1,12 @@
 import logging
 import functools
 import time
+import traceback
 from typing import Optional
 
 # noinspection PyUnresolvedReferences
 from pamagent import pamagent_core
+from pamagent.utils.exceptions import exception_info
 from pamagent.utils.sql_statement import sql_statement
 
 from .wrapper import FuncWrapper, wrap_object
@@ -41,6 +43,8 @@ class TimeTrace(object):
         transaction = self.transaction
         self.transaction = None
         self.end_time = time.time()
+        if exc is not None:
+            pamagent_core.record_node_error(transaction, *exception_info(exc, value, tb))
         pamagent_core.pop_current(transaction, id(self), self.end_time)
 
 
@@ -81,10 +85,16 @@ class ExternalTrace(TimeTrace):
         self.activated = True
         return self
 
+    def outbound_headers(self):
+        """W3C Trace Context headers to send with the request, empty dict if trace is not active."""
+        if not self.activated or not self.transaction:
+            return {}
+        return pamagent_core.get_outbound_trace_headers(self.transaction) or {}
+
 
 class DatabaseTrace(TimeTrace):
     __slots__ = ['sql', 'dbapi2_module', 'connect_params', 'cursor_params', 'sql_parameters', 'execute_params', 'host',
-                 'port', 'database_name', '_sql_statement']
+                 'port', 'database_name', '_sql_statement', 'row_count']
 
     def __init__(self, transaction, sql, dbapi2_module=None, connect_params=None, cursor_params=None,
                  sql_parameters=None, execute_params=None, host=None, port=None, database_name=None):
@@ -101,7 +111,10 @@ class DatabaseTrace(TimeTrace):
         self.host = host
         self.port = port
         self.database_name = database_name or connect_params[1].get('database')
-        self._sql_statement = sql_statement(self.sql, self.dbapi2_module)
+        # Hooks pass the module in a tuple, as given to their connection factory
+        self._sql_statement = sql_statement(self.sql, self.dbapi2_module and self.dbapi2_module[0])
+        # Set by hooks after execute, cursor.rowcount as usual
+        self.row_count = None
 
     def _operation(self):
         return self._sql_statement.operation
@@ -119,12 +132,26 @@ class DatabaseTrace(TimeTrace):
     def __enter__(self):
         if not self.transaction:
             return self
-        pamagent_core.push_current_database(self.transaction, id(self), time.time(),
+        self.start_time = time.time()
+        # Raw sql with its quoting style hits the parse cached by parse_sql for operation and target
+        pamagent_core.push_current_database(self.transaction, id(self), self.start_time,
                                             self.dbapi2_module[0]._pam_database_product, self.database_name, self.host,
-                                            int(self.port or 0), self._operation(), self._target(), self._obfuse())
+                                            int(self.port or 0), self._operation(), self._target(), self.sql,
+                                            quoting_style=self._sql_statement.quoting_style or "single")
         self.activated = True
         return self
 
+    def __exit__(self, exc, value, tb):
+        if self.activated and self.transaction:
+            stack = None
+            # Slow query is sent with the stack of database call, threshold is taken from current config
+            if pamagent_core.is_slow_query(time.time() - self.start_time):
+                stack = [frame.rstrip() for frame in traceback.format_stack()[:-1]]
+            row_count = self.row_count if self.row_count is not None and self.row_count >= 0 else None
+            if stack or row_count is not None:
+                pamagent_core.set_query_details(self.transaction, id(self), row_count, None, stack)
+        super(DatabaseTrace, self).__exit__(exc, value, tb)
+
 
 class CacheTrace(TimeTrace):
     def __init__(self, transaction, product, operation, host, port, db=0):
@@ -144,9 +171,10 @@ class CacheTrace(TimeTrace):
         return self
 
 
-def external_trace_wrapper(wrapped, library, url, method):
+def external_trace_wrapper(wrapped, library, url, method, inject=None):
     def dynamic_wrapper(wrapped_func, instance, args, kwargs):
-        transaction = current_transaction()
+        # Unsampled transaction still propagates its decision in outbound headers
+        transaction = current_transaction(sampled_only=False)
 
         if transaction is None:
             return wrapped_func(*args, **kwargs)
@@ -169,18 +197,29 @@ def external_trace_wrapper(wrapped, library, url, method):
         else:
             _method = method
 
-        with ExternalTrace(transaction, library, _url, _method):
+        with ExternalTrace(transaction, library, _url, _method) as trace:
+            if inject is not None:
+                headers = trace.outbound_headers()
+                if headers:
+                    if instance is not None:
+                        inject(headers, instance, *args, **kwargs)
+                    else:
+                        inject(headers, *args, **kwargs)
             return wrapped_func(*args, **kwargs)
 
     return FuncWrapper(wrapped, dynamic_wrapper)
 
 
-def external_trace(library, url, method=None):
-    return functools.partial(external_trace_wrapper, library=library, url=url, method=method)
+def external_trace(library, url, method=None, inject=None):
+    return functools.partial(external_trace_wrapper, library=library, url=url, method=method, inject=inject)
 
 
-def wrap_external_trace(module, object_path, library, url, method=None):
-    wrap_object(module, object_path, external_trace_wrapper, library, url, method)
+def wrap_external_trace(module, object_path, library, url, method=None, inject=None):
+    """
+    :param inject: callable that adds trace headers (dict) to outbound request, called with wrapped
+                   function arguments after the headers.
+    """
+    wrap_object(module, object_path, external_trace_wrapper, library, url, method, inject)
 
 
 def register_database_client(dbapi2_module, database_product, quoting_style='single', instance_info=None):
@@ -195,7 +234,8 @@ def register_database_client(dbapi2_module, database_product, quoting_style='sin
 
 def database_trace_wrapper(wrapped, sql, dbapi2_module=None):
     def _pam_database_trace_wrapper_(wrapped_func, instance, args, kwargs):
-        transaction = current_transaction()
+        # Calls of unsampled transaction are still counted in metrics
+        transaction = current_transaction(sampled_only=False)
 
         if transaction is None:
             return wrapped_func(*args, **kwargs)
@@ -3,8 +3,10 @@ import time
 
 # noinspection PyUnresolvedReferences
 from pamagent import pamagent_core
+from pamagent.utils.exceptions import exception_info
 
-from .transaction_cache import save_transaction, drop_transaction, current_thread_id, get_start_time, get_end_time
+from .transaction_cache import (save_transaction, drop_transaction, current_thread_id, get_start_time, get_end_time,
+                                get_summary, get_insights)
 
 
 _logger = logging.getLogger(__name__)
@@ -25,6 +27,11 @@ class Transaction(object):
         self._path = ""
         self._read_start = 0.0
         self._read_end = 0.0
+        # W3C Trace Context of inbound request
+        self.traceparent = None
+        self.tracestate = None
+        # Head sampling decision, only the root node of unsampled transaction is recorded
+        self.sampled = False
         if enabled:
             self.enabled = True
 
@@ -36,6 +43,16 @@ class Transaction(object):
     def end_time(self):
         return get_end_time(self)
 
+    @property
+    def summary(self):
+        """Timing of transaction as dict, see `pamagent_core.get_transaction_summary`."""
+        return get_summary(self)
+
+    @property
+    def insights(self):
+        """Performance problems like N+1 queries, see `pamagent_core.get_transaction_insights`."""
+        return get_insights(self)
+
     def __del__(self):
         if self._state == self.STATE_RUNNING:
             self.__exit__(None, None, None)
@@ -71,7 +88,8 @@ class Transaction(object):
             return
 
         try:
-            print(self.end_time)
+            if exc is not None:
+                pamagent_core.record_transaction_error(self.thread_id, *exception_info(exc, value, tb))
             pamagent_core.pop_current(self.thread_id, id(self), time.time())
             self.drop_transaction()
         except Exception:
@@ -92,6 +110,9 @@ class Transaction(object):
         self._path = path
         pamagent_core.set_transaction_path(self.thread_id, path)
 
+    def add_attribute(self, key, value):
+        return pamagent_core.add_transaction_attribute(self.thread_id, key, value)
+
     @property
     def path(self):
         return self._path
@@ -15,20 +15,25 @@ def current_thread_id():
     return _thread.get_ident()
 
 
-def current_transaction():
+def current_transaction(sampled_only=True):
     """
     Return the transaction object if one exists for the currently executing thread.
+
+    :param sampled_only: skip transaction that is not sampled, function hooks have nothing to record for it.
+                         Database, cache and external hooks pass False, calls are counted in metrics.
     """
-    return pamagent_core.get_transaction(_thread.get_ident())
+    return pamagent_core.get_transaction(_thread.get_ident(), sampled_only)
 
 
 def save_transaction(transaction):
     """
     Saves the specified transaction away under the thread ID of the current executing thread.
     """
-    res = pamagent_core.set_transaction(id=transaction.thread_id, transaction=transaction.name, path=transaction.path)
+    res = pamagent_core.set_transaction(id=transaction.thread_id, transaction=transaction.name, path=transaction.path,
+                                        traceparent=transaction.traceparent, tracestate=transaction.tracestate)
     if not res:
         raise RuntimeError('Transaction already active')
+    transaction.sampled = pamagent_core.is_sampled(transaction.thread_id)
 
 
 def drop_transaction(transaction):
@@ -51,9 +56,28 @@ def set_transaction_path(path):
         raise RuntimeError("No active transaction")
 
 
+def add_custom_attribute(key, value):
+    """
+    Add attribute to transaction of the current thread, e.g. tenant id or release version.
+
+    :param key: attribute name
+    :param value: str, int, float or bool
+    :return: True if attribute is added
+    """
+    return pamagent_core.add_transaction_attribute(current_thread_id(), key, value)
+
+
 def get_start_time(transaction):
     return pamagent_core.get_transaction_start_time(id=transaction.thread_id)
 
 
 def get_end_time(transaction):
     return pamagent_core.get_transaction_end_time(id=transaction.thread_id)
+
+
+def get_summary(transaction):
+    return pamagent_core.get_transaction_summary(id=transaction.thread_id)
+
+
+def get_insights(transaction):
+    return pamagent_core.get_transaction_insights(id=transaction.thread_id)
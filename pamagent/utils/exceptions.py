@@ -0,0 +1,19 @@
+import traceback
+
+
+def exception_info(exc, value, tb):
+    """
+    Convert exc info into arguments of pamagent_core.record_node_error/record_transaction_error.
+
+    :return: qualified class name, message and formatted traceback frames
+    """
+    module = getattr(exc, '__module__', None)
+    name = getattr(exc, '__qualname__', None) or getattr(exc, '__name__', repr(exc))
+    if module and module != 'builtins':
+        name = '{}.{}'.format(module, name)
+    try:
+        message = str(value) if value is not None else ''
+    except Exception:
+        message = '<unprintable {} object>'.format(name)
+    frames = [frame.rstrip() for frame in traceback.format_tb(tb)] if tb is not None else None
+    return name, message, frames
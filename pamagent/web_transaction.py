@@ -21,6 +21,8 @@ class WebTransaction(Transaction):
         self._name = "Uri"
         if not self.enabled:
             return
+        self.traceparent = environ.get('HTTP_TRACEPARENT')
+        self.tracestate = environ.get('HTTP_TRACESTATE')
         port = environ.get('SERVER_PORT')
         try:
             self._port = int(port)
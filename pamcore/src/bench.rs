@@ -0,0 +1,85 @@
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::Instant;
+
+use core::{FuncNode, ShardedCache, StackNode, TransactionCache, MAIN_TASK};
+use tracecontext::TraceContext;
+
+/// Transaction is restarted after this many nodes, like a request of web server.
+const TRANSACTION_NODES: usize = 100;
+
+/// Result of one benchmark run.
+pub struct BenchResult {
+    /// Count of push and pop calls done by all threads.
+    pub ops: u64,
+    pub elapsed: f64,
+}
+
+impl BenchResult {
+    pub fn ops_per_sec(&self) -> f64 {
+        if self.elapsed > 0.0 {
+            self.ops as f64 / self.elapsed
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Measure push/pop throughput of transaction cache with `shards` shards. Every thread opens its
+/// own transaction, like a worker thread of web server, and pushes and pops `iterations` nodes.
+/// One shard is the single global lock used before sharding. The cache is private and only
+/// function nodes are pushed, without sampling, so query stats, metrics and sampling stats of
+/// the agent are untouched.
+pub fn run_cache_bench(threads: usize, iterations: usize, shards: usize) -> BenchResult {
+    let threads = threads.max(1);
+    let cache = Arc::new(ShardedCache::new(shards));
+    let barrier = Arc::new(Barrier::new(threads + 1));
+    let handles: Vec<thread::JoinHandle<()>> = (0..threads)
+        .map(|n| {
+            let cache = cache.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                // Spread ids like addresses of thread stacks
+                let id: u64 = 0x7f00_0000_0000 + (n as u64) * 0x80_0000;
+                barrier.wait();
+                for i in 0..iterations {
+                    if i % TRANSACTION_NODES == 0 {
+                        cache.discard(id);
+                        cache.write(id).set_transaction(
+                            id,
+                            "bench".to_owned(),
+                            None,
+                            TraceContext::new(),
+                            |trace| trace.sampled,
+                        );
+                        cache.write(id).push_current(
+                            id,
+                            StackNode::Func(FuncNode::new(0, 0.0, "root".to_owned())),
+                            MAIN_TASK,
+                        );
+                    }
+                    let node_id = i as u64 + 1;
+                    let start_time = i as f64;
+                    cache.write(id).push_current(
+                        id,
+                        StackNode::Func(FuncNode::new(node_id, start_time, "f".to_owned())),
+                        MAIN_TASK,
+                    );
+                    cache.write(id).pop_current(id, node_id, start_time + 0.5, MAIN_TASK);
+                }
+            })
+        })
+        .collect();
+    barrier.wait();
+    let start = Instant::now();
+    for handle in handles {
+        if handle.join().is_err() {
+            error!("Benchmark thread panicked");
+        }
+    }
+    let elapsed = start.elapsed();
+    BenchResult {
+        ops: (threads * iterations * 2) as u64,
+        elapsed: elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9,
+    }
+}
@@ -0,0 +1,641 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use toml;
+
+use core;
+use http;
+use logging;
+use metrics;
+use output::{self, BatchConfig, Compression, OverflowPolicy};
+use reaper;
+use sampling::{self, KeepRules, SamplingMode};
+use sql;
+use tls::{self, TlsConfig};
+use worker;
+use error::recover;
+
+/// Prefix of environment variables, `PAMAGENT_COLLECTOR_HOST` sets `collector_host` and so on.
+const ENV_PREFIX: &str = "PAMAGENT_";
+const CONFIG_FILE_KEY: &str = "PAMAGENT_CONFIG_FILE";
+/// Old name of `PAMAGENT_LOG_LEVEL`, still honored.
+const LEGACY_LOG_LEVEL_KEY: &str = "PAMAGENT_LEVEL_LOG";
+/// Section of TOML/INI file with agent settings. Keys outside of any section are read as well.
+const FILE_SECTION: &str = "pamagent";
+const SECRET_MASK: &str = "********";
+
+/// Names of every setting. Unknown `PAMAGENT_*` environment variables are ignored,
+/// unknown keys in config file or `configure` are errors.
+pub const KEYS: &[&str] = &[
+    "token",
+    "collector_host",
+    "plaintext",
+    "tls_hostname",
+    "ca_file",
+    "client_cert",
+    "client_cert_password",
+    "min_tls_version",
+    "socket_timeout",
+    "output_file",
+    "output_file_max_bytes",
+    "output_file_max_files",
+    "otlp_endpoint",
+    "otlp_headers",
+    "zipkin_endpoint",
+    "service_name",
+    "shutdown_timeout",
+    "batch_max_items",
+    "batch_max_bytes",
+    "batch_max_delay_ms",
+    "compression",
+    "queue_max_items",
+    "queue_max_bytes",
+    "queue_policy",
+    "sampling_mode",
+    "sample_rate",
+    "sampling_target",
+    "slow_transaction_threshold",
+    "tail_sampling",
+    "keep_min_duration",
+    "keep_errored",
+    "keep_path_patterns",
+    "keep_min_database_calls",
+    "record_sql",
+    "sql_cache_size",
+    "n_plus_one_threshold",
+    "slow_query_threshold",
+    "metrics_harvest_interval",
+    "transaction_max_age",
+    "max_nodes",
+    "max_depth",
+    "log_level",
+    "strict",
+];
+
+lazy_static! {
+    pub static ref CONFIG: RwLock<Config> = { RwLock::new(Config::default()) };
+    static ref OVERRIDES: RwLock<Overrides> = { RwLock::new(Overrides::default()) };
+}
+
+/// Everything passed to `configure` so far.
+#[derive(Default)]
+struct Overrides {
+    config_file: Option<String>,
+    values: BTreeMap<String, String>,
+}
+
+#[derive(Debug)]
+pub struct ConfigError(pub String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Agent configuration.
+///
+/// Values are taken, from lowest to highest precedence, from defaults, config file
+/// (`PAMAGENT_CONFIG_FILE` or `config_file` argument of `configure`), `PAMAGENT_*` environment
+/// variables and the dict passed to `configure`.
+#[derive(Clone, Debug, Serialize)]
+pub struct Config {
+    // Transport
+    pub token: String,
+    pub collector_host: String,
+    pub plaintext: bool,
+    pub tls_hostname: Option<String>,
+    pub ca_file: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_cert_password: Option<String>,
+    pub min_tls_version: Option<String>,
+    /// Seconds a read or write on socket of PAMCollector or exporter may block.
+    pub socket_timeout: f64,
+    pub output_file: Option<String>,
+    pub output_file_max_bytes: u64,
+    pub output_file_max_files: u32,
+    pub otlp_endpoint: Option<String>,
+    /// Extra headers of OTLP requests as `key=value,key2=value2`, e.g. for auth.
+    pub otlp_headers: Option<String>,
+    pub zipkin_endpoint: Option<String>,
+    pub service_name: String,
+    pub shutdown_timeout: f64,
+    pub batch_max_items: usize,
+    pub batch_max_bytes: usize,
+    pub batch_max_delay_ms: u64,
+    pub compression: String,
+    // Queue
+    pub queue_max_items: usize,
+    pub queue_max_bytes: usize,
+    pub queue_policy: String,
+    // Sampling
+    /// fixed, rate_limited or adaptive.
+    pub sampling_mode: String,
+    /// Share of transactions recorded in fixed mode.
+    pub sample_rate: f64,
+    /// Transactions per second recorded in rate_limited and adaptive modes.
+    pub sampling_target: f64,
+    /// Duration in seconds from which unsampled transactions are sent in adaptive mode, 0
+    /// disables it.
+    pub slow_transaction_threshold: f64,
+    /// Send sampled transaction only if it matches one of keep_* rules when it is dropped.
+    pub tail_sampling: bool,
+    pub keep_min_duration: f64,
+    pub keep_errored: bool,
+    /// Comma separated glob patterns of transaction path.
+    pub keep_path_patterns: String,
+    pub keep_min_database_calls: usize,
+    // Obfuscation
+    pub record_sql: String,
+    /// Count of parsed SQL statements kept in LRU cache, 0 disables it.
+    pub sql_cache_size: usize,
+    // Insights
+    /// Calls of the same query under one parent node reported as N+1, 0 disables detection.
+    pub n_plus_one_threshold: usize,
+    /// Duration in seconds from which database calls are reported as slow queries, 0 disables it.
+    pub slow_query_threshold: f64,
+    // Metrics
+    /// Seconds between harvests of timeslice metrics, 0 disables harvest.
+    pub metrics_harvest_interval: f64,
+    // Limits
+    /// Seconds of inactivity after which transaction that was not dropped is closed, 0 disables.
+    pub transaction_max_age: f64,
+    /// Limits of trace nodes kept in tree of transaction, 0 is unlimited.
+    pub max_nodes: usize,
+    pub max_depth: usize,
+    // Logging
+    pub log_level: u8,
+    // Errors
+    /// Raise `PamAgentError` from tracing calls instead of logging errors.
+    pub strict: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        let batch = BatchConfig::default();
+        Config {
+            token: "".to_owned(),
+            collector_host: "pamcollector.pushamp.com".to_owned(),
+            plaintext: false,
+            tls_hostname: None,
+            ca_file: None,
+            client_cert: None,
+            client_cert_password: None,
+            min_tls_version: None,
+            socket_timeout: output::DEFAULT_SOCKET_TIMEOUT,
+            output_file: None,
+            output_file_max_bytes: 0,
+            output_file_max_files: 0,
+            otlp_endpoint: None,
+            otlp_headers: None,
+            zipkin_endpoint: None,
+            service_name: "unknown_service:python".to_owned(),
+            shutdown_timeout: 5.0,
+            batch_max_items: batch.max_items,
+            batch_max_bytes: batch.max_bytes,
+            batch_max_delay_ms: 1000,
+            compression: "none".to_owned(),
+            queue_max_items: output::DEFAULT_QUEUE_MAX_ITEMS,
+            queue_max_bytes: output::DEFAULT_QUEUE_MAX_BYTES,
+            queue_policy: "drop_oldest".to_owned(),
+            sampling_mode: "fixed".to_owned(),
+            sample_rate: 1.0,
+            sampling_target: sampling::DEFAULT_SAMPLING_TARGET,
+            slow_transaction_threshold: sampling::DEFAULT_SLOW_TRANSACTION_THRESHOLD,
+            tail_sampling: false,
+            keep_min_duration: sampling::DEFAULT_KEEP_MIN_DURATION,
+            keep_errored: true,
+            keep_path_patterns: "".to_owned(),
+            keep_min_database_calls: 0,
+            record_sql: "obfuscated".to_owned(),
+            sql_cache_size: sql::DEFAULT_SQL_CACHE_SIZE,
+            n_plus_one_threshold: core::DEFAULT_N_PLUS_ONE_THRESHOLD,
+            slow_query_threshold: core::DEFAULT_SLOW_QUERY_THRESHOLD,
+            metrics_harvest_interval: metrics::DEFAULT_METRICS_HARVEST_INTERVAL,
+            transaction_max_age: reaper::DEFAULT_TRANSACTION_MAX_AGE,
+            max_nodes: core::DEFAULT_MAX_NODES,
+            max_depth: core::DEFAULT_MAX_DEPTH,
+            log_level: 0,
+            strict: false,
+        }
+    }
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, ConfigError> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| ConfigError(format!("Invalid value {:?} for {}", value, key)))
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, ConfigError> {
+    match value.trim().to_lowercase().as_ref() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => Err(ConfigError(format!("Invalid value {:?} for {}", value, key))),
+    }
+}
+
+fn parse_opt(value: &str) -> Option<String> {
+    match value.trim() {
+        "" => None,
+        v => Some(v.to_owned()),
+    }
+}
+
+fn check_choice(key: &str, value: &str, valid: bool) -> Result<String, ConfigError> {
+    if valid {
+        Ok(value.to_owned())
+    } else {
+        Err(ConfigError(format!("Invalid value {:?} for {}", value, key)))
+    }
+}
+
+impl Config {
+    /// Set one setting from its string representation.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        match key {
+            "token" => self.token = value.to_owned(),
+            "collector_host" => self.collector_host = value.trim().to_owned(),
+            "plaintext" => self.plaintext = parse_bool(key, value)?,
+            "tls_hostname" => self.tls_hostname = parse_opt(value),
+            "ca_file" => self.ca_file = parse_opt(value),
+            "client_cert" => self.client_cert = parse_opt(value),
+            "client_cert_password" => self.client_cert_password = parse_opt(value),
+            "min_tls_version" => {
+                self.min_tls_version = match parse_opt(value) {
+                    Some(v) => Some(check_choice(key, &v, tls::parse_protocol(&v).is_ok())?),
+                    None => None,
+                }
+            }
+            "socket_timeout" => {
+                let timeout: f64 = parse(key, value)?;
+                if timeout.is_nan() || timeout <= 0.0 {
+                    return Err(ConfigError(format!("{} must be positive", key)));
+                }
+                self.socket_timeout = timeout;
+            }
+            "output_file" => self.output_file = parse_opt(value),
+            "output_file_max_bytes" => self.output_file_max_bytes = parse(key, value)?,
+            "output_file_max_files" => self.output_file_max_files = parse(key, value)?,
+            "otlp_endpoint" => self.otlp_endpoint = parse_opt(value),
+            "otlp_headers" => {
+                self.otlp_headers = match parse_opt(value) {
+                    Some(v) => {
+                        http::parse_headers(&v).map_err(|e| ConfigError(e.to_string()))?;
+                        Some(v)
+                    }
+                    None => None,
+                }
+            }
+            "zipkin_endpoint" => self.zipkin_endpoint = parse_opt(value),
+            "service_name" => {
+                self.service_name = match parse_opt(value) {
+                    Some(v) => v,
+                    None => return Err(ConfigError(format!("{} must not be empty", key))),
+                }
+            }
+            "shutdown_timeout" => {
+                let timeout: f64 = parse(key, value)?;
+                if timeout.is_nan() || timeout < 0.0 {
+                    return Err(ConfigError(format!("{} must not be negative", key)));
+                }
+                self.shutdown_timeout = timeout;
+            }
+            "batch_max_items" => self.batch_max_items = parse(key, value)?,
+            "batch_max_bytes" => self.batch_max_bytes = parse(key, value)?,
+            "batch_max_delay_ms" => self.batch_max_delay_ms = parse(key, value)?,
+            "compression" => {
+                self.compression = check_choice(key, value, Compression::parse(value).is_some())?
+            }
+            "queue_max_items" => self.queue_max_items = parse(key, value)?,
+            "queue_max_bytes" => self.queue_max_bytes = parse(key, value)?,
+            "queue_policy" => {
+                self.queue_policy =
+                    check_choice(key, value, OverflowPolicy::parse(value).is_some())?
+            }
+            "sampling_mode" => {
+                self.sampling_mode =
+                    check_choice(key, value, SamplingMode::parse(value).is_some())?
+            }
+            "sample_rate" => {
+                let rate: f64 = parse(key, value)?;
+                if !(0.0..=1.0).contains(&rate) {
+                    return Err(ConfigError(format!("{} must be between 0.0 and 1.0", key)));
+                }
+                self.sample_rate = rate;
+            }
+            "sampling_target" => {
+                let target: f64 = parse(key, value)?;
+                if target.is_nan() || target < 0.0 {
+                    return Err(ConfigError(format!("{} must not be negative", key)));
+                }
+                self.sampling_target = target;
+            }
+            "slow_transaction_threshold" => {
+                let threshold: f64 = parse(key, value)?;
+                if threshold.is_nan() || threshold < 0.0 {
+                    return Err(ConfigError(format!("{} must not be negative", key)));
+                }
+                self.slow_transaction_threshold = threshold;
+            }
+            "tail_sampling" => self.tail_sampling = parse_bool(key, value)?,
+            "keep_min_duration" => {
+                let duration: f64 = parse(key, value)?;
+                if duration.is_nan() || duration < 0.0 {
+                    return Err(ConfigError(format!("{} must not be negative", key)));
+                }
+                self.keep_min_duration = duration;
+            }
+            "keep_errored" => self.keep_errored = parse_bool(key, value)?,
+            "keep_path_patterns" => self.keep_path_patterns = value.trim().to_owned(),
+            "keep_min_database_calls" => self.keep_min_database_calls = parse(key, value)?,
+            "record_sql" => {
+                self.record_sql = check_choice(key, value, value == "obfuscated" || value == "off")?
+            }
+            "sql_cache_size" => self.sql_cache_size = parse(key, value)?,
+            "n_plus_one_threshold" => {
+                let threshold: usize = parse(key, value)?;
+                if threshold == 1 {
+                    return Err(ConfigError(format!("{} must be 0 or at least 2", key)));
+                }
+                self.n_plus_one_threshold = threshold;
+            }
+            "slow_query_threshold" => {
+                let threshold: f64 = parse(key, value)?;
+                if threshold.is_nan() || threshold < 0.0 {
+                    return Err(ConfigError(format!("{} must not be negative", key)));
+                }
+                self.slow_query_threshold = threshold;
+            }
+            "metrics_harvest_interval" => {
+                let interval: f64 = parse(key, value)?;
+                if interval.is_nan() || interval < 0.0 {
+                    return Err(ConfigError(format!("{} must not be negative", key)));
+                }
+                self.metrics_harvest_interval = interval;
+            }
+            "transaction_max_age" => {
+                let max_age: f64 = parse(key, value)?;
+                if max_age.is_nan() || max_age < 0.0 {
+                    return Err(ConfigError(format!("{} must not be negative", key)));
+                }
+                self.transaction_max_age = max_age;
+            }
+            "max_nodes" => self.max_nodes = parse(key, value)?,
+            "max_depth" => self.max_depth = parse(key, value)?,
+            "log_level" => {
+                let level: u8 = parse(key, value)?;
+                if level > 3 {
+                    return Err(ConfigError(format!("{} must be between 0 and 3", key)));
+                }
+                self.log_level = level;
+            }
+            "strict" => self.strict = parse_bool(key, value)?,
+            _ => return Err(ConfigError(format!("Unknown setting {}", key))),
+        }
+        Ok(())
+    }
+
+    pub fn tls_config(&self) -> TlsConfig {
+        TlsConfig {
+            plaintext: self.plaintext,
+            hostname: self.tls_hostname.clone(),
+            ca_file: self.ca_file.clone(),
+            client_cert: self.client_cert.clone(),
+            client_cert_password: self.client_cert_password.clone(),
+            min_version: self.min_tls_version.clone(),
+        }
+    }
+
+    pub fn batch_config(&self) -> BatchConfig {
+        BatchConfig {
+            max_items: self.batch_max_items,
+            max_bytes: self.batch_max_bytes,
+            max_delay: Duration::from_millis(self.batch_max_delay_ms),
+            compression: Compression::parse(&self.compression).unwrap_or(Compression::None),
+        }
+    }
+
+    pub fn keep_rules(&self) -> KeepRules {
+        KeepRules {
+            enabled: self.tail_sampling,
+            min_duration: self.keep_min_duration,
+            errored: self.keep_errored,
+            path_patterns: self
+                .keep_path_patterns
+                .split(',')
+                .map(|v| v.trim())
+                .filter(|v| !v.is_empty())
+                .map(|v| v.to_owned())
+                .collect(),
+            min_database_calls: self.keep_min_database_calls,
+        }
+    }
+
+    /// Copy of config with secrets replaced by mask, for displaying.
+    pub fn masked(&self) -> Config {
+        let mut config = self.clone();
+        if !config.token.is_empty() {
+            config.token = SECRET_MASK.to_owned();
+        }
+        if config.client_cert_password.is_some() {
+            config.client_cert_password = Some(SECRET_MASK.to_owned());
+        }
+        if config.otlp_headers.is_some() {
+            config.otlp_headers = Some(SECRET_MASK.to_owned());
+        }
+        config
+    }
+
+    fn apply(
+        &mut self,
+        source: &str,
+        values: &BTreeMap<String, String>,
+    ) -> Result<(), ConfigError> {
+        for (key, value) in values {
+            self.set(key, value)
+                .map_err(|e| ConfigError(format!("{} ({})", e, source)))?;
+        }
+        Ok(())
+    }
+}
+
+fn toml_to_string(value: &toml::Value) -> Option<String> {
+    match *value {
+        toml::Value::String(ref v) => Some(v.clone()),
+        toml::Value::Integer(v) => Some(v.to_string()),
+        toml::Value::Float(v) => Some(v.to_string()),
+        toml::Value::Boolean(v) => Some(v.to_string()),
+        _ => None,
+    }
+}
+
+fn parse_toml(content: &str) -> Result<BTreeMap<String, String>, ConfigError> {
+    let value: toml::Value = content
+        .parse()
+        .map_err(|e| ConfigError(format!("Invalid TOML: {}", e)))?;
+    let mut values: BTreeMap<String, String> = BTreeMap::new();
+    let root = match value.as_table() {
+        Some(v) => v,
+        None => return Ok(values),
+    };
+    let section = root.get(FILE_SECTION).and_then(|v| v.as_table());
+    for table in Some(root).into_iter().chain(section) {
+        for (key, value) in table {
+            if let Some(v) = toml_to_string(value) {
+                values.insert(key.clone(), v);
+            }
+        }
+    }
+    Ok(values)
+}
+
+fn parse_ini(content: &str) -> Result<BTreeMap<String, String>, ConfigError> {
+    let mut values: BTreeMap<String, String> = BTreeMap::new();
+    let mut in_section: bool = true;
+    for (num, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            in_section = line[1..line.len() - 1].trim() == FILE_SECTION;
+            continue;
+        }
+        if !in_section {
+            continue;
+        }
+        let pos = match line.find('=').or_else(|| line.find(':')) {
+            Some(v) => v,
+            None => return Err(ConfigError(format!("Invalid line {} in INI file", num + 1))),
+        };
+        let value = line[pos + 1..].trim().trim_matches('"');
+        values.insert(line[..pos].trim().to_owned(), value.to_owned());
+    }
+    Ok(values)
+}
+
+/// Read TOML (`.toml`) or INI (any other extension) config file.
+fn read_file(path: &str) -> Result<BTreeMap<String, String>, ConfigError> {
+    let mut content = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut content))
+        .map_err(|e| ConfigError(format!("Unable to read config file {}: {}", path, e)))?;
+    let values = if path.ends_with(".toml") {
+        parse_toml(&content)
+    } else {
+        parse_ini(&content)
+    };
+    values.map_err(|e| ConfigError(format!("{} in {}", e, path)))
+}
+
+fn read_env() -> BTreeMap<String, String> {
+    let mut values: BTreeMap<String, String> = BTreeMap::new();
+    if let Ok(v) = env::var(LEGACY_LOG_LEVEL_KEY) {
+        values.insert("log_level".to_owned(), v);
+    }
+    for (key, value) in env::vars() {
+        if key == CONFIG_FILE_KEY || key == LEGACY_LOG_LEVEL_KEY || !key.starts_with(ENV_PREFIX) {
+            continue;
+        }
+        let name = key[ENV_PREFIX.len()..].to_lowercase();
+        if KEYS.contains(&name.as_str()) {
+            values.insert(name, value);
+        }
+    }
+    values
+}
+
+/// Build config from every source. `config_file` takes precedence over `PAMAGENT_CONFIG_FILE`.
+pub fn load(
+    config_file: Option<&str>,
+    overrides: &BTreeMap<String, String>,
+) -> Result<Config, ConfigError> {
+    let mut config = Config::default();
+    let env_file = env::var(CONFIG_FILE_KEY).ok();
+    if let Some(path) = config_file.or_else(|| env_file.as_ref().map(|v| v.as_str())) {
+        config.apply(path, &read_file(path)?)?;
+    }
+    config.apply("environment", &read_env())?;
+    config.apply("configure()", overrides)?;
+    Ok(config)
+}
+
+/// Merge `values` into settings passed by previous calls, reload config and apply it.
+/// `config_file` is remembered for next calls as well. Config is left untouched on error.
+pub fn configure(
+    config_file: Option<String>,
+    values: BTreeMap<String, String>,
+) -> Result<Config, ConfigError> {
+    let mut overrides = recover(OVERRIDES.write());
+    let config_file = config_file.or_else(|| overrides.config_file.clone());
+    let mut merged = overrides.values.clone();
+    merged.extend(values);
+    let config = load(config_file.as_ref().map(|v| v.as_str()), &merged)?;
+    *overrides = Overrides {
+        config_file,
+        values: merged,
+    };
+    apply(&config);
+    *recover(CONFIG.write()) = config.clone();
+    Ok(config)
+}
+
+/// Forget settings and config file passed to `configure`, reload config from
+/// `PAMAGENT_CONFIG_FILE` and environment and apply it. Config is left untouched on error.
+pub fn reset() -> Result<Config, ConfigError> {
+    let mut overrides = recover(OVERRIDES.write());
+    let config = load(None, &BTreeMap::new())?;
+    *overrides = Overrides::default();
+    apply(&config);
+    *recover(CONFIG.write()) = config.clone();
+    Ok(config)
+}
+
+/// Push runtime settings to subsystems.
+pub fn apply(config: &Config) {
+    logging::set_verbosity(config.log_level);
+    recover(output::OUTPUT_QUEUE.lock()).configure(
+        config.queue_max_items,
+        config.queue_max_bytes,
+        OverflowPolicy::parse(&config.queue_policy).unwrap_or(OverflowPolicy::DropOldest),
+    );
+    *recover(output::BATCH_CONFIG.write()) = config.batch_config();
+    *recover(output::SOCKET_TIMEOUT.write()) = worker::secs_to_duration(config.socket_timeout);
+    recover(sql::SQL_CACHE.lock()).resize(config.sql_cache_size);
+    *recover(core::N_PLUS_ONE_THRESHOLD.write()) = config.n_plus_one_threshold;
+    *recover(core::SLOW_QUERY_THRESHOLD.write()) = config.slow_query_threshold;
+    recover(sampling::SAMPLER.lock()).configure(
+        SamplingMode::parse(&config.sampling_mode).unwrap_or(SamplingMode::Fixed),
+        config.sample_rate,
+        config.sampling_target,
+        config.slow_transaction_threshold,
+    );
+    recover(sampling::SAMPLER.lock()).set_keep_rules(config.keep_rules());
+    *recover(metrics::METRICS_HARVEST_INTERVAL.write()) = config.metrics_harvest_interval;
+    *recover(reaper::TRANSACTION_MAX_AGE.write()) = config.transaction_max_age;
+    *recover(core::NODE_LIMITS.write()) = core::NodeLimits {
+        max_nodes: config.max_nodes,
+        max_depth: config.max_depth,
+    };
+}
+
+/// Load config from file and environment at module import. On error defaults are used.
+pub fn init() {
+    let config = match load(None, &BTreeMap::new()) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Unable to load config. Defaults are used. Error: {}", e);
+            Config::default()
+        }
+    };
+    apply(&config);
+    *recover(CONFIG.write()) = config;
+}
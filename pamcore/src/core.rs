@@ -1,14 +1,134 @@
-use std::sync::RwLock;
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::mem;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::Ordering as AtomicOrdering;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
 use rand;
 use serde_json;
 use output;
+use metrics;
+use reaper;
+use sampling;
+use serde::Serializer;
+use sql;
+use error::{invalid_input, recover, PamResult};
+use tracecontext::{new_span_id, TraceContext};
 const DEFAULT_TIME_VAL: f64 = 0.0;
+const MAX_ERROR_MESSAGE: usize = 4096;
+const MAX_ERROR_FRAMES: usize = 100;
+const MAX_ATTRIBUTE_KEY_LENGTH: usize = 255;
+const MAX_ATTRIBUTE_VALUE_LENGTH: usize = 255;
+const MAX_ATTRIBUTE_COUNT: usize = 64;
+/// Count of shards of `TRANSACTION_CACHE`. Power of two.
+pub const CACHE_SHARDS: usize = 64;
+pub const DEFAULT_N_PLUS_ONE_THRESHOLD: usize = 5;
+pub const DEFAULT_SLOW_QUERY_THRESHOLD: f64 = 0.5;
+/// Slowest queries kept per transaction.
+const MAX_SLOW_QUERIES: usize = 10;
+const MAX_EXPLAIN_PLAN_LENGTH: usize = 65536;
+pub const DEFAULT_MAX_NODES: usize = 2000;
+pub const DEFAULT_MAX_DEPTH: usize = 100;
+/// Task id of the stack of thread or task that started transaction.
+pub const MAIN_TASK: u64 = 0;
 
 lazy_static! {
-    pub static ref TRANSACTION_CACHE: RwLock<TrMap> = { RwLock::new(TrMap::new()) };
+    pub static ref TRANSACTION_CACHE: ShardedCache = { ShardedCache::new(CACHE_SHARDS) };
+    /// Count of calls of the same query under one parent node reported as N+1, 0 disables it.
+    pub static ref N_PLUS_ONE_THRESHOLD: RwLock<usize> =
+        { RwLock::new(DEFAULT_N_PLUS_ONE_THRESHOLD) };
+    /// Duration in seconds from which database calls are copied to slow queries, 0 disables it.
+    pub static ref SLOW_QUERY_THRESHOLD: RwLock<f64> =
+        { RwLock::new(DEFAULT_SLOW_QUERY_THRESHOLD) };
+    pub static ref NODE_LIMITS: RwLock<NodeLimits> = {
+        RwLock::new(NodeLimits {
+            max_nodes: DEFAULT_MAX_NODES,
+            max_depth: DEFAULT_MAX_DEPTH,
+        })
+    };
 }
+
+/// Current time in seconds since epoch, like `time.time()` of Python.
+pub fn now() -> f64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as f64 + f64::from(d.subsec_nanos()) * 1e-9,
+        Err(_) => DEFAULT_TIME_VAL,
+    }
+}
+
+/// Whether database call of `duration` seconds is copied to slow queries.
+pub fn is_slow_query(duration: f64) -> bool {
+    let threshold = *recover(SLOW_QUERY_THRESHOLD.read());
+    threshold > 0.0 && duration >= threshold
+}
+
+/// Limits of trace nodes kept in the tree of transaction, 0 is unlimited. Nodes over them are
+/// folded into summary nodes of their parents.
+#[derive(Clone, Copy, Debug)]
+pub struct NodeLimits {
+    /// Count of nodes, including the root.
+    pub max_nodes: usize,
+    /// Depth of nodes, the root has depth 1.
+    pub max_depth: usize,
+}
+
+pub fn cache_read(id: u64) -> RwLockReadGuard<'static, TrMap> {
+    TRANSACTION_CACHE.read(id)
+}
+
+pub fn cache_write(id: u64) -> RwLockWriteGuard<'static, TrMap> {
+    TRANSACTION_CACHE.write(id)
+}
+
+/// Transaction cache split into shards by transaction id, each behind its own lock. Transaction
+/// is touched by one thread at a time, so threads working on their own transactions rarely wait
+/// for each other. With one shard it is a single global lock.
+pub struct ShardedCache {
+    shards: Vec<RwLock<TrMap>>,
+    shift: u32,
+}
+
+impl ShardedCache {
+    pub fn new(shards: usize) -> ShardedCache {
+        let shards = shards.max(1).next_power_of_two();
+        ShardedCache {
+            shards: (0..shards).map(|_| RwLock::new(TrMap::new())).collect(),
+            shift: 64 - shards.trailing_zeros(),
+        }
+    }
+
+    /// Thread ids are aligned addresses, so low bits are mixed in by Fibonacci hashing.
+    fn shard(&self, id: u64) -> &RwLock<TrMap> {
+        if self.shards.len() == 1 {
+            return &self.shards[0];
+        }
+        let hash = id.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        &self.shards[(hash >> self.shift) as usize]
+    }
+
+    pub fn read(&self, id: u64) -> RwLockReadGuard<TrMap> {
+        recover(self.shard(id).read())
+    }
+
+    pub fn write(&self, id: u64) -> RwLockWriteGuard<TrMap> {
+        recover(self.shard(id).write())
+    }
+
+    /// Close and send transactions inactive for more than `max_age` seconds. Returns their count.
+    pub fn reap(&self, max_age: f64) -> usize {
+        let now = now();
+        self.shards
+            .iter()
+            .map(|shard| recover(shard.write()).reap(now, max_age))
+            .sum()
+    }
+
+    /// Remove transaction without sending it to output.
+    pub fn discard(&self, id: u64) -> bool {
+        self.write(id).0.remove(&id).is_some()
+    }
+}
+
 #[derive(Debug, Serialize)]
 #[serde(tag = "type")]
 pub enum StackNode {
@@ -67,26 +187,330 @@ impl StackNode {
             StackNode::Cache(ref x) => x.duration,
         }
     }
+    fn get_exclusive(&self) -> f64 {
+        match *self {
+            StackNode::Func(ref x) => x.exclusive,
+            StackNode::External(ref x) => x.exclusive,
+            StackNode::Database(ref x) => x.exclusive,
+            StackNode::Cache(ref x) => x.exclusive,
+        }
+    }
+    fn get_span_id(&self) -> &str {
+        match *self {
+            StackNode::Func(ref x) => &x.span_id,
+            StackNode::External(ref x) => &x.span_id,
+            StackNode::Database(ref x) => &x.span_id,
+            StackNode::Cache(ref x) => &x.span_id,
+        }
+    }
+    fn get_childrens(&self) -> &[StackNode] {
+        match *self {
+            StackNode::Func(ref x) => &x.childrens,
+            StackNode::External(ref x) => &x.childrens,
+            StackNode::Database(ref x) => &x.childrens,
+            StackNode::Cache(ref x) => &x.childrens,
+        }
+    }
+    fn set_error(&mut self, error: ErrorInfo) {
+        match *self {
+            StackNode::Func(ref mut x) => x.error = Some(error),
+            StackNode::External(ref mut x) => x.error = Some(error),
+            StackNode::Database(ref mut x) => x.error = Some(error),
+            StackNode::Cache(ref mut x) => x.error = Some(error),
+        }
+    }
+    fn set_implicitly_closed(&mut self) {
+        match *self {
+            StackNode::Func(ref mut x) => x.implicitly_closed = true,
+            StackNode::External(ref mut x) => x.implicitly_closed = true,
+            StackNode::Database(ref mut x) => x.implicitly_closed = true,
+            StackNode::Cache(ref mut x) => x.implicitly_closed = true,
+        }
+    }
+    fn get_attributes_mut(&mut self) -> &mut Attributes {
+        match *self {
+            StackNode::Func(ref mut x) => &mut x.attributes,
+            StackNode::External(ref mut x) => &mut x.attributes,
+            StackNode::Database(ref mut x) => &mut x.attributes,
+            StackNode::Cache(ref mut x) => &mut x.attributes,
+        }
+    }
+    /// Add node and its childrens to summary. Root node has depth 1.
+    fn summarize(&self, depth: usize, summary: &mut TransactionSummary) {
+        let exclusive = self.get_exclusive().max(0.0);
+        match *self {
+            StackNode::Func(_) => summary.time_by_type.func += exclusive,
+            StackNode::External(_) => summary.time_by_type.external += exclusive,
+            StackNode::Database(_) => summary.time_by_type.database += exclusive,
+            StackNode::Cache(_) => summary.time_by_type.cache += exclusive,
+        }
+        if let StackNode::Database(_) = *self {
+            summary.database_calls += 1;
+        }
+        for folded in self.get_summaries() {
+            if folded.node_type == "Database" {
+                summary.database_calls += folded.count as usize;
+            }
+        }
+        summary.node_count += 1;
+        summary.max_depth = summary.max_depth.max(depth);
+        for child in self.get_childrens() {
+            child.summarize(depth + 1, summary);
+        }
+    }
+    /// Find queries called at least `threshold` times directly under one node, like a query in a
+    /// loop over results of another query.
+    fn detect_n_plus_one(&self, threshold: usize, found: &mut Vec<NPlusOne>) {
+        let mut queries: Vec<NPlusOne> = vec![];
+        for child in self.get_childrens() {
+            if let StackNode::Database(ref x) = *child {
+                let fingerprint = sql::fingerprint_hex(x.sql_fingerprint);
+                match queries.iter().position(|q| q.fingerprint == fingerprint) {
+                    Some(i) => {
+                        queries[i].count += 1;
+                        queries[i].total_time += x.duration;
+                    }
+                    None => queries.push(NPlusOne {
+                        parent_span_id: self.get_span_id().to_owned(),
+                        parent_name: match *self {
+                            StackNode::Func(ref f) => f.func_name.clone(),
+                            _ => "".to_owned(),
+                        },
+                        fingerprint,
+                        normalized: x.sql_normalized.clone(),
+                        operation: x.operation.clone(),
+                        target: x.target.clone(),
+                        count: 1,
+                        total_time: x.duration,
+                    }),
+                }
+            }
+            child.detect_n_plus_one(threshold, found);
+        }
+        found.extend(queries.into_iter().filter(|q| q.count >= threshold));
+    }
+    /// Copy of finished database call for slow queries of transaction, None if it is fast.
+    fn slow_query(&self) -> Option<SlowQuery> {
+        let x = match *self {
+            StackNode::Database(ref x) => x,
+            _ => return None,
+        };
+        if !is_slow_query(x.duration) {
+            return None;
+        }
+        Some(SlowQuery {
+            node_id: x.node_id,
+            span_id: x.span_id.clone(),
+            start_time: x.start_time,
+            duration: x.duration,
+            host: x.host.clone(),
+            port: x.port,
+            database_product: x.database_product.clone(),
+            database_name: x.database_name.clone(),
+            operation: x.operation.clone(),
+            target: x.target.clone(),
+            sql: x.sql.clone(),
+            sql_fingerprint: x.sql_fingerprint,
+            details: x.details.clone(),
+        })
+    }
+    /// Add finished database call to aggregates of its query in `stats` of transaction.
+    fn record_query(&self, stats: &mut sql::QueryStats) {
+        if let StackNode::Database(ref x) = *self {
+            stats.record(
+                x.sql_fingerprint,
+                &x.sql_normalized,
+                &x.database_product,
+                &x.operation,
+                &x.target,
+                x.duration,
+            );
+        }
+    }
+    /// Add finished external, database or cache call to timeslice `metrics` of transaction.
+    fn record_metric(&self, metrics: &mut metrics::MetricTable) {
+        match *self {
+            StackNode::Func(_) => {}
+            StackNode::External(ref x) => metrics.record_external(&x.host, x.duration),
+            StackNode::Database(ref x) => metrics.record_database(
+                &x.database_product,
+                &x.operation,
+                &x.target,
+                x.duration,
+            ),
+            StackNode::Cache(ref x) => {
+                metrics.record_cache(&x.database_product, &x.operation, x.duration)
+            }
+        }
+    }
     fn process_child(&mut self, node: StackNode) {
+        let node_count = 1 + node.get_node_count();
         match *self {
             StackNode::Func(ref mut x) => {
                 x.exclusive -= node.get_duration();
+                x.node_count += node_count;
                 x.childrens.push(node);
             }
             StackNode::External(ref mut x) => {
                 x.exclusive -= node.get_duration();
+                x.node_count += node_count;
                 x.childrens.push(node);
             }
             StackNode::Database(ref mut x) => {
                 x.exclusive -= node.get_duration();
+                x.node_count += node_count;
                 x.childrens.push(node);
             }
             StackNode::Cache(ref mut x) => {
                 x.exclusive -= node.get_duration();
+                x.node_count += node_count;
                 x.childrens.push(node);
             }
         }
     }
+    fn get_type_name(&self) -> &'static str {
+        match *self {
+            StackNode::Func(_) => "Func",
+            StackNode::External(_) => "External",
+            StackNode::Database(_) => "Database",
+            StackNode::Cache(_) => "Cache",
+        }
+    }
+    fn get_node_count(&self) -> u32 {
+        match *self {
+            StackNode::Func(ref x) => x.node_count,
+            StackNode::External(ref x) => x.node_count,
+            StackNode::Database(ref x) => x.node_count,
+            StackNode::Cache(ref x) => x.node_count,
+        }
+    }
+    fn get_summaries(&self) -> &[SummaryNode] {
+        match *self {
+            StackNode::Func(ref x) => &x.summaries,
+            StackNode::External(ref x) => &x.summaries,
+            StackNode::Database(ref x) => &x.summaries,
+            StackNode::Cache(ref x) => &x.summaries,
+        }
+    }
+    fn get_summaries_mut(&mut self) -> &mut Vec<SummaryNode> {
+        match *self {
+            StackNode::Func(ref mut x) => &mut x.summaries,
+            StackNode::External(ref mut x) => &mut x.summaries,
+            StackNode::Database(ref mut x) => &mut x.summaries,
+            StackNode::Cache(ref mut x) => &mut x.summaries,
+        }
+    }
+    /// Add node and its descendants to summary nodes.
+    fn summarize_into(&self, summaries: &mut Vec<SummaryNode>) {
+        add_summary(summaries, self.get_type_name(), 1, self.get_duration());
+        for summary in self.get_summaries() {
+            add_summary(summaries, summary.node_type, summary.count, summary.total_time);
+        }
+        for child in self.get_childrens() {
+            child.summarize_into(summaries);
+        }
+    }
+    /// Fold finished node into summary nodes instead of childrens. Returns count of nodes that
+    /// are removed from the tree with it.
+    fn summarize_child(&mut self, node: StackNode) -> u32 {
+        match *self {
+            StackNode::Func(ref mut x) => x.exclusive -= node.get_duration(),
+            StackNode::External(ref mut x) => x.exclusive -= node.get_duration(),
+            StackNode::Database(ref mut x) => x.exclusive -= node.get_duration(),
+            StackNode::Cache(ref mut x) => x.exclusive -= node.get_duration(),
+        }
+        node.summarize_into(self.get_summaries_mut());
+        1 + node.get_node_count()
+    }
+}
+
+/// Nodes of one type folded together when transaction is over node limits.
+#[derive(Debug, Serialize)]
+pub struct SummaryNode {
+    node_type: &'static str,
+    count: u32,
+    total_time: f64,
+}
+
+fn add_summary(summaries: &mut Vec<SummaryNode>, node_type: &'static str, count: u32, time: f64) {
+    match summaries.iter_mut().find(|s| s.node_type == node_type) {
+        Some(summary) => {
+            summary.count += count;
+            summary.total_time += time;
+        }
+        None => summaries.push(SummaryNode {
+            node_type,
+            count,
+            total_time: time,
+        }),
+    }
+}
+
+/// Exception raised inside of node or transaction.
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorInfo {
+    class: String,
+    message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    frames: Vec<String>,
+}
+
+impl ErrorInfo {
+    /// Long messages and tracebacks are cut to `MAX_ERROR_MESSAGE` chars and `MAX_ERROR_FRAMES`
+    /// innermost frames.
+    pub fn new(class: String, message: String, frames: Vec<String>) -> ErrorInfo {
+        let message = match message.char_indices().nth(MAX_ERROR_MESSAGE) {
+            Some((pos, _)) => message[..pos].to_owned(),
+            None => message,
+        };
+        let skip = frames.len().saturating_sub(MAX_ERROR_FRAMES);
+        ErrorInfo {
+            class,
+            message,
+            frames: frames.into_iter().skip(skip).collect(),
+        }
+    }
+}
+
+/// Value of custom attribute.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum AttrValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+pub type Attributes = BTreeMap<String, AttrValue>;
+
+/// Set custom attribute within limits. Too long string value is truncated to
+/// `MAX_ATTRIBUTE_VALUE_LENGTH` chars, too long key and too many attributes are rejected.
+fn add_attribute(attributes: &mut Attributes, key: String, value: AttrValue) -> PamResult<()> {
+    if key.is_empty() || key.chars().count() > MAX_ATTRIBUTE_KEY_LENGTH {
+        return Err(invalid_input(format!(
+            "Attribute key must be 1 to {} chars long",
+            MAX_ATTRIBUTE_KEY_LENGTH
+        )));
+    }
+    if attributes.len() >= MAX_ATTRIBUTE_COUNT && !attributes.contains_key(&key) {
+        return Err(invalid_input(format!(
+            "Too many attributes, max is {}. Attribute {} is dropped",
+            MAX_ATTRIBUTE_COUNT, key
+        )));
+    }
+    let value = match value {
+        AttrValue::Str(v) => match v.char_indices().nth(MAX_ATTRIBUTE_VALUE_LENGTH) {
+            Some((pos, _)) => AttrValue::Str(v[..pos].to_owned()),
+            None => AttrValue::Str(v),
+        },
+        AttrValue::Float(v) if !v.is_finite() => {
+            return Err(invalid_input(format!("Attribute {} must be a finite number", key)))
+        }
+        v => v,
+    };
+    attributes.insert(key, value);
+    Ok(())
 }
 
 trait Node {
@@ -117,24 +541,45 @@ trait Node {
 #[derive(Debug, Serialize)]
 pub struct FuncNode {
     node_id: u64,
+    span_id: String,
     childrens: Vec<StackNode>,
     start_time: f64,
     end_time: f64,
     exclusive: f64,
-    node_count: u8,
+    /// Count of descendants kept in the tree.
+    node_count: u32,
     duration: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorInfo>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    attributes: Attributes,
+    /// Closed by pop of its ancestor, e.g. when exception skipped `__exit__` of the node.
+    #[serde(skip_serializing_if = "is_false")]
+    implicitly_closed: bool,
+    /// Descendants over node limits of transaction, by node type.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    summaries: Vec<SummaryNode>,
     func_name: String,
 }
 
 #[derive(Debug, Serialize)]
 pub struct ExternalNode {
     node_id: u64,
+    span_id: String,
     childrens: Vec<StackNode>,
     start_time: f64,
     end_time: f64,
     exclusive: f64,
-    node_count: u8,
+    node_count: u32,
     duration: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorInfo>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    attributes: Attributes,
+    #[serde(skip_serializing_if = "is_false")]
+    implicitly_closed: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    summaries: Vec<SummaryNode>,
     host: String,
     port: u16,
     library: String,
@@ -145,12 +590,21 @@ pub struct ExternalNode {
 #[derive(Debug, Serialize)]
 pub struct DatabaseNode {
     node_id: u64,
+    span_id: String,
     childrens: Vec<StackNode>,
     start_time: f64,
     end_time: f64,
     exclusive: f64,
-    node_count: u8,
+    node_count: u32,
     duration: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorInfo>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    attributes: Attributes,
+    #[serde(skip_serializing_if = "is_false")]
+    implicitly_closed: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    summaries: Vec<SummaryNode>,
     host: String,
     port: u16,
     database_product: String,
@@ -158,17 +612,133 @@ pub struct DatabaseNode {
     operation: String,
     target: String,
     sql: String,
+    /// Normalized sql of `sql::parse`, the same for calls that differ only in values.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    sql_normalized: String,
+    #[serde(serialize_with = "serialize_fingerprint")]
+    sql_fingerprint: u64,
+    /// Reported only for slow queries.
+    #[serde(skip)]
+    details: QueryDetails,
+}
+
+/// Context of database call set by `set_query_details`.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct QueryDetails {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stack: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    row_count: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    explain_plan: Option<String>,
+}
+
+impl QueryDetails {
+    /// Stack is cut to `MAX_ERROR_FRAMES` innermost frames and explain plan to
+    /// `MAX_EXPLAIN_PLAN_LENGTH` chars.
+    pub fn new(
+        row_count: Option<i64>,
+        explain_plan: Option<String>,
+        stack: Option<Vec<String>>,
+    ) -> QueryDetails {
+        let stack = stack.unwrap_or_default();
+        let skip = stack.len().saturating_sub(MAX_ERROR_FRAMES);
+        QueryDetails {
+            stack: stack.into_iter().skip(skip).collect(),
+            row_count,
+            explain_plan: explain_plan.map(|v| match v.char_indices().nth(MAX_EXPLAIN_PLAN_LENGTH) {
+                Some((pos, _)) => v[..pos].to_owned(),
+                None => v,
+            }),
+        }
+    }
+
+    /// Values that are not set in `other` are left as they are.
+    fn update(&mut self, other: QueryDetails) {
+        if !other.stack.is_empty() {
+            self.stack = other.stack;
+        }
+        if other.row_count.is_some() {
+            self.row_count = other.row_count;
+        }
+        if other.explain_plan.is_some() {
+            self.explain_plan = other.explain_plan;
+        }
+    }
+}
+
+/// Database call slower than `SLOW_QUERY_THRESHOLD` with its context, for slow SQL page.
+#[derive(Clone, Debug, Serialize)]
+pub struct SlowQuery {
+    #[serde(skip)]
+    node_id: u64,
+    span_id: String,
+    start_time: f64,
+    duration: f64,
+    host: String,
+    port: u16,
+    database_product: String,
+    database_name: String,
+    operation: String,
+    target: String,
+    sql: String,
+    #[serde(serialize_with = "serialize_fingerprint")]
+    sql_fingerprint: u64,
+    #[serde(flatten)]
+    details: QueryDetails,
+}
+
+/// Keep `MAX_SLOW_QUERIES` slowest queries.
+fn keep_slow_query(slow_queries: &mut Vec<SlowQuery>, query: SlowQuery) {
+    if slow_queries.len() < MAX_SLOW_QUERIES {
+        slow_queries.push(query);
+        return;
+    }
+    let fastest = slow_queries
+        .iter()
+        .enumerate()
+        .min_by(|a, b| a.1.duration.partial_cmp(&b.1.duration).unwrap_or(Ordering::Equal))
+        .map(|(i, q)| (i, q.duration));
+    if let Some((i, duration)) = fastest {
+        if query.duration > duration {
+            slow_queries[i] = query;
+        }
+    }
+}
+
+fn serialize_fingerprint<S: Serializer>(
+    fingerprint: &u64,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&sql::fingerprint_hex(*fingerprint))
+}
+
+fn is_false(val: &bool) -> bool {
+    !*val
+}
+
+fn is_zero(val: &u32) -> bool {
+    *val == 0
 }
 
 #[derive(Debug, Serialize)]
 pub struct CacheNode {
     node_id: u64,
+    span_id: String,
     childrens: Vec<StackNode>,
     start_time: f64,
     end_time: f64,
     exclusive: f64,
-    node_count: u8,
+    node_count: u32,
     duration: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorInfo>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    attributes: Attributes,
+    #[serde(skip_serializing_if = "is_false")]
+    implicitly_closed: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    summaries: Vec<SummaryNode>,
     host: String,
     port: u16,
     database_product: String,
@@ -292,11 +862,16 @@ impl FuncNode {
     pub fn new(node_id: u64, start_time: f64, func_name: String) -> FuncNode {
         FuncNode {
             node_id,
+            span_id: new_span_id(),
             childrens: vec![],
             start_time,
             end_time: DEFAULT_TIME_VAL,
             exclusive: DEFAULT_TIME_VAL,
             node_count: 0,
+            error: None,
+            attributes: Attributes::new(),
+            implicitly_closed: false,
+            summaries: vec![],
             duration: DEFAULT_TIME_VAL,
             func_name,
         }
@@ -315,11 +890,16 @@ impl ExternalNode {
     ) -> ExternalNode {
         ExternalNode {
             node_id: node_id,
+            span_id: new_span_id(),
             childrens: vec![],
             start_time: start_time,
             end_time: DEFAULT_TIME_VAL,
             exclusive: DEFAULT_TIME_VAL,
             node_count: 0,
+            error: None,
+            attributes: Attributes::new(),
+            implicitly_closed: false,
+            summaries: vec![],
             duration: DEFAULT_TIME_VAL,
             host: host.to_string(),
             port: port,
@@ -341,6 +921,8 @@ impl DatabaseNode {
         operation: String,
         target: String,
         sql: String,
+        sql_normalized: String,
+        sql_fingerprint: u64,
     ) -> DatabaseNode {
         let mut default_host: &str = "";
         let mut default_port: u16 = 0;
@@ -361,11 +943,16 @@ impl DatabaseNode {
         };
         DatabaseNode {
             node_id: node_id,
+            span_id: new_span_id(),
             childrens: vec![],
             start_time: start_time,
             end_time: DEFAULT_TIME_VAL,
             exclusive: DEFAULT_TIME_VAL,
             node_count: 0,
+            error: None,
+            attributes: Attributes::new(),
+            implicitly_closed: false,
+            summaries: vec![],
             duration: DEFAULT_TIME_VAL,
             host: target_host.to_string(),
             port: target_port,
@@ -374,6 +961,9 @@ impl DatabaseNode {
             operation: operation.to_string(),
             target: target.to_string(),
             sql: sql,
+            sql_normalized,
+            sql_fingerprint,
+            details: QueryDetails::default(),
         }
     }
 }
@@ -390,11 +980,16 @@ impl CacheNode {
     ) -> CacheNode {
         CacheNode {
             node_id,
+            span_id: new_span_id(),
             childrens: vec![],
             start_time,
             end_time: DEFAULT_TIME_VAL,
             exclusive: DEFAULT_TIME_VAL,
             node_count: 0,
+            error: None,
+            attributes: Attributes::new(),
+            implicitly_closed: false,
+            summaries: vec![],
             duration: DEFAULT_TIME_VAL,
             host,
             port,
@@ -409,34 +1004,522 @@ impl CacheNode {
 struct TransactionNode {
     base_name: String,
     nodes_stack: Vec<StackNode>,
-    trace_node_count: u8,
+    trace_node_count: u32,
     guid: String,
     path: String,
+    trace: TraceContext,
+    /// Exception raised in transaction or in one of its nodes.
+    errored: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorInfo>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    attributes: Attributes,
+    /// Performance problems found when transaction is dropped.
+    #[serde(skip_serializing_if = "Insights::is_empty")]
+    insights: Insights,
+    /// Copies of slow database calls, apart from the tree of nodes.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    slow_queries: Vec<SlowQuery>,
+    /// Pops of nodes that were not on top of their stack, or were not open at all.
+    pop_anomalies: u32,
+    /// Wall clock time of `set_transaction` and of the last change of transaction.
+    created_at: f64,
+    last_active: f64,
+    #[serde(flatten)]
+    truncation: Truncation,
+    /// Keep rule of tail sampling matched by transaction.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kept_by: Option<&'static str>,
+    /// Stacks of tasks running in parallel with the main stack, keyed by task id.
+    #[serde(skip)]
+    tasks: HashMap<u64, TaskStack>,
+    /// Open calls of unsampled transaction by node id, with their start time.
+    #[serde(skip)]
+    calls: HashMap<u64, (f64, metrics::Call)>,
+    /// Aggregates of finished database calls, merged into `sql::QUERY_STATS` when transaction
+    /// is closed, so pops do not contend on the global lock.
+    #[serde(skip)]
+    query_stats: sql::QueryStats,
+    /// Metrics of finished calls, merged into `metrics::METRICS` with the transaction itself
+    /// when it is closed.
+    #[serde(skip)]
+    metrics: metrics::MetricTable,
+}
+
+/// Open nodes of task that runs in parallel with others of the same transaction, e.g. of asyncio
+/// task. Finished top nodes of the task are attached to the node that started it.
+#[derive(Debug, Default)]
+struct TaskStack {
+    /// Node that was current in parent task when this task was started, and its depth.
+    parent_node_id: Option<u64>,
+    depth: usize,
+    nodes: Vec<StackNode>,
+}
+
+/// What is missing in dump of transaction.
+#[derive(Debug, Default, Serialize)]
+struct Truncation {
+    /// Nodes in the tree besides the root.
+    #[serde(skip)]
+    kept_nodes: u32,
+    /// Nodes folded into summary nodes because of `NODE_LIMITS`.
+    #[serde(skip_serializing_if = "is_zero")]
+    summarized_nodes: u32,
+    /// Transaction was not dropped and was closed by reaper.
+    #[serde(skip_serializing_if = "is_false")]
+    reaped: bool,
+    #[serde(skip_serializing_if = "is_false")]
+    truncated: bool,
+}
+
+impl Truncation {
+    /// Attach finished node at `depth` to its parent, or fold it into summary nodes of the parent
+    /// if the tree is over limits. Places of `open` nodes are reserved, so ancestors are kept.
+    fn attach(&mut self, parent: &mut StackNode, node: StackNode, depth: usize, open: usize) {
+        let limits = *recover(NODE_LIMITS.read());
+        let over_depth = limits.max_depth > 0 && depth > limits.max_depth;
+        let over_count =
+            limits.max_nodes > 0 && self.kept_nodes as usize + open + 1 > limits.max_nodes;
+        if over_depth || over_count {
+            let removed = parent.summarize_child(node);
+            self.kept_nodes = self.kept_nodes.saturating_sub(removed - 1);
+            self.summarized_nodes += removed;
+            self.truncated = true;
+        } else {
+            self.kept_nodes += 1;
+            parent.process_child(node);
+        }
+    }
+}
+
+/// Open node of any task, see `TransactionNode::open_node_mut`.
+fn find_open_node<'a>(
+    nodes_stack: &'a mut Vec<StackNode>,
+    tasks: &'a mut HashMap<u64, TaskStack>,
+    node_id: u64,
+) -> Option<&'a mut StackNode> {
+    if let Some(i) = nodes_stack.iter().rposition(|n| n.get_node_id() == node_id) {
+        return nodes_stack.get_mut(i);
+    }
+    for task in tasks.values_mut() {
+        if let Some(i) = task.nodes.iter().rposition(|n| n.get_node_id() == node_id) {
+            return task.nodes.get_mut(i);
+        }
+    }
+    None
+}
+
+/// Set end time of popped node and record its query and metric.
+fn finish_node(
+    node: &mut StackNode,
+    end_time: f64,
+    slow_queries: &mut Vec<SlowQuery>,
+    query_stats: &mut sql::QueryStats,
+    metrics: &mut metrics::MetricTable,
+) {
+    node.set_endtime(end_time);
+    node.comp_exclusive();
+    node.record_query(query_stats);
+    node.record_metric(metrics);
+    if let Some(query) = node.slow_query() {
+        keep_slow_query(slow_queries, query);
+    }
+}
+
+/// Query repeated under one parent node, see `StackNode::detect_n_plus_one`.
+#[derive(Clone, Debug, Serialize)]
+pub struct NPlusOne {
+    pub parent_span_id: String,
+    /// Function name of parent node, empty for other node types.
+    pub parent_name: String,
+    pub fingerprint: String,
+    pub normalized: String,
+    pub operation: String,
+    pub target: String,
+    pub count: usize,
+    pub total_time: f64,
+}
+
+/// Performance problems of transaction.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Insights {
+    /// Sorted by total time, slowest first.
+    pub n_plus_one: Vec<NPlusOne>,
+}
+
+impl Insights {
+    pub fn is_empty(&self) -> bool {
+        self.n_plus_one.is_empty()
+    }
+}
+
+/// Exclusive time of nodes by node type.
+#[derive(Debug, Default, Serialize)]
+pub struct TimeByType {
+    pub func: f64,
+    pub external: f64,
+    pub database: f64,
+    pub cache: f64,
+}
+
+/// Timing of transaction, see `TransactionCache::get_transaction_summary`.
+#[derive(Debug, Default, Serialize)]
+pub struct TransactionSummary {
+    pub guid: String,
+    pub trace_id: String,
+    pub start_time: f64,
+    pub end_time: f64,
+    pub duration: f64,
+    pub exclusive: f64,
+    pub node_count: usize,
+    pub max_depth: usize,
+    pub time_by_type: TimeByType,
+    /// Database nodes, including those folded into summary nodes.
+    pub database_calls: usize,
+    pub errored: bool,
+    pub sampled: bool,
+    pub pop_anomalies: u32,
+    pub summarized_nodes: u32,
 }
 
 impl TransactionNode {
     fn set_path(&mut self, path: String) {
         self.path = path;
     }
+    /// Nodes that are still open are counted, but their time is not known yet.
+    fn summary(&self) -> TransactionSummary {
+        let mut summary = TransactionSummary {
+            guid: self.guid.clone(),
+            trace_id: self.trace.trace_id.clone(),
+            errored: self.errored,
+            sampled: self.trace.sampled,
+            pop_anomalies: self.pop_anomalies,
+            summarized_nodes: self.truncation.summarized_nodes,
+            ..TransactionSummary::default()
+        };
+        if let Some(root) = self.nodes_stack.first() {
+            summary.start_time = root.get_start_time();
+            summary.end_time = root.get_end_time();
+            summary.duration = root.get_duration();
+            summary.exclusive = root.get_exclusive().max(0.0);
+        }
+        for (depth, node) in self.nodes_stack.iter().enumerate() {
+            node.summarize(depth + 1, &mut summary);
+        }
+        summary
+    }
+    fn insights(&self) -> Insights {
+        let threshold = *recover(N_PLUS_ONE_THRESHOLD.read());
+        let mut n_plus_one: Vec<NPlusOne> = vec![];
+        if threshold > 0 {
+            for node in &self.nodes_stack {
+                node.detect_n_plus_one(threshold, &mut n_plus_one);
+            }
+        }
+        n_plus_one.sort_by(|a, b| {
+            b.total_time
+                .partial_cmp(&a.total_time)
+                .unwrap_or(Ordering::Equal)
+        });
+        Insights { n_plus_one }
+    }
+    fn stack(&self, task: u64) -> Option<&Vec<StackNode>> {
+        match task {
+            MAIN_TASK => Some(&self.nodes_stack),
+            _ => self.tasks.get(&task).map(|t| &t.nodes),
+        }
+    }
+    fn stack_mut(&mut self, task: u64) -> Option<&mut Vec<StackNode>> {
+        match task {
+            MAIN_TASK => Some(&mut self.nodes_stack),
+            _ => self.tasks.get_mut(&task).map(|t| &mut t.nodes),
+        }
+    }
+    /// Task with open node on its stack, preferably `task`. Node can be finished by other task
+    /// than started it.
+    fn task_of(&self, node_id: u64, task: u64) -> Option<u64> {
+        let has_node = |nodes: &Vec<StackNode>| nodes.iter().any(|n| n.get_node_id() == node_id);
+        match self.stack(task) {
+            Some(nodes) if has_node(nodes) => return Some(task),
+            _ => {}
+        }
+        if has_node(&self.nodes_stack) {
+            return Some(MAIN_TASK);
+        }
+        self.tasks
+            .iter()
+            .find(|&(_, t)| has_node(&t.nodes))
+            .map(|(task, _)| *task)
+    }
+    /// Open node of any task.
+    fn open_node(&self, node_id: u64) -> Option<&StackNode> {
+        self.nodes_stack
+            .iter()
+            .chain(self.tasks.values().flat_map(|t| t.nodes.iter()))
+            .find(|n| n.get_node_id() == node_id)
+    }
+    fn open_node_mut(&mut self, node_id: u64) -> Option<&mut StackNode> {
+        find_open_node(&mut self.nodes_stack, &mut self.tasks, node_id)
+    }
+    fn open_node_count(&self) -> usize {
+        self.nodes_stack.len() + self.tasks.values().map(|t| t.nodes.len()).sum::<usize>()
+    }
+    /// Depth of the node a task starts on, 0 for the main stack.
+    fn task_depth(&self, task: u64) -> usize {
+        self.tasks.get(&task).map_or(0, |t| t.depth)
+    }
+    /// Current node of task, or node that started the task if it has no open nodes.
+    fn current_node(&self, task: u64) -> Option<&StackNode> {
+        if let Some(node) = self.stack(task).and_then(|s| s.last()) {
+            return Some(node);
+        }
+        self.open_node(self.tasks.get(&task)?.parent_node_id?)
+    }
+    fn start_task(&mut self, task: u64, parent_task: u64) -> bool {
+        if task == MAIN_TASK || self.tasks.contains_key(&task) {
+            return false;
+        }
+        let parent_node_id = self.current_node(parent_task).map(|n| n.get_node_id());
+        let depth = self.task_depth(parent_task) + self.stack(parent_task).map_or(0, |s| s.len());
+        self.tasks.insert(
+            task,
+            TaskStack {
+                parent_node_id,
+                depth,
+                nodes: vec![],
+            },
+        );
+        true
+    }
+    /// Close nodes left open by task and forget its stack.
+    fn end_task(&mut self, task: u64, end_time: f64) -> bool {
+        let bottom = match self.tasks.get_mut(&task) {
+            Some(t) => {
+                for node in &mut t.nodes {
+                    node.set_implicitly_closed();
+                }
+                t.nodes.first().map(|n| n.get_node_id())
+            }
+            None => return false,
+        };
+        if let Some(node_id) = bottom {
+            self.pop_node(task, node_id, end_time);
+        }
+        self.tasks.remove(&task);
+        true
+    }
+    /// Pop node from stack of task. Nodes above it were not popped, e.g. exception skipped their
+    /// `__exit__`, so they are closed with the same end time and marked as implicitly closed.
+    /// Root stays on the main stack. Top node of task is attached to the node that started the
+    /// task, or to the root if that one is already finished. Returns id of parent node.
+    fn pop_node(&mut self, task: u64, node_id: u64, end_time: f64) -> Option<u64> {
+        let started_by = self.tasks.get(&task).and_then(|t| t.parent_node_id);
+        let base_depth = self.task_depth(task);
+        let mut open = self.open_node_count();
+        let node = {
+            let stack = match task {
+                MAIN_TASK => &mut self.nodes_stack,
+                _ => &mut self.tasks.get_mut(&task)?.nodes,
+            };
+            let pos = stack.iter().rposition(|n| n.get_node_id() == node_id)?;
+            while stack.len() > pos + 1 {
+                let mut node = stack.pop()?;
+                open -= 1;
+                node.set_implicitly_closed();
+                finish_node(
+                    &mut node,
+                    end_time,
+                    &mut self.slow_queries,
+                    &mut self.query_stats,
+                    &mut self.metrics,
+                );
+                self.trace_node_count += 1;
+                let depth = base_depth + stack.len() + 1;
+                self.truncation.attach(stack.last_mut()?, node, depth, open);
+            }
+            self.trace_node_count += 1;
+            if task == MAIN_TASK && pos == 0 {
+                finish_node(
+                    &mut stack[0],
+                    end_time,
+                    &mut self.slow_queries,
+                    &mut self.query_stats,
+                    &mut self.metrics,
+                );
+                return None;
+            }
+            let mut node = stack.pop()?;
+            open -= 1;
+            finish_node(
+                &mut node,
+                end_time,
+                &mut self.slow_queries,
+                &mut self.query_stats,
+                &mut self.metrics,
+            );
+            let depth = base_depth + stack.len() + 1;
+            if let Some(parent) = stack.last_mut() {
+                self.truncation.attach(parent, node, depth, open);
+                return Some(parent.get_node_id());
+            }
+            node
+        };
+        let started_by = started_by.filter(|v| self.open_node(*v).is_some());
+        let (parent, depth) = match started_by {
+            Some(v) => (
+                find_open_node(&mut self.nodes_stack, &mut self.tasks, v)?,
+                base_depth + 1,
+            ),
+            None => (self.nodes_stack.first_mut()?, 2),
+        };
+        self.truncation.attach(parent, node, depth, open);
+        Some(parent.get_node_id())
+    }
+    fn is_abandoned(&self, now: f64, max_age: f64) -> bool {
+        max_age > 0.0 && now - self.last_active > max_age
+    }
+    /// Close open nodes at time of last activity and send transaction marked as truncated.
+    fn reap(mut self) {
+        warn!(
+            "Transaction {} {:?} is inactive since {}, it is closed",
+            self.guid, self.base_name, self.last_active
+        );
+        reaper::REAPED.fetch_add(1, AtomicOrdering::SeqCst);
+        let end_time = self.last_active;
+        let tasks: Vec<u64> = self.tasks.keys().cloned().collect();
+        for task in tasks {
+            self.end_task(task, end_time);
+        }
+        let root = match self.nodes_stack.first() {
+            Some(node) if node.get_end_time() == DEFAULT_TIME_VAL => Some(node.get_node_id()),
+            _ => None,
+        };
+        if let Some(node_id) = root {
+            for node in &mut self.nodes_stack {
+                node.set_implicitly_closed();
+            }
+            self.pop_node(MAIN_TASK, node_id, end_time);
+        }
+        self.truncation.reaped = true;
+        self.truncation.truncated = true;
+        self.close();
+    }
+    /// Unsampled transaction has the root node only, it is not sent unless sampler keeps it.
+    fn records_nodes(&self) -> bool {
+        self.trace.sampled || self.nodes_stack.is_empty()
+    }
+    /// Record metric of finished transaction and send it, unsampled one only if it errored or is
+    /// slow in adaptive mode, sampled one only if it matches keep rules when tail sampling is on.
+    fn close(mut self) {
+        let query_stats = mem::replace(&mut self.query_stats, sql::QueryStats::new());
+        recover(sql::QUERY_STATS.lock()).merge(query_stats);
+        let summary = self.summary();
+        let created_at = self.created_at;
+        let mut metrics = mem::replace(&mut self.metrics, metrics::MetricTable::new(created_at));
+        metrics.record_transaction(&self.base_name, summary.duration);
+        recover(metrics::METRICS.lock()).merge(metrics);
+        let keep = {
+            let mut sampler = recover(sampling::SAMPLER.lock());
+            if !self.trace.sampled {
+                sampler.keep_unsampled(summary.errored, summary.duration)
+            } else if sampler.tail_sampling() {
+                self.kept_by = sampler.keep_rule(
+                    &self.path,
+                    summary.duration,
+                    summary.errored,
+                    summary.database_calls,
+                );
+                self.kept_by.is_some()
+            } else {
+                true
+            }
+        };
+        if keep {
+            self.send();
+        }
+    }
+    fn send(mut self) {
+        self.insights = self.insights();
+        let j: String = serde_json::to_string(&self).unwrap_or_else(|_| "".to_uppercase());
+        output::enqueue(j);
+    }
     fn dump(&self) -> String {
-        let dump_str: String = serde_json::to_string(self).unwrap();
-        dump_str
+        serde_json::to_string(self).unwrap_or_else(|e| {
+            error!("Unable to serialize transaction. Error: {}", e);
+            "".to_owned()
+        })
     }
 }
 
 pub struct TrMap(HashMap<u64, TransactionNode>);
 
+impl TrMap {
+    /// Transaction that is changed now.
+    fn active(&mut self, id: u64) -> Option<&mut TransactionNode> {
+        let tr = self.0.get_mut(&id)?;
+        tr.last_active = now();
+        Some(tr)
+    }
+    /// Close and send transactions inactive for more than `max_age` seconds at `now`.
+    pub fn reap(&mut self, now: f64, max_age: f64) -> usize {
+        let ids: Vec<u64> = self
+            .0
+            .iter()
+            .filter(|&(_, tr)| tr.is_abandoned(now, max_age))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &ids {
+            if let Some(tr) = self.0.remove(id) {
+                tr.reap();
+            }
+        }
+        ids.len()
+    }
+}
+
 pub trait TransactionCache {
     fn new() -> TrMap;
     fn get_transaction_start_time(&self, id: u64) -> f64;
     fn get_transaction_end_time(&self, id: u64) -> f64;
-    fn set_transaction(&mut self, id: u64, transaction: String, path: Option<String>) -> bool;
+    fn set_transaction(
+        &mut self,
+        id: u64,
+        transaction: String,
+        path: Option<String>,
+        trace: TraceContext,
+        decide: fn(&TraceContext) -> bool,
+    ) -> bool;
     fn availability_transaction(&self, id: u64) -> Option<u64>;
+    fn is_sampled(&self, id: u64) -> Option<bool>;
+    fn records_nodes(&self, id: u64) -> Option<bool>;
     fn drop_transaction(&mut self, id: u64) -> bool;
-    fn push_current(&mut self, id: u64, node: StackNode) -> bool;
-    fn pop_current(&mut self, id: u64, node_id: u64, end_time: f64) -> Option<u64>;
+    fn push_current(&mut self, id: u64, node: StackNode, task: u64) -> bool;
+    fn start_call(&mut self, id: u64, node_id: u64, start_time: f64, call: metrics::Call) -> bool;
+    fn pop_current(&mut self, id: u64, node_id: u64, end_time: f64, task: u64) -> Option<u64>;
+    fn start_task(&mut self, id: u64, task: u64, parent_task: u64) -> bool;
+    fn end_task(&mut self, id: u64, task: u64, end_time: f64) -> bool;
     fn set_transaction_path(&mut self, id: u64, path: String) -> bool;
     fn dump_transaction(&self, id: u64) -> String;
+    fn get_transaction_summary(&self, id: u64) -> Option<TransactionSummary>;
+    fn get_transaction_insights(&self, id: u64) -> Option<Insights>;
+    fn set_query_details(&mut self, id: u64, node_id: u64, details: QueryDetails) -> bool;
+    fn get_outbound_trace_headers(&self, id: u64, task: u64)
+        -> Option<(String, Option<String>)>;
+    fn record_node_error(&mut self, id: u64, error: ErrorInfo, task: u64) -> bool;
+    fn record_transaction_error(&mut self, id: u64, error: ErrorInfo) -> bool;
+    fn add_transaction_attribute(
+        &mut self,
+        id: u64,
+        key: String,
+        value: AttrValue,
+    ) -> PamResult<bool>;
+    fn add_node_attribute(
+        &mut self,
+        id: u64,
+        node_id: u64,
+        key: String,
+        value: AttrValue,
+    ) -> PamResult<bool>;
 }
 
 impl<'b> TransactionCache for TrMap {
@@ -447,7 +1530,7 @@ impl<'b> TransactionCache for TrMap {
         match self.0.get(&id) {
             Some(tr) => {
                 if !tr.nodes_stack.is_empty() {
-                    tr.nodes_stack[0].get_start_time();
+                    return tr.nodes_stack[0].get_start_time();
                 }
                 DEFAULT_TIME_VAL
             }
@@ -458,27 +1541,58 @@ impl<'b> TransactionCache for TrMap {
         match self.0.get(&id) {
             Some(tr) => {
                 if !tr.nodes_stack.is_empty() {
-                    tr.nodes_stack[0].get_end_time();
+                    return tr.nodes_stack[0].get_end_time();
                 }
                 DEFAULT_TIME_VAL
             }
             None => DEFAULT_TIME_VAL,
         }
     }
-    fn set_transaction(&mut self, id: u64, transaction: String, path: Option<String>) -> bool {
-        match self.0.entry(id) {
-            Entry::Occupied(_) => false,
-            Entry::Vacant(v) => {
-                v.insert(TransactionNode {
-                    base_name: transaction,
-                    nodes_stack: vec![],
-                    trace_node_count: 0,
-                    guid: format!("{:x}", rand::random::<u64>()),
-                    path: path.unwrap_or_else(|| "".to_owned()),
-                });
-                true
+    /// Register transaction, false if one with this id is active. Sampling decision is taken by
+    /// `decide` only for registered transaction.
+    fn set_transaction(
+        &mut self,
+        id: u64,
+        transaction: String,
+        path: Option<String>,
+        mut trace: TraceContext,
+        decide: fn(&TraceContext) -> bool,
+    ) -> bool {
+        let created_at = now();
+        if let Some(tr) = self.0.get(&id) {
+            // Id of killed thread is reused, transaction left by it is closed right away
+            let max_age = *recover(reaper::TRANSACTION_MAX_AGE.read());
+            if !tr.is_abandoned(created_at, max_age) {
+                return false;
             }
         }
+        trace.sampled = decide(&trace);
+        let tr = TransactionNode {
+            base_name: transaction,
+            nodes_stack: vec![],
+            trace_node_count: 0,
+            guid: format!("{:x}", rand::random::<u64>()),
+            path: path.unwrap_or_else(|| "".to_owned()),
+            trace,
+            errored: false,
+            error: None,
+            attributes: Attributes::new(),
+            insights: Insights::default(),
+            slow_queries: vec![],
+            pop_anomalies: 0,
+            created_at,
+            last_active: created_at,
+            truncation: Truncation::default(),
+            kept_by: None,
+            tasks: HashMap::new(),
+            calls: HashMap::new(),
+            query_stats: sql::QueryStats::new(),
+            metrics: metrics::MetricTable::new(created_at),
+        };
+        if let Some(abandoned) = self.0.insert(id, tr) {
+            abandoned.reap();
+        }
+        true
     }
     fn availability_transaction(&self, id: u64) -> Option<u64> {
         match self.0.get(&id) {
@@ -486,66 +1600,92 @@ impl<'b> TransactionCache for TrMap {
             None => None,
         }
     }
+    fn is_sampled(&self, id: u64) -> Option<bool> {
+        self.0.get(&id).map(|tr| tr.trace.sampled)
+    }
+    /// Whether node pushed now is kept. Only the root of unsampled transaction is.
+    fn records_nodes(&self, id: u64) -> Option<bool> {
+        self.0.get(&id).map(|tr| tr.records_nodes())
+    }
     fn drop_transaction(&mut self, id: u64) -> bool {
         match self.0.remove(&id) {
             Some(val) => {
-                let j: String = serde_json::to_string(&val).unwrap_or_else(|_| "".to_uppercase());
-                output::OUTPUT_QUEUE.lock().unwrap().push_back(j);
+                val.close();
+                true
+            }
+            None => false,
+        }
+    }
+    /// Push node to stack of task. Stack of task other than MAIN_TASK must be started first.
+    fn push_current(&mut self, id: u64, node: StackNode, task: u64) -> bool {
+        let tr: &mut TransactionNode = match self.active(id) {
+            Some(v) => v,
+            None => return false,
+        };
+        if !tr.records_nodes() {
+            return true;
+        }
+        match tr.stack_mut(task) {
+            Some(stack) => {
+                stack.push(node);
                 true
             }
             None => false,
         }
     }
-    fn push_current(&mut self, id: u64, node: StackNode) -> bool {
-        match self.0.get_mut(&id) {
-            Some(v) => {
-                v.nodes_stack.push(node);
+
+    /// Time call of unsampled transaction, its node is not recorded. It is added to metrics when
+    /// popped.
+    fn start_call(&mut self, id: u64, node_id: u64, start_time: f64, call: metrics::Call) -> bool {
+        match self.active(id) {
+            Some(tr) => {
+                tr.calls.insert(node_id, (start_time, call));
                 true
             }
             None => false,
         }
     }
 
-    fn pop_current(&mut self, id: u64, node_id: u64, end_time: f64) -> Option<u64> {
-        let c_tr: &mut TransactionNode = match self.0.get_mut(&id) {
+    fn pop_current(&mut self, id: u64, node_id: u64, end_time: f64, task: u64) -> Option<u64> {
+        let c_tr: &mut TransactionNode = match self.active(id) {
             Some(v) => v,
             None => return None,
         };
-        let ln = c_tr.nodes_stack.len();
-        if ln == 1 {
-            let root_id = &mut c_tr.nodes_stack[0];
-            match root_id {
-                _ => {
-                    root_id.set_endtime(end_time);
-                    root_id.comp_exclusive();
-                    c_tr.trace_node_count += 1;
+        // Node started by one task and finished by another is popped from the stack it is on
+        let task = match c_tr.task_of(node_id, task) {
+            Some(v) => v,
+            None if !c_tr.trace.sampled => {
+                if let Some((start_time, call)) = c_tr.calls.remove(&node_id) {
+                    let duration = (end_time - start_time).max(0.0);
+                    c_tr.metrics.record_call(&call, duration);
                 }
+                return None;
             }
-
-            return None;
-        };
-        let cur_id: StackNode = match c_tr.nodes_stack.pop() {
-            Some(mut v) => {
-                v.set_endtime(end_time);
-                v.comp_exclusive();
-                c_tr.trace_node_count += 1;
-                v
+            None => {
+                c_tr.pop_anomalies += 1;
+                warn!("Unable to pop node {}, it is not open in transaction {}", node_id, id);
+                return None;
             }
-            None => return None,
         };
-        let ln: usize = c_tr.nodes_stack.len();
-
-        if cur_id.get_node_id() == node_id {
-            let parent_node: &mut StackNode = &mut c_tr.nodes_stack[ln - 1];
-            parent_node.process_child(cur_id);
-            let t: u64 = parent_node.get_node_id();
-            return Some(t);
-        };
-
-        None
+        if c_tr.stack(task).and_then(|s| s.last()).map(|n| n.get_node_id()) != Some(node_id) {
+            c_tr.pop_anomalies += 1;
+        }
+        c_tr.pop_node(task, node_id, end_time)
+    }
+    fn start_task(&mut self, id: u64, task: u64, parent_task: u64) -> bool {
+        match self.active(id) {
+            Some(tr) => tr.start_task(task, parent_task),
+            None => false,
+        }
+    }
+    fn end_task(&mut self, id: u64, task: u64, end_time: f64) -> bool {
+        match self.active(id) {
+            Some(tr) => tr.end_task(task, end_time),
+            None => false,
+        }
     }
     fn set_transaction_path(&mut self, id: u64, path: String) -> bool {
-        match self.0.get_mut(&id) {
+        match self.active(id) {
             Some(tr) => {
                 tr.set_path(path);
                 true
@@ -559,4 +1699,96 @@ impl<'b> TransactionCache for TrMap {
             None => "".to_owned(),
         }
     }
+    fn get_transaction_summary(&self, id: u64) -> Option<TransactionSummary> {
+        self.0.get(&id).map(|tr| tr.summary())
+    }
+    /// Set details of open database node, or of slow query if the node is already finished.
+    fn set_query_details(&mut self, id: u64, node_id: u64, details: QueryDetails) -> bool {
+        let tr: &mut TransactionNode = match self.active(id) {
+            Some(v) => v,
+            None => return false,
+        };
+        if let Some(&mut StackNode::Database(ref mut x)) = tr.open_node_mut(node_id) {
+            x.details.update(details);
+            return true;
+        }
+        match tr.slow_queries.iter_mut().find(|q| q.node_id == node_id) {
+            Some(query) => {
+                query.details.update(details);
+                true
+            }
+            None => false,
+        }
+    }
+    /// Insights of finished nodes, the same that are added to dump when transaction is dropped.
+    fn get_transaction_insights(&self, id: u64) -> Option<Insights> {
+        self.0.get(&id).map(|tr| tr.insights())
+    }
+    /// `traceparent` and `tracestate` for outbound request made inside of current node.
+    fn get_outbound_trace_headers(
+        &self,
+        id: u64,
+        task: u64,
+    ) -> Option<(String, Option<String>)> {
+        let tr: &TransactionNode = self.0.get(&id)?;
+        let node: &StackNode = tr.current_node(task)?;
+        Some((
+            tr.trace.traceparent(node.get_span_id()),
+            tr.trace.tracestate.clone(),
+        ))
+    }
+    fn record_node_error(&mut self, id: u64, error: ErrorInfo, task: u64) -> bool {
+        let tr: &mut TransactionNode = match self.active(id) {
+            Some(v) => v,
+            None => return false,
+        };
+        if !tr.trace.sampled {
+            tr.errored = true;
+            return true;
+        }
+        match tr.stack_mut(task).and_then(|s| s.last_mut()) {
+            Some(node) => node.set_error(error),
+            None => return false,
+        }
+        tr.errored = true;
+        true
+    }
+    fn record_transaction_error(&mut self, id: u64, error: ErrorInfo) -> bool {
+        match self.active(id) {
+            Some(tr) => {
+                tr.error = Some(error);
+                tr.errored = true;
+                true
+            }
+            None => false,
+        }
+    }
+    fn add_transaction_attribute(
+        &mut self,
+        id: u64,
+        key: String,
+        value: AttrValue,
+    ) -> PamResult<bool> {
+        match self.active(id) {
+            Some(tr) => add_attribute(&mut tr.attributes, key, value).map(|_| true),
+            None => Ok(false),
+        }
+    }
+    /// Only active nodes, that are not popped yet, can get attributes.
+    fn add_node_attribute(
+        &mut self,
+        id: u64,
+        node_id: u64,
+        key: String,
+        value: AttrValue,
+    ) -> PamResult<bool> {
+        let tr: &mut TransactionNode = match self.active(id) {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+        match tr.open_node_mut(node_id) {
+            Some(node) => add_attribute(node.get_attributes_mut(), key, value).map(|_| true),
+            None => Ok(false),
+        }
+    }
 }
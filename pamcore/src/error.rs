@@ -0,0 +1,82 @@
+use std::any::Any;
+use std::fmt;
+use std::io;
+use std::sync::LockResult;
+
+use config::ConfigError;
+
+/// Error of pamagent core. Every variant maps to a subclass of `PamAgentError` in Python.
+#[derive(Debug)]
+pub enum PamError {
+    /// Malformed argument passed from Python: unparsable URL, NaN timestamp, unknown option.
+    InvalidInput(String),
+    /// Invalid agent configuration.
+    Config(String),
+    /// Output could not be set up.
+    Io(io::Error),
+    /// Panic inside of the core.
+    Internal(String),
+}
+
+pub type PamResult<T> = Result<T, PamError>;
+
+impl fmt::Display for PamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PamError::InvalidInput(ref v) => write!(f, "Invalid input: {}", v),
+            PamError::Config(ref v) => write!(f, "{}", v),
+            PamError::Io(ref v) => write!(f, "{}", v),
+            PamError::Internal(ref v) => write!(f, "Internal error: {}", v),
+        }
+    }
+}
+
+impl From<io::Error> for PamError {
+    fn from(err: io::Error) -> PamError {
+        match err.kind() {
+            io::ErrorKind::InvalidInput => PamError::Config(err.to_string()),
+            _ => PamError::Io(err),
+        }
+    }
+}
+
+impl From<ConfigError> for PamError {
+    fn from(err: ConfigError) -> PamError {
+        PamError::Config(err.0)
+    }
+}
+
+impl PamError {
+    pub fn from_panic(payload: Box<Any + Send>) -> PamError {
+        let msg = match payload.downcast_ref::<&str>() {
+            Some(v) => v.to_string(),
+            None => match payload.downcast_ref::<String>() {
+                Some(v) => v.clone(),
+                None => "unknown panic".to_owned(),
+            },
+        };
+        PamError::Internal(msg)
+    }
+}
+
+pub fn invalid_input<T: Into<String>>(msg: T) -> PamError {
+    PamError::InvalidInput(msg.into())
+}
+
+/// Check that timestamp passed from Python is usable.
+pub fn check_time(name: &str, val: f64) -> PamResult<f64> {
+    if val.is_finite() && val >= 0.0 {
+        Ok(val)
+    } else {
+        Err(invalid_input(format!("{} must be a non-negative timestamp, got {}", name, val)))
+    }
+}
+
+/// Take guard of lock even if it was poisoned by panic in other thread. The panic itself is
+/// reported to Python, so the agent keeps working with data left by the panicked call.
+pub fn recover<G>(res: LockResult<G>) -> G {
+    res.unwrap_or_else(|e| {
+        error!("Lock was poisoned by panic in other thread. Recovered");
+        e.into_inner()
+    })
+}
@@ -0,0 +1,63 @@
+//! Helpers for exporters that convert JSON dump of transaction into spans of other tracing systems.
+
+use serde_json::Value;
+
+pub fn str_field<'a>(node: &'a Value, key: &str) -> &'a str {
+    node.get(key).and_then(|v| v.as_str()).unwrap_or("")
+}
+
+/// Port of node, None if it is unknown.
+pub fn port_field(node: &Value) -> Option<u16> {
+    match node.get("port").and_then(|v| v.as_u64()) {
+        Some(0) | None => None,
+        Some(v) => Some(v as u16),
+    }
+}
+
+/// Name of database system in OpenTelemetry semantic conventions.
+pub fn db_system(product: &str) -> String {
+    match product.to_lowercase().as_ref() {
+        "postgresql" | "postgres" => "postgresql".to_owned(),
+        "mysql" => "mysql".to_owned(),
+        "sqlite" | "sqlite3" => "sqlite".to_owned(),
+        "redis" => "redis".to_owned(),
+        v => v.to_owned(),
+    }
+}
+
+/// Payload of timeslice metrics, it has no spans.
+pub fn is_metrics(payload: &Value) -> bool {
+    str_field(payload, "type") == "metrics"
+}
+
+/// Trace id of transaction, empty if it is unknown.
+pub fn trace_id(tr: &Value) -> &str {
+    tr.get("trace").map(|v| str_field(v, "trace_id")).unwrap_or("")
+}
+
+fn walk_node<F: FnMut(&Value, Option<&str>)>(node: &Value, parent: Option<&str>, f: &mut F) {
+    f(node, parent);
+    let span_id = str_field(node, "span_id");
+    if let Some(childrens) = node.get("childrens").and_then(|v| v.as_array()) {
+        for child in childrens {
+            walk_node(child, Some(span_id), f);
+        }
+    }
+}
+
+/// Call `f` for every trace node of transaction with span id of its parent, root first. Parent of
+/// root is the span of inbound request. Nodes left on stack are children of the previous one.
+pub fn walk_nodes<F: FnMut(&Value, Option<&str>)>(tr: &Value, mut f: F) {
+    let nodes = match tr.get("nodes_stack").and_then(|v| v.as_array()) {
+        Some(v) => v,
+        None => return,
+    };
+    let mut parent: Option<&str> = tr
+        .get("trace")
+        .and_then(|v| v.get("parent_span_id"))
+        .and_then(|v| v.as_str());
+    for node in nodes {
+        walk_node(node, parent, &mut f);
+        parent = Some(str_field(node, "span_id"));
+    }
+}
@@ -0,0 +1,275 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+use url::Url;
+
+use output::{get_connection, Stream};
+use tls::{TlsConfig, Transport};
+
+/// Max size of response headers and body that is read. Exporters only need the status.
+const MAX_RESPONSE_BYTES: u64 = 1024 * 1024;
+
+fn config_err<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err.to_string())
+}
+
+/// Minimal HTTP/1.1 client for exporters, that POSTs batches to one endpoint over keep-alive
+/// connection. `http://` endpoints are allowed only on localhost, same as plaintext PAMCollector.
+pub struct HttpClient {
+    url: String,
+    addr: String,
+    host: String,
+    path: String,
+    transport: Transport,
+    headers: Vec<(String, String)>,
+    stream: Option<Box<Stream>>,
+}
+
+/// Parse `key=value,key2=value2` list of extra request headers.
+pub fn parse_headers(val: &str) -> Result<Vec<(String, String)>, io::Error> {
+    let mut headers: Vec<(String, String)> = vec![];
+    for item in val.split(',').map(|v| v.trim()).filter(|v| !v.is_empty()) {
+        let mut parts = item.splitn(2, '=');
+        let key = parts.next().unwrap_or("").trim();
+        let value = match parts.next() {
+            Some(v) => v.trim(),
+            None => {
+                return Err(config_err(format!(
+                    "Invalid header {:?}, expected key=value",
+                    item
+                )))
+            }
+        };
+        let valid = |v: &str| !v.contains('\r') && !v.contains('\n');
+        if key.is_empty() || key.contains(':') || !valid(key) || !valid(value) {
+            return Err(config_err(format!("Invalid header {:?}", item)));
+        }
+        headers.push((key.to_owned(), value.to_owned()));
+    }
+    Ok(headers)
+}
+
+impl HttpClient {
+    /// Validate endpoint and build transport. `tls_config` is used for `https://` endpoints.
+    pub fn new(
+        endpoint: &str,
+        tls_config: &TlsConfig,
+        headers: Vec<(String, String)>,
+    ) -> Result<HttpClient, io::Error> {
+        let url = Url::parse(endpoint)
+            .map_err(|e| config_err(format!("Invalid endpoint {:?}: {}", endpoint, e)))?;
+        let plaintext = match url.scheme() {
+            "http" => true,
+            "https" => false,
+            v => return Err(config_err(format!("Unsupported scheme {} of {}", v, endpoint))),
+        };
+        let host = match url.host_str() {
+            Some(v) => v.to_owned(),
+            None => return Err(config_err(format!("No host in endpoint {}", endpoint))),
+        };
+        let port = url.port_or_known_default().unwrap_or(80);
+        let addr = match host.contains(':') {
+            true => format!("[{}]:{}", host.trim_matches(|c| c == '[' || c == ']'), port),
+            false => format!("{}:{}", host, port),
+        };
+        let path = match url.query() {
+            Some(q) => format!("{}?{}", url.path(), q),
+            None => url.path().to_owned(),
+        };
+        let tls_config = TlsConfig {
+            plaintext,
+            ..tls_config.clone()
+        };
+        let transport = tls_config.transport(&addr)?;
+        Ok(HttpClient {
+            url: endpoint.to_owned(),
+            host: match url.port() {
+                Some(p) => format!("{}:{}", host, p),
+                None => host,
+            },
+            addr,
+            path,
+            transport,
+            headers,
+            stream: None,
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn connect(&mut self) -> Result<(), io::Error> {
+        self.stream = None;
+        self.stream = Some(get_connection(&self.addr, &self.transport)?);
+        Ok(())
+    }
+
+    /// POST body and return response status. Connection is dropped on error and when server asks
+    /// to close it, and opened again by next call.
+    pub fn post(
+        &mut self,
+        content_type: &str,
+        content_encoding: Option<&str>,
+        body: &[u8],
+    ) -> Result<u16, io::Error> {
+        if self.stream.is_none() {
+            self.connect()?;
+        }
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n",
+            self.path,
+            self.host,
+            content_type,
+            body.len()
+        );
+        if let Some(v) = content_encoding {
+            request.push_str(&format!("Content-Encoding: {}\r\n", v));
+        }
+        for &(ref key, ref value) in &self.headers {
+            request.push_str(&format!("{}: {}\r\n", key, value));
+        }
+        request.push_str("\r\n");
+        let res = match self.stream {
+            Some(ref mut stream) => exchange(stream, request.as_bytes(), body),
+            None => return Err(io::Error::new(io::ErrorKind::NotConnected, "Not connected")),
+        };
+        match res {
+            Ok((status, keep_alive)) => {
+                if !keep_alive {
+                    self.stream = None;
+                }
+                Ok(status)
+            }
+            Err(e) => {
+                self.stream = None;
+                Err(e)
+            }
+        }
+    }
+}
+
+fn invalid_response<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+fn exchange(stream: &mut Box<Stream>, head: &[u8], body: &[u8]) -> Result<(u16, bool), io::Error> {
+    stream.write_all(head)?;
+    stream.write_all(body)?;
+    stream.flush()?;
+    read_response(stream)
+}
+
+/// Status line and headers of response that matter to the client.
+struct ResponseHead {
+    status: u16,
+    content_length: Option<u64>,
+    chunked: bool,
+    keep_alive: bool,
+}
+
+fn read_head<R: BufRead>(reader: &mut R, line: &mut String) -> Result<ResponseHead, io::Error> {
+    line.clear();
+    if reader.read_line(line)? == 0 {
+        return Err(invalid_response("Connection closed by server"));
+    }
+    let status: u16 = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| invalid_response(format!("Invalid status line {:?}", line.trim())))?;
+    let mut head = ResponseHead {
+        status,
+        content_length: None,
+        chunked: false,
+        keep_alive: true,
+    };
+    loop {
+        line.clear();
+        if reader.read_line(line)? == 0 {
+            return Err(invalid_response("Unexpected end of response headers"));
+        }
+        let header = line.trim();
+        if header.is_empty() {
+            return Ok(head);
+        }
+        let mut parts = header.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim().to_lowercase();
+        let value = parts.next().unwrap_or("").trim().to_lowercase();
+        match name.as_ref() {
+            "content-length" => head.content_length = value.parse().ok(),
+            "transfer-encoding" => head.chunked = value.contains("chunked"),
+            "connection" => head.keep_alive = value != "close",
+            _ => {}
+        }
+    }
+}
+
+fn skip_chunks<R: BufRead>(reader: &mut R, line: &mut String) -> Result<(), io::Error> {
+    loop {
+        line.clear();
+        reader.read_line(line)?;
+        let size = line.trim().split(';').next().unwrap_or("");
+        let size = u64::from_str_radix(size, 16)
+            .map_err(|_| invalid_response(format!("Invalid chunk size {:?}", size)))?;
+        if size == 0 {
+            break;
+        }
+        // Chunk and its trailing CRLF
+        io::copy(&mut reader.by_ref().take(size + 2), &mut io::sink())?;
+    }
+    // Trailer fields after the last chunk end with empty line
+    loop {
+        line.clear();
+        if reader.read_line(line)? == 0 || line.trim().is_empty() {
+            return Ok(());
+        }
+    }
+}
+
+/// Read response and return its status and whether connection can be reused. Interim `1xx`
+/// responses are skipped. Body without length is not read, connection is dropped instead, and
+/// so is connection with response over `MAX_RESPONSE_BYTES`, as the rest of it is left unread.
+fn read_response(stream: &mut Box<Stream>) -> Result<(u16, bool), io::Error> {
+    let mut reader = BufReader::new((&mut *stream).take(MAX_RESPONSE_BYTES));
+    let mut line = String::new();
+    let mut head = read_head(&mut reader, &mut line)?;
+    while head.status < 200 {
+        head = read_head(&mut reader, &mut line)?;
+    }
+    let mut keep_alive = head.keep_alive;
+    if head.status == 204 || head.status == 304 {
+        // Responses without body
+    } else if head.chunked {
+        skip_chunks(&mut reader, &mut line)?;
+    } else if let Some(len) = head.content_length {
+        if io::copy(&mut (&mut reader).take(len), &mut io::sink())? < len {
+            keep_alive = false;
+        }
+    } else {
+        // Body ends when server closes connection
+        keep_alive = false;
+    }
+    if reader.get_ref().limit() == 0 {
+        keep_alive = false;
+    }
+    Ok((head.status, keep_alive))
+}
+
+/// Server errors and throttling are retried, other rejected batches are dropped, as resending
+/// them would fail again.
+pub fn check_status(url: &str, status: u16) -> Result<(), io::Error> {
+    match status {
+        200..=299 => {
+            trace!("Batch is accepted by {}", url);
+            Ok(())
+        }
+        408 | 429 | 500..=599 => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} responded with status {}", url, status),
+        )),
+        _ => {
+            error!("{} rejected batch with status {}. Batch is dropped", url, status);
+            Ok(())
+        }
+    }
+}
@@ -2,71 +2,304 @@
 #![feature(proc_macro, specialization)]
 #![feature(refcell_replace_swap)]
 #![feature(proc_macro_path_invoc)]
+#[macro_use]
 extern crate pyo3;
 use pyo3::prelude::*;
+use pyo3::{exc, PyDict, PyErr, PyObject, ToPyObject};
 extern crate backoff;
 extern crate chrono;
 extern crate fern;
+extern crate flate2;
 #[macro_use]
 extern crate lazy_static;
 #[macro_use]
 extern crate log;
 extern crate rand;
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
 extern crate serde_json;
+extern crate toml;
 extern crate url;
 extern crate native_tls;
 
-use std::thread;
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::Ordering;
 
+#[cfg(feature = "bench")]
+mod bench;
+mod config;
 mod core;
+mod error;
+mod export;
+mod http;
 mod output;
+mod sql;
 mod logging;
-use core::{CacheNode, DatabaseNode, ExternalNode, FuncNode, StackNode, TransactionCache};
+mod metrics;
+mod otlp;
+mod reaper;
+mod sampling;
+mod tls;
+mod tracecontext;
+mod worker;
+mod zipkin;
+use core::{AttrValue, CacheNode, DatabaseNode, ErrorInfo, ExternalNode, FuncNode, QueryDetails,
+           StackNode, TransactionCache, MAIN_TASK};
 use url::Url;
-use self::output::Output;
-use self::output::PamCollectorOutput;
+use self::output::{Compression, FileOutput, OverflowPolicy, PamCollectorOutput};
+use self::tls::TlsConfig;
+use http::HttpClient;
+use metrics::Call;
+use otlp::OtlpOutput;
+use sql::QuotingStyle;
+use tracecontext::TraceContext;
+use zipkin::ZipkinOutput;
+use error::{check_time, invalid_input, recover, PamError, PamResult};
+
+const DEFAULT_WORKER_TIMEOUT: f64 = 5.0;
+
+py_exception!(pamagent_core, PamAgentError);
+py_exception!(pamagent_core, InvalidInputError, PamAgentError);
+py_exception!(pamagent_core, ConfigurationError, PamAgentError);
+py_exception!(pamagent_core, OutputError, PamAgentError);
+py_exception!(pamagent_core, InternalError, PamAgentError);
+
+fn to_py_err(err: PamError) -> PyErr {
+    let msg = err.to_string();
+    match err {
+        PamError::InvalidInput(_) => InvalidInputError::new(msg),
+        PamError::Config(_) => ConfigurationError::new(msg),
+        PamError::Io(_) => OutputError::new(msg),
+        PamError::Internal(_) => InternalError::new(msg),
+    }
+}
+
+fn catch<T, F: FnOnce() -> PamResult<T>>(f: F) -> PamResult<T> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(res) => res,
+        Err(payload) => Err(PamError::from_panic(payload)),
+    }
+}
+
+/// Run body of tracing call. In strict mode errors and panics are raised as `PamAgentError`,
+/// in lenient mode (default) they are logged and `default` is returned, so the instrumented
+/// application is never broken by the agent.
+fn guard<T, F: FnOnce() -> PamResult<T>>(default: T, f: F) -> PyResult<T> {
+    match catch(f) {
+        Ok(v) => Ok(v),
+        Err(e) => {
+            if recover(config::CONFIG.read()).strict {
+                Err(to_py_err(e))
+            } else {
+                error!("{}", e);
+                Ok(default)
+            }
+        }
+    }
+}
+
+/// Result of push that is not done, checked before node is built. Nodes of unsampled transaction,
+/// besides its root, are ignored, push to missing transaction fails.
+fn skipped_push(id: u64) -> Option<bool> {
+    match core::cache_read(id).records_nodes(id) {
+        Some(true) => None,
+        Some(false) => Some(true),
+        None => Some(false),
+    }
+}
+
+/// Like `skipped_push` for external, database and cache calls. Call of unsampled transaction is
+/// still timed for metrics.
+fn skipped_call<F: FnOnce() -> Call>(
+    id: u64,
+    node_id: u64,
+    start_time: f64,
+    call: F,
+) -> Option<bool> {
+    match skipped_push(id) {
+        Some(true) => Some(core::cache_write(id).start_call(id, node_id, start_time, call())),
+        res => res,
+    }
+}
+
+/// Run body of setup call. Errors and panics are always raised as `PamAgentError`.
+fn checked<T, F: FnOnce() -> PamResult<T>>(f: F) -> PyResult<T> {
+    catch(f).map_err(to_py_err)
+}
+
+#[cfg(feature = "bench")]
+fn benchmark_cache(
+    py: Python,
+    threads: usize,
+    iterations: usize,
+    shards: usize,
+) -> PyResult<PyObject> {
+    let result = py.allow_threads(|| bench::run_cache_bench(threads, iterations, shards));
+    let dict = PyDict::new(py);
+    dict.set_item("ops", result.ops)?;
+    dict.set_item("elapsed", result.elapsed)?;
+    dict.set_item("ops_per_sec", result.ops_per_sec())?;
+    Ok(dict.to_object(py))
+}
+
+#[cfg(not(feature = "bench"))]
+fn benchmark_cache(_: Python, _: usize, _: usize, _: usize) -> PyResult<PyObject> {
+    Err(to_py_err(PamError::Config(
+        "pamagent_core is built without bench feature".to_owned(),
+    )))
+}
+
+/// Convert JSON value into Python object. JSON objects become dicts.
+fn json_to_py(py: Python, value: &serde_json::Value) -> PyResult<PyObject> {
+    Ok(match *value {
+        serde_json::Value::Null => py.None(),
+        serde_json::Value::Bool(v) => v.to_object(py),
+        serde_json::Value::Number(ref v) => match (v.as_u64(), v.as_i64()) {
+            (Some(n), _) => n.to_object(py),
+            (None, Some(n)) => n.to_object(py),
+            _ => v.as_f64().unwrap_or(0.0).to_object(py),
+        },
+        serde_json::Value::String(ref v) => v.to_object(py),
+        serde_json::Value::Array(ref v) => {
+            let mut items: Vec<PyObject> = Vec::with_capacity(v.len());
+            for item in v {
+                items.push(json_to_py(py, item)?);
+            }
+            items.to_object(py)
+        }
+        serde_json::Value::Object(ref v) => {
+            let dict = PyDict::new(py);
+            for (key, item) in v {
+                dict.set_item(key, json_to_py(py, item)?)?;
+            }
+            dict.to_object(py)
+        }
+    })
+}
+
+/// Convert str, int, float or bool into its string form. None is skipped.
+fn py_to_setting(py: Python, key: &str, value: &PyObject) -> PyResult<Option<String>> {
+    if value.is_none() {
+        return Ok(None);
+    }
+    if let Ok(v) = value.extract::<bool>(py) {
+        return Ok(Some(v.to_string()));
+    }
+    if let Ok(v) = value.extract::<i64>(py) {
+        return Ok(Some(v.to_string()));
+    }
+    if let Ok(v) = value.extract::<f64>(py) {
+        return Ok(Some(v.to_string()));
+    }
+    match value.extract::<String>(py) {
+        Ok(v) => Ok(Some(v)),
+        Err(_) => Err(PyErr::new::<exc::TypeError, _>(format!(
+            "Unsupported type of value for {}",
+            key
+        ))),
+    }
+}
+
+/// Convert str, int, float or bool into value of custom attribute.
+fn py_to_attribute(py: Python, key: &str, value: &PyObject) -> PyResult<AttrValue> {
+    if let Ok(v) = value.extract::<bool>(py) {
+        return Ok(AttrValue::Bool(v));
+    }
+    if let Ok(v) = value.extract::<i64>(py) {
+        return Ok(AttrValue::Int(v));
+    }
+    if let Ok(v) = value.extract::<f64>(py) {
+        return Ok(AttrValue::Float(v));
+    }
+    match value.extract::<String>(py) {
+        Ok(v) => Ok(AttrValue::Str(v)),
+        Err(_) => Err(PyErr::new::<exc::TypeError, _>(format!(
+            "Unsupported type of value for attribute {}",
+            key
+        ))),
+    }
+}
 
 /// This module is implemented in Rust.
 ///
-/// This module has the ability to configure the logging level
-/// To configure the logging, it is necessary to set the variable environment PAMAGENT_LEVEL_LOG
-/// with a value from 0 to 3 before the application starts, if the environment variable is not set
-/// to the default value of 0 (see pamcore/src/logging.rs for more information)
+/// Agent settings are read at import from config file (PAMAGENT_CONFIG_FILE) and PAMAGENT_*
+/// environment variables, and can be changed later with `configure`. For example the logging level
+/// is set by PAMAGENT_LOG_LEVEL with a value from 0 to 3, 0 by default
+/// (see pamcore/src/config.rs for more information)
 ///
 #[py::modinit(pamagent_core)]
 fn init(py: Python, m: &PyModule) -> PyResult<()> {
     logging::configure_logging();
+    config::init();
+    m.add("PamAgentError", py.get_type::<PamAgentError>())?;
+    m.add("InvalidInputError", py.get_type::<InvalidInputError>())?;
+    m.add("ConfigurationError", py.get_type::<ConfigurationError>())?;
+    m.add("OutputError", py.get_type::<OutputError>())?;
+    m.add("InternalError", py.get_type::<InternalError>())?;
 
     /// Set transaction
     ///
     /// :param int id: Transaction ID. ThreadID as usual.
     /// :param str transaction: Transaction name.
     /// :param str path: Path of transaction. URI without qs as usual.
-    /// :return: the return code.
+    /// :param str traceparent: W3C traceparent header of inbound request. Continues distributed
+    ///                         trace of caller, new trace is started if it is None or invalid.
+    /// :param str tracestate: W3C tracestate header of inbound request.
+    /// :return: Return True if transaction is registered, False if transaction with this id is
+    ///          already active. Head sampling decision is taken for registered transaction only,
+    ///          see is_sampled.
     /// :rtype: bool
     ///
     #[pyfn(m, "set_transaction")]
-    fn set_transaction_py(id: u64, transaction: String, path: Option<String>) -> PyResult<bool> {
-        Ok(core::TRANSACTION_CACHE
-            .write()
-            .unwrap()
-            .set_transaction(id, transaction, path))
+    fn set_transaction_py(
+        id: u64,
+        transaction: String,
+        path: Option<String>,
+        traceparent: Option<String>,
+        tracestate: Option<String>,
+    ) -> PyResult<bool> {
+        guard(false, || {
+            let trace = TraceContext::from_headers(
+                traceparent.as_ref().map(|v| v.as_str()),
+                tracestate.as_ref().map(|v| v.as_str()),
+            );
+            let mut cache = core::cache_write(id);
+            Ok(cache.set_transaction(id, transaction, path, trace, sampling::decide))
+        })
+    }
+
+    /// Head sampling decision of transaction. Only the root node of unsampled transaction is
+    /// recorded, so hooks may skip it. Trace continued from caller follows decision of caller.
+    ///
+    /// :param int id: Transaction ID. ThreadID as usual.
+    /// :return: Return whether transaction is sampled or None if Transaction not found.
+    /// :rtype: bool or None
+    ///
+    #[pyfn(m, "is_sampled")]
+    fn is_sampled_py(id: u64) -> PyResult<Option<bool>> {
+        guard(None, || Ok(core::cache_read(id).is_sampled(id)))
     }
 
     /// Get transaction by id
     ///
     /// :param int id: Transaction ID. ThreadID as usual.
+    /// :param bool sampled_only: Return None for transaction that is not sampled. Default is
+    ///                           False.
     /// :return: Return transaction_id or None if Transaction not availability.
     /// :rtype: int or None
     ///
     #[pyfn(m, "get_transaction")]
-    fn get_transaction_py(id: u64) -> PyResult<Option<u64>> {
-        Ok(core::TRANSACTION_CACHE
-            .read()
-            .unwrap()
-            .availability_transaction(id))
+    fn get_transaction_py(id: u64, sampled_only: Option<bool>) -> PyResult<Option<u64>> {
+        guard(None, || {
+            let cache = core::cache_read(id);
+            if sampled_only.unwrap_or(false) && cache.is_sampled(id) != Some(true) {
+                return Ok(None);
+            }
+            Ok(cache.availability_transaction(id))
+        })
     }
 
     /// Get transaction start time
@@ -78,10 +311,7 @@ fn init(py: Python, m: &PyModule) -> PyResult<()> {
     ///
     #[pyfn(m, "get_transaction_start_time")]
     fn get_transaction_start_time_py(id: u64) -> PyResult<f64> {
-        Ok(core::TRANSACTION_CACHE
-            .read()
-            .unwrap()
-            .get_transaction_start_time(id))
+        guard(0.0, || Ok(core::cache_read(id).get_transaction_start_time(id)))
     }
 
     /// Get transaction end time
@@ -93,10 +323,83 @@ fn init(py: Python, m: &PyModule) -> PyResult<()> {
     ///
     #[pyfn(m, "get_transaction_end_time")]
     fn get_transaction_end_time_py(id: u64) -> PyResult<f64> {
-        Ok(core::TRANSACTION_CACHE
-            .read()
-            .unwrap()
-            .get_transaction_end_time(id))
+        guard(0.0, || Ok(core::cache_read(id).get_transaction_end_time(id)))
+    }
+
+    /// Get timing summary of transaction
+    ///
+    /// :param int id: Transaction ID. ThreadID as usual.
+    /// :return: Dict with keys guid, start_time, end_time, duration, exclusive, node_count,
+    ///          max_depth, time_by_type (exclusive time of func, external, database and cache
+    ///          nodes), database_calls, errored, sampled, pop_anomalies and summarized_nodes
+    ///          (nodes over max_nodes or max_depth). None if Transaction not found.
+    /// :rtype: dict or None
+    ///
+    #[pyfn(m, "get_transaction_summary")]
+    fn get_transaction_summary_py(py: Python, id: u64) -> PyResult<PyObject> {
+        let value = guard(serde_json::Value::Null, || {
+            match core::cache_read(id).get_transaction_summary(id) {
+                Some(summary) => {
+                    serde_json::to_value(summary).map_err(|e| PamError::Internal(e.to_string()))
+                }
+                None => Ok(serde_json::Value::Null),
+            }
+        })?;
+        json_to_py(py, &value)
+    }
+
+    /// Get insights of transaction: performance problems like N+1 queries, found in finished
+    /// nodes. The same insights are added to transaction dump when it is dropped.
+    ///
+    /// :param int id: Transaction ID. ThreadID as usual.
+    /// :return: Dict with n_plus_one, list of dicts with parent_span_id, parent_name, fingerprint,
+    ///          normalized, operation, target, count and total_time of queries called at least
+    ///          n_plus_one_threshold times under one parent node. None if Transaction not found.
+    /// :rtype: dict or None
+    ///
+    #[pyfn(m, "get_transaction_insights")]
+    fn get_transaction_insights_py(py: Python, id: u64) -> PyResult<PyObject> {
+        let value = guard(serde_json::Value::Null, || {
+            match core::cache_read(id).get_transaction_insights(id) {
+                Some(insights) => {
+                    serde_json::to_value(insights).map_err(|e| PamError::Internal(e.to_string()))
+                }
+                None => Ok(serde_json::Value::Null),
+            }
+        })?;
+        json_to_py(py, &value)
+    }
+
+    /// Get W3C Trace Context headers for outbound request made inside of current TransactionNode,
+    /// usually ExternalNode.
+    ///
+    /// :param int id: Transaction ID. ThreadID as usual.
+    /// :param int task_id: Task started by start_task. Main stack of transaction if None.
+    /// :return: Dict with traceparent and, if inbound request had it, tracestate. None if
+    ///          Transaction not found or has no active node.
+    /// :rtype: dict or None
+    ///
+    #[pyfn(m, "get_outbound_trace_headers")]
+    fn get_outbound_trace_headers_py(
+        py: Python,
+        id: u64,
+        task_id: Option<u64>,
+    ) -> PyResult<PyObject> {
+        let task_id = task_id.unwrap_or(MAIN_TASK);
+        let headers = guard(None, || {
+            Ok(core::cache_read(id).get_outbound_trace_headers(id, task_id))
+        })?;
+        Ok(match headers {
+            Some((traceparent, tracestate)) => {
+                let dict = PyDict::new(py);
+                dict.set_item("traceparent", traceparent)?;
+                if let Some(v) = tracestate {
+                    dict.set_item("tracestate", v)?;
+                }
+                dict.to_object(py)
+            }
+            None => py.None(),
+        })
     }
 
     /// Push trace node to current transaction
@@ -106,6 +409,7 @@ fn init(py: Python, m: &PyModule) -> PyResult<()> {
     /// :param float start_time: Timestamp when TransactionNode is activated
     /// :param func_name: Function name if exists
     /// :type func_name: str or None
+    /// :param int task_id: Task started by start_task. Main stack of transaction if None.
     /// :return: the return code.
     /// :rtype: bool
     ///
@@ -115,15 +419,23 @@ fn init(py: Python, m: &PyModule) -> PyResult<()> {
         node_id: u64,
         start_time: f64,
         func_name: Option<String>,
+        task_id: Option<u64>,
     ) -> PyResult<bool> {
-        Ok(core::TRANSACTION_CACHE.write().unwrap().push_current(
-            id,
-            StackNode::Func(FuncNode::new(
-                node_id,
-                start_time,
-                func_name.unwrap_or_else(|| "unknow".to_string()),
-            )),
-        ))
+        guard(false, || {
+            let start_time = check_time("start_time", start_time)?;
+            if let Some(res) = skipped_push(id) {
+                return Ok(res);
+            }
+            Ok(core::cache_write(id).push_current(
+                id,
+                StackNode::Func(FuncNode::new(
+                    node_id,
+                    start_time,
+                    func_name.unwrap_or_else(|| "unknow".to_string()),
+                )),
+                task_id.unwrap_or(MAIN_TASK),
+            ))
+        })
     }
 
     /// Push external trace node to current transaction
@@ -134,6 +446,7 @@ fn init(py: Python, m: &PyModule) -> PyResult<()> {
     /// :param str url: Full URL that was used to request an external service.
     /// :param str library: Name of library
     /// :param str method: Method that was used to request an external service
+    /// :param int task_id: Task started by start_task. Main stack of transaction if None.
     /// :return: the return code.
     /// :rtype: bool
     ///
@@ -145,24 +458,60 @@ fn init(py: Python, m: &PyModule) -> PyResult<()> {
         url: &str,
         library: String,
         method: String,
+        task_id: Option<u64>,
     ) -> PyResult<bool> {
-        let parse_url = Url::parse(url).unwrap();
-        let host = Some(parse_url.host_str().unwrap_or("undef").to_string());
-        let port = parse_url.port();
-        let path = parse_url.path();
-
-        Ok(core::TRANSACTION_CACHE.write().unwrap().push_current(
-            id,
-            StackNode::External(ExternalNode::new(
-                node_id,
-                start_time,
-                host.unwrap_or_else(|| "undef".to_string()).to_string(),
-                port.unwrap_or(0),
-                library,
-                method,
-                path,
-            )),
-        ))
+        guard(false, || {
+            let start_time = check_time("start_time", start_time)?;
+            let parse_url = Url::parse(url)
+                .map_err(|e| invalid_input(format!("Unable to parse url {:?}: {}", url, e)))?;
+            let host = parse_url.host_str().unwrap_or("undef").to_string();
+            let skipped = skipped_call(id, node_id, start_time, || Call::External {
+                host: host.clone(),
+            });
+            if let Some(res) = skipped {
+                return Ok(res);
+            }
+            let port = parse_url.port();
+            let path = parse_url.path();
+
+            Ok(core::cache_write(id).push_current(
+                id,
+                StackNode::External(ExternalNode::new(
+                    node_id,
+                    start_time,
+                    host,
+                    port.unwrap_or(0),
+                    library,
+                    method,
+                    path,
+                )),
+                task_id.unwrap_or(MAIN_TASK),
+            ))
+        })
+    }
+
+    /// Obfuscate SQL statement and parse its operation and target. Comments are removed, string,
+    /// numeric, hex and boolean literals are replaced with ?, IN lists are collapsed into IN (?).
+    /// Results are kept in LRU cache of sql_cache_size statements.
+    ///
+    /// :param str sql: SQL statement as passed to cursor.execute
+    /// :param str quoting_style: Quotes of string literals: single (default), double or
+    ///                           single+double. Other quotes are identifiers.
+    /// :return: Obfuscated sql, operation and target table, e.g.
+    ///          ('SELECT * FROM orders WHERE id = ?', 'select', 'orders'). Statement that fails
+    ///          to parse gives empty strings, raw sql is never returned.
+    /// :rtype: tuple
+    ///
+    #[pyfn(m, "parse_sql")]
+    fn parse_sql_py(
+        sql: &str,
+        quoting_style: Option<String>,
+    ) -> PyResult<(String, String, String)> {
+        let quoting = QuotingStyle::parse(&quoting_style.unwrap_or_default());
+        guard((String::new(), String::new(), String::new()), || {
+            let statement = recover(sql::SQL_CACHE.lock()).get(sql, quoting);
+            Ok((statement.obfuscated, statement.operation, statement.target))
+        })
     }
 
     /// Push database trace node to current transaction
@@ -176,7 +525,11 @@ fn init(py: Python, m: &PyModule) -> PyResult<()> {
     /// :param int port: Port of database instanse
     /// :param str operation: SQL Operation
     /// :param str target: Target table/view
-    /// :param str sql: Obfuscated sql
+    /// :param str sql: Obfuscated sql, or sql as executed if quoting_style is given. Its normalized
+    ///                 form and fingerprint are added to node.
+    /// :param int task_id: Task started by start_task. Main stack of transaction if None.
+    /// :param str quoting_style: Quoting style of database, as for parse_sql. If given, sql is
+    ///                           obfuscated in the same cached parse parse_sql did for it.
     ///
     #[pyfn(m, "push_current_database")]
     fn push_current_database_py(
@@ -190,23 +543,47 @@ fn init(py: Python, m: &PyModule) -> PyResult<()> {
         operation: String,
         target: String,
         sql: String,
+        task_id: Option<u64>,
+        quoting_style: Option<String>,
     ) -> PyResult<bool> {
-        let host: String = host.unwrap_or("".to_string());
-        let port: u16 = port.unwrap_or(0);
-        Ok(core::TRANSACTION_CACHE.write().unwrap().push_current(
-            id,
-            StackNode::Database(DatabaseNode::new(
-                node_id,
-                start_time,
-                host,
-                port,
-                database_product,
-                database_name,
-                operation,
-                target,
-                sql,
-            )),
-        ))
+        guard(false, || {
+            let start_time = check_time("start_time", start_time)?;
+            let skipped = skipped_call(id, node_id, start_time, || Call::Database {
+                database_product: database_product.clone(),
+                operation: operation.clone(),
+                target: target.clone(),
+            });
+            if let Some(res) = skipped {
+                return Ok(res);
+            }
+            let host: String = host.unwrap_or("".to_string());
+            let port: u16 = port.unwrap_or(0);
+            let quoting =
+                quoting_style.as_ref().map_or(QuotingStyle::Single, |v| QuotingStyle::parse(v));
+            let statement = recover(sql::SQL_CACHE.lock()).get(&sql, quoting);
+            let (sql, normalized) = match recover(config::CONFIG.read()).record_sql.as_ref() {
+                "off" => ("".to_owned(), "".to_owned()),
+                _ if quoting_style.is_some() => (statement.obfuscated, statement.normalized),
+                _ => (sql, statement.normalized),
+            };
+            Ok(core::cache_write(id).push_current(
+                id,
+                StackNode::Database(DatabaseNode::new(
+                    node_id,
+                    start_time,
+                    host,
+                    port,
+                    database_product,
+                    database_name,
+                    operation,
+                    target,
+                    sql,
+                    normalized,
+                    statement.fingerprint,
+                )),
+                task_id.unwrap_or(MAIN_TASK),
+            ))
+        })
     }
 
     /// Push cache trace node to current transaction
@@ -218,6 +595,7 @@ fn init(py: Python, m: &PyModule) -> PyResult<()> {
     /// :param str database_name: Name of database name.
     /// :param str host: Host of cache instanse
     /// :param int port: Port of cache instanse
+    /// :param int task_id: Task started by start_task. Main stack of transaction if None.
     ///
     #[pyfn(m, "push_current_cache")]
     fn push_current_cache_py(
@@ -229,19 +607,31 @@ fn init(py: Python, m: &PyModule) -> PyResult<()> {
         port: u16,
         operation: String,
         database_product: String,
+        task_id: Option<u64>,
     ) -> PyResult<bool> {
-        Ok(core::TRANSACTION_CACHE.write().unwrap().push_current(
-            id,
-            StackNode::Cache(CacheNode::new(
-                node_id,
-                start_time,
-                host,
-                port,
-                database_product,
-                database_name,
-                operation,
-            )),
-        ))
+        guard(false, || {
+            let start_time = check_time("start_time", start_time)?;
+            let skipped = skipped_call(id, node_id, start_time, || Call::Cache {
+                database_product: database_product.clone(),
+                operation: operation.clone(),
+            });
+            if let Some(res) = skipped {
+                return Ok(res);
+            }
+            Ok(core::cache_write(id).push_current(
+                id,
+                StackNode::Cache(CacheNode::new(
+                    node_id,
+                    start_time,
+                    host,
+                    port,
+                    database_product,
+                    database_name,
+                    operation,
+                )),
+                task_id.unwrap_or(MAIN_TASK),
+            ))
+        })
     }
 
     /// Pop TraceNode from TraceStack. Call when TransactionNode is closed.
@@ -249,29 +639,200 @@ fn init(py: Python, m: &PyModule) -> PyResult<()> {
     /// :param int id: Transaction ID. ThreadID as usual.
     /// :param int node_id: ID of TransactionNode. Object.__id__ as usual.
     /// :param float end_time: Timestamp when TransactionNode is closed.
+    /// :param int task_id: Task that closes the node. Node started by other task is popped from
+    ///                     the stack of that task.
     /// :return: Return ID of Parent TransactionNode. If current TransactionNode not found return None
     /// :rtype: int or None
     ///
     #[pyfn(m, "pop_current")]
-    fn pop_current_py(id: u64, node_id: u64, end_time: f64) -> PyResult<Option<u64>> {
-        Ok(core::TRANSACTION_CACHE
-            .write()
-            .unwrap()
-            .pop_current(id, node_id, end_time))
+    fn pop_current_py(
+        id: u64,
+        node_id: u64,
+        end_time: f64,
+        task_id: Option<u64>,
+    ) -> PyResult<Option<u64>> {
+        guard(None, || {
+            let end_time = check_time("end_time", end_time)?;
+            let task_id = task_id.unwrap_or(MAIN_TASK);
+            Ok(core::cache_write(id).pop_current(id, node_id, end_time, task_id))
+        })
+    }
+
+    /// Start stack of task that runs in parallel with others of the same transaction, e.g. of
+    /// asyncio task. Nodes finished on top of the task stack are attached to the node that is
+    /// current in parent task now.
+    ///
+    /// :param int id: Transaction ID.
+    /// :param int task_id: ID of task, not 0. id(asyncio.current_task()) as usual.
+    /// :param int parent_task_id: Task that starts the task. Main stack of transaction if None.
+    /// :return: the return code. False if Transaction not found or task is already started.
+    /// :rtype: bool
+    ///
+    #[pyfn(m, "start_task")]
+    fn start_task_py(id: u64, task_id: u64, parent_task_id: Option<u64>) -> PyResult<bool> {
+        guard(false, || {
+            let parent_task_id = parent_task_id.unwrap_or(MAIN_TASK);
+            Ok(core::cache_write(id).start_task(id, task_id, parent_task_id))
+        })
+    }
+
+    /// End stack of task. Nodes left open by the task are closed.
+    ///
+    /// :param int id: Transaction ID.
+    /// :param int task_id: ID of task started by start_task.
+    /// :param float end_time: Timestamp for nodes left open.
+    /// :return: the return code. False if Transaction or task not found.
+    /// :rtype: bool
+    ///
+    #[pyfn(m, "end_task")]
+    fn end_task_py(id: u64, task_id: u64, end_time: f64) -> PyResult<bool> {
+        guard(false, || {
+            let end_time = check_time("end_time", end_time)?;
+            Ok(core::cache_write(id).end_task(id, task_id, end_time))
+        })
     }
 
-    /// Drop transaction from transaction cache
+    /// Check duration of database call against slow_query_threshold of current config, e.g. to
+    /// collect call stack only for slow queries.
+    ///
+    /// :param float duration: Duration of database call in seconds.
+    /// :return: True if the call is reported in slow_queries of transaction.
+    /// :rtype: bool
+    ///
+    #[pyfn(m, "is_slow_query")]
+    fn is_slow_query_py(duration: f64) -> PyResult<bool> {
+        Ok(core::is_slow_query(duration))
+    }
+
+    /// Attach context to database TransactionNode. Details of slow queries are reported in
+    /// slow_queries of transaction. Call before pop_current of the node, or after it for slow
+    /// queries, e.g. to attach explain plan.
     ///
     /// :param int id: Transaction ID. ThreadID as usual.
-    /// :return: the return code.
+    /// :param int node_id: ID of database TransactionNode. Object.__id__ as usual.
+    /// :param int row_count: Count of rows returned or changed by query.
+    /// :param str explain_plan: Free-form plan of query, e.g. output of EXPLAIN.
+    /// :param list stack: Formatted frames of call stack that made the query, outermost first.
+    /// :return: True if node or slow query is found.
+    /// :rtype: bool
+    ///
+    #[pyfn(m, "set_query_details")]
+    fn set_query_details_py(
+        id: u64,
+        node_id: u64,
+        row_count: Option<i64>,
+        explain_plan: Option<String>,
+        stack: Option<Vec<String>>,
+    ) -> PyResult<bool> {
+        guard(false, || {
+            let details = QueryDetails::new(row_count, explain_plan, stack);
+            Ok(core::cache_write(id).set_query_details(id, node_id, details))
+        })
+    }
+
+    /// Record exception raised inside of current TransactionNode. Transaction is marked as errored.
+    /// Call before pop_current of the node.
+    ///
+    /// :param int id: Transaction ID. ThreadID as usual.
+    /// :param str exc_class: Qualified name of exception class.
+    /// :param str message: Exception message.
+    /// :param frames: Formatted traceback frames, outermost first.
+    /// :type frames: list of str or None
+    /// :param int task_id: Task started by start_task. Main stack of transaction if None.
+    /// :return: the return code. False if Transaction not found or has no active node.
+    /// :rtype: bool
+    ///
+    #[pyfn(m, "record_node_error")]
+    fn record_node_error_py(
+        id: u64,
+        exc_class: String,
+        message: String,
+        frames: Option<Vec<String>>,
+        task_id: Option<u64>,
+    ) -> PyResult<bool> {
+        guard(false, || {
+            let error = ErrorInfo::new(exc_class, message, frames.unwrap_or_default());
+            let task_id = task_id.unwrap_or(MAIN_TASK);
+            Ok(core::cache_write(id).record_node_error(id, error, task_id))
+        })
+    }
+
+    /// Record exception that terminated transaction. Transaction is marked as errored.
+    ///
+    /// :param int id: Transaction ID. ThreadID as usual.
+    /// :param str exc_class: Qualified name of exception class.
+    /// :param str message: Exception message.
+    /// :param frames: Formatted traceback frames, outermost first.
+    /// :type frames: list of str or None
+    /// :return: the return code. False if Transaction not found.
+    /// :rtype: bool
+    ///
+    #[pyfn(m, "record_transaction_error")]
+    fn record_transaction_error_py(
+        id: u64,
+        exc_class: String,
+        message: String,
+        frames: Option<Vec<String>>,
+    ) -> PyResult<bool> {
+        guard(false, || {
+            let error = ErrorInfo::new(exc_class, message, frames.unwrap_or_default());
+            Ok(core::cache_write(id).record_transaction_error(id, error))
+        })
+    }
+
+    /// Add custom attribute to transaction, e.g. tenant id or release version. Key is up to 255
+    /// chars, str value is truncated to 255 chars, transaction has up to 64 attributes.
+    ///
+    /// :param int id: Transaction ID. ThreadID as usual.
+    /// :param str key: Attribute name. Value of existing attribute is replaced.
+    /// :param value: Attribute value.
+    /// :type value: str, int, float or bool
+    /// :return: the return code. False if Transaction not found or limits are exceeded.
+    /// :rtype: bool
+    ///
+    #[pyfn(m, "add_transaction_attribute")]
+    fn add_transaction_attribute_py(
+        py: Python,
+        id: u64,
+        key: String,
+        value: PyObject,
+    ) -> PyResult<bool> {
+        let value = py_to_attribute(py, &key, &value)?;
+        guard(false, || core::cache_write(id).add_transaction_attribute(id, key, value))
+    }
+
+    /// Add custom attribute to active TransactionNode. Limits are the same as for transaction.
+    ///
+    /// :param int id: Transaction ID. ThreadID as usual.
+    /// :param int node_id: ID of TransactionNode. Object.__id__ as usual.
+    /// :param str key: Attribute name. Value of existing attribute is replaced.
+    /// :param value: Attribute value.
+    /// :type value: str, int, float or bool
+    /// :return: the return code. False if TransactionNode is not active or limits are exceeded.
+    /// :rtype: bool
+    ///
+    #[pyfn(m, "add_node_attribute")]
+    fn add_node_attribute_py(
+        py: Python,
+        id: u64,
+        node_id: u64,
+        key: String,
+        value: PyObject,
+    ) -> PyResult<bool> {
+        let value = py_to_attribute(py, &key, &value)?;
+        guard(false, || core::cache_write(id).add_node_attribute(id, node_id, key, value))
+    }
+
+    /// Drop transaction from transaction cache and send it to output, unless sampling discards it:
+    /// with tail_sampling on, only transaction matching one of keep_* rules is sent.
+    ///
+    /// :param int id: Transaction ID. ThreadID as usual.
+    /// :return: the return code. True for discarded transaction too.
     /// :rtype: bool
     ///
     #[pyfn(m, "drop_transaction")]
     fn drop_transaction_py(id: u64) -> PyResult<bool> {
-        Ok(core::TRANSACTION_CACHE
-            .write()
-            .unwrap()
-            .drop_transaction(id))
+        guard(false, || Ok(core::cache_write(id).drop_transaction(id)))
     }
 
     /// Set transaction path
@@ -283,10 +844,7 @@ fn init(py: Python, m: &PyModule) -> PyResult<()> {
     ///
     #[pyfn(m, "set_transaction_path")]
     fn set_transaction_path_py(id: u64, path: String) -> PyResult<bool> {
-        Ok(core::TRANSACTION_CACHE
-            .write()
-            .unwrap()
-            .set_transaction_path(id, path))
+        guard(false, || Ok(core::cache_write(id).set_transaction_path(id, path)))
     }
 
     /// Dump transaction into JSON string
@@ -297,27 +855,433 @@ fn init(py: Python, m: &PyModule) -> PyResult<()> {
     ///
     #[pyfn(m, "dump_transaction")]
     fn dump_transaction_py(id: u64) -> PyResult<String> {
-        Ok(core::TRANSACTION_CACHE
-            .write()
-            .unwrap()
-            .dump_transaction(id))
+        guard("".to_owned(), || Ok(core::cache_write(id).dump_transaction(id)))
     }
 
     /// Activate output transport to PAMCollector
     ///
     /// :param str token: Secret token for auth on PAMCollector.
     /// :param str addr: Address with format host:port for connect to PAMCollector instance .
+    /// :param bool plaintext: Connect without TLS. Allowed only for PAMCollector on localhost.
+    /// :param str tls_hostname: Hostname for SNI and certificate verification. Host of addr by default.
+    /// :param str ca_file: Path to PEM bundle with additional trusted CA certificates.
+    /// :param str client_cert: Path to PKCS #12 archive with client certificate for mutual TLS.
+    /// :param str client_cert_password: Password of client_cert archive.
+    /// :param str min_tls_version: Minimal TLS version, one of "tls1.0", "tls1.1", "tls1.2".
     /// :return: the return code.
     /// :rtype: bool
+    /// :raises ConfigurationError: if TLS settings are invalid.
     ///
     #[pyfn(m, "activate")]
-    fn activate_py(token: &str, addr: &str) -> PyResult<bool> {
-        let output_transport: PamCollectorOutput =
-            PamCollectorOutput::new(token.to_owned(), addr.to_owned());
-        thread::spawn(move || {
-            output_transport.start();
-        });
-        Ok(true)
+    fn activate_py(
+        token: &str,
+        addr: &str,
+        plaintext: Option<bool>,
+        tls_hostname: Option<String>,
+        ca_file: Option<String>,
+        client_cert: Option<String>,
+        client_cert_password: Option<String>,
+        min_tls_version: Option<String>,
+    ) -> PyResult<bool> {
+        checked(|| {
+            let tls_config = TlsConfig {
+                plaintext: plaintext.unwrap_or(false),
+                hostname: tls_hostname,
+                ca_file,
+                client_cert,
+                client_cert_password,
+                min_version: min_tls_version,
+            };
+            let transport = tls_config.transport(addr)?;
+            let output_transport: PamCollectorOutput =
+                PamCollectorOutput::new(token.to_owned(), addr.to_owned(), transport);
+            Ok(worker::activate(Box::new(output_transport)))
+        })
+    }
+
+    /// Activate output transport to local file. Every transaction is appended as one line of JSON.
+    ///
+    /// :param str path: Path of output file.
+    /// :param int max_bytes: Size of file in bytes after which the file is rotated. 0 disables rotation.
+    /// :param int max_files: How many rotated files (path.1, path.2, ...) are kept.
+    /// :return: the return code.
+    /// :rtype: bool
+    ///
+    #[pyfn(m, "activate_file")]
+    fn activate_file_py(path: &str, max_bytes: Option<u64>, max_files: Option<u32>) -> PyResult<bool> {
+        let output_transport: FileOutput = FileOutput::new(
+            path.to_owned(),
+            max_bytes.unwrap_or(0),
+            max_files.unwrap_or(0),
+        );
+        Ok(worker::activate(Box::new(output_transport)))
+    }
+
+    /// Activate export of transactions as OpenTelemetry spans to OTLP/HTTP endpoint with JSON
+    /// encoding. Batches are compressed as set by configure_batching.
+    ///
+    /// :param str endpoint: URL of endpoint, e.g. http://localhost:4318/v1/traces. http:// is
+    ///                      allowed only for localhost, https:// uses TLS settings of agent config.
+    /// :param str headers: Extra request headers as key=value,key2=value2, e.g. for auth.
+    /// :param str service_name: Value of service.name resource attribute. Default is taken from
+    ///                          agent config.
+    /// :return: the return code.
+    /// :rtype: bool
+    /// :raises ConfigurationError: if endpoint, headers or TLS settings are invalid.
+    ///
+    #[pyfn(m, "activate_otlp")]
+    fn activate_otlp_py(
+        endpoint: &str,
+        headers: Option<String>,
+        service_name: Option<String>,
+    ) -> PyResult<bool> {
+        checked(|| {
+            let config = recover(config::CONFIG.read()).clone();
+            let headers = http::parse_headers(&headers.unwrap_or_default())?;
+            let client = HttpClient::new(endpoint, &config.tls_config(), headers)?;
+            let service_name = service_name.unwrap_or(config.service_name);
+            Ok(worker::activate(Box::new(OtlpOutput::new(client, service_name))))
+        })
+    }
+
+    /// Activate export of transactions as Zipkin v2 JSON spans. Jaeger accepts them on its Zipkin
+    /// compatible endpoint. Batches are gzip compressed if compression of batching is gzip.
+    ///
+    /// :param str endpoint: URL of endpoint, e.g. http://localhost:9411/api/v2/spans. http:// is
+    ///                      allowed only for localhost, https:// uses TLS settings of agent config.
+    /// :param str service_name: Service name of local endpoint. Default is taken from agent config.
+    /// :return: the return code.
+    /// :rtype: bool
+    /// :raises ConfigurationError: if endpoint or TLS settings are invalid.
+    ///
+    #[pyfn(m, "activate_zipkin")]
+    fn activate_zipkin_py(endpoint: &str, service_name: Option<String>) -> PyResult<bool> {
+        checked(|| {
+            let config = recover(config::CONFIG.read()).clone();
+            let client = HttpClient::new(endpoint, &config.tls_config(), vec![])?;
+            let service_name = service_name.unwrap_or(config.service_name);
+            Ok(worker::activate(Box::new(ZipkinOutput::new(client, service_name))))
+        })
+    }
+
+    /// Start output worker again after shutdown. Output must be activated before.
+    ///
+    /// :return: the return code. False if worker is already running or output is not activated.
+    /// :rtype: bool
+    ///
+    #[pyfn(m, "start")]
+    fn start_py() -> PyResult<bool> {
+        Ok(worker::start())
+    }
+
+    /// Wait until all queued transactions are sent to output.
+    ///
+    /// :param float timeout: Max time in seconds to wait. Default is 5.0
+    /// :return: True if queue is drained, False on timeout.
+    /// :rtype: bool
+    ///
+    #[pyfn(m, "flush")]
+    fn flush_py(py: Python, timeout: Option<f64>) -> PyResult<bool> {
+        let timeout = worker::secs_to_duration(timeout.unwrap_or(DEFAULT_WORKER_TIMEOUT));
+        Ok(py.allow_threads(|| worker::flush(timeout)))
+    }
+
+    /// Send queued transactions to output and stop output worker. Call at interpreter exit.
+    ///
+    /// :param float timeout: Max time in seconds to wait. Default is 5.0
+    /// :return: True if worker is stopped, False on timeout.
+    /// :rtype: bool
+    ///
+    #[pyfn(m, "shutdown")]
+    fn shutdown_py(py: Python, timeout: Option<f64>) -> PyResult<bool> {
+        let timeout = worker::secs_to_duration(timeout.unwrap_or(DEFAULT_WORKER_TIMEOUT));
+        Ok(py.allow_threads(|| worker::shutdown(timeout)))
+    }
+
+    /// Configure capacity of output queue. Transactions that are waiting for output are kept in
+    /// memory, so during a long outage of PAMCollector the queue is limited and overflow is
+    /// handled by policy. Same as configure with queue_max_items, queue_max_bytes and
+    /// queue_policy, so later configure calls keep these settings.
+    ///
+    /// :param int max_items: Max count of transactions in queue. 0 means unlimited.
+    /// :param int max_bytes: Max total size of transactions in queue. 0 means unlimited.
+    /// :param str policy: One of "drop_oldest" (default), "drop_newest" or "sample".
+    /// :return: the return code. False if policy is unknown, InvalidInputError is raised in strict
+    ///          mode.
+    /// :rtype: bool
+    ///
+    #[pyfn(m, "configure_output_queue")]
+    fn configure_output_queue_py(
+        max_items: usize,
+        max_bytes: usize,
+        policy: Option<String>,
+    ) -> PyResult<bool> {
+        guard(false, || {
+            let policy = policy.unwrap_or_else(|| "drop_oldest".to_owned());
+            if OverflowPolicy::parse(&policy).is_none() {
+                return Err(invalid_input(format!("Unknown output queue policy {:?}", policy)));
+            }
+            let mut values: BTreeMap<String, String> = BTreeMap::new();
+            values.insert("queue_max_items".to_owned(), max_items.to_string());
+            values.insert("queue_max_bytes".to_owned(), max_bytes.to_string());
+            values.insert("queue_policy".to_owned(), policy);
+            config::configure(None, values)?;
+            Ok(true)
+        })
+    }
+
+    /// Configure batching of transactions sent to output. Transactions are grouped into one batch
+    /// until one of the limits is reached, and PAMCollector acknowledges the whole batch at once.
+    /// Same as configure with batch_max_items, batch_max_bytes, batch_max_delay_ms and
+    /// compression, so later configure calls keep these settings.
+    ///
+    /// :param int max_items: Max count of transactions in batch.
+    /// :param int max_bytes: Max size of batch before compression.
+    /// :param int max_delay_ms: Max time in milliseconds that batch is waiting for more transactions.
+    /// :param str compression: One of "none" (default), "gzip" or "deflate".
+    /// :return: the return code. False if compression is unknown, InvalidInputError is raised in
+    ///          strict mode.
+    /// :rtype: bool
+    ///
+    #[pyfn(m, "configure_batching")]
+    fn configure_batching_py(
+        max_items: usize,
+        max_bytes: usize,
+        max_delay_ms: u64,
+        compression: Option<String>,
+    ) -> PyResult<bool> {
+        guard(false, || {
+            let compression = compression.unwrap_or_else(|| "none".to_owned());
+            if Compression::parse(&compression).is_none() {
+                return Err(invalid_input(format!("Unknown batch compression {:?}", compression)));
+            }
+            let mut values: BTreeMap<String, String> = BTreeMap::new();
+            values.insert("batch_max_items".to_owned(), max_items.to_string());
+            values.insert("batch_max_bytes".to_owned(), max_bytes.to_string());
+            values.insert("batch_max_delay_ms".to_owned(), max_delay_ms.to_string());
+            values.insert("compression".to_owned(), compression);
+            config::configure(None, values)?;
+            Ok(true)
+        })
+    }
+
+    /// Measure push/pop throughput of transaction cache. Runs in native threads without GIL on a
+    /// private cache, so transactions, stats and metrics of the agent are untouched, see
+    /// benchmarks/bench_cache.py. Available in build with bench feature only.
+    ///
+    /// :param int threads: Count of threads, each with own transaction.
+    /// :param int iterations: Count of push/pop pairs done by every thread.
+    /// :param int shards: Count of cache shards. 1 is the single global lock. Default is the count
+    ///                    used by the agent.
+    /// :return: Dict with keys ops, elapsed (seconds) and ops_per_sec.
+    /// :rtype: dict
+    /// :raises ConfigurationError: if core is built without bench feature.
+    ///
+    #[pyfn(m, "benchmark_cache")]
+    fn benchmark_cache_py(
+        py: Python,
+        threads: usize,
+        iterations: usize,
+        shards: Option<usize>,
+    ) -> PyResult<PyObject> {
+        benchmark_cache(py, threads, iterations, shards.unwrap_or(core::CACHE_SHARDS))
+    }
+
+    /// Get output queue counters
+    ///
+    /// :return: Dict with keys size, bytes (current state of queue), enqueued and dropped
+    ///          (count of transactions since start).
+    /// :rtype: dict
+    ///
+    #[pyfn(m, "get_output_queue_stats")]
+    fn get_output_queue_stats_py(py: Python) -> PyResult<PyObject> {
+        let stats = recover(output::OUTPUT_QUEUE.lock()).stats();
+        let dict = PyDict::new(py);
+        dict.set_item("size", stats.size)?;
+        dict.set_item("bytes", stats.bytes)?;
+        dict.set_item("enqueued", stats.enqueued)?;
+        dict.set_item("dropped", stats.dropped)?;
+        Ok(dict.to_object(py))
+    }
+
+    /// Close and send transactions that were not dropped and are inactive for more than max_age
+    /// seconds, e.g. left by killed thread. Open nodes are closed at time of last activity and
+    /// transaction is marked as truncated. Reaper thread does it periodically while output worker
+    /// is running.
+    ///
+    /// :param float max_age: Seconds of inactivity. Default is transaction_max_age setting.
+    /// :return: Count of closed transactions.
+    /// :rtype: int
+    ///
+    #[pyfn(m, "reap_transactions")]
+    fn reap_transactions_py(py: Python, max_age: Option<f64>) -> PyResult<usize> {
+        let max_age = match max_age {
+            Some(v) => v,
+            None => *recover(reaper::TRANSACTION_MAX_AGE.read()),
+        };
+        Ok(py.allow_threads(|| reaper::reap(max_age)))
+    }
+
+    /// Get reaper counters
+    ///
+    /// :return: Dict with reaped (count of transactions closed by reaper since start).
+    /// :rtype: dict
+    ///
+    #[pyfn(m, "get_reaper_stats")]
+    fn get_reaper_stats_py(py: Python) -> PyResult<PyObject> {
+        let dict = PyDict::new(py);
+        dict.set_item("reaped", reaper::REAPED.load(Ordering::SeqCst))?;
+        Ok(dict.to_object(py))
+    }
+
+    /// Get head sampling counters
+    ///
+    /// :return: Dict with mode, probability (current probability of head sampling, None in
+    ///          rate_limited mode), sampled and not_sampled (counts of decisions since start),
+    ///          kept (unsampled transactions sent because they errored or were slow), tail_kept
+    ///          and tail_discarded (sampled transactions sent and not sent by keep rules).
+    /// :rtype: dict
+    ///
+    #[pyfn(m, "get_sampling_stats")]
+    fn get_sampling_stats_py(py: Python) -> PyResult<PyObject> {
+        let value = checked(|| {
+            let sampler = recover(sampling::SAMPLER.lock());
+            let stats = sampler.stats();
+            Ok(json!({
+                "mode": sampler.mode().name(),
+                "probability": sampler.probability(),
+                "sampled": stats.sampled,
+                "not_sampled": stats.not_sampled,
+                "kept": stats.kept,
+                "tail_kept": stats.tail_kept,
+                "tail_discarded": stats.tail_discarded,
+            }))
+        })?;
+        json_to_py(py, &value)
+    }
+
+    /// Get aggregates of finished database calls by query fingerprint, slowest first. Calls of a
+    /// transaction are added when it is dropped.
+    ///
+    /// :param bool reset: Clear aggregates after reading, e.g. on every harvest. Default is False.
+    /// :return: Dict with queries (list of dicts with fingerprint, normalized, database_product,
+    ///          operation, target, count, total_time, min_time, max_time) and dropped (count of
+    ///          calls of queries over the limit of distinct queries).
+    /// :rtype: dict
+    ///
+    #[pyfn(m, "get_query_stats")]
+    fn get_query_stats_py(py: Python, reset: Option<bool>) -> PyResult<PyObject> {
+        let value = checked(|| {
+            let mut stats = recover(sql::QUERY_STATS.lock());
+            let (queries, dropped) = stats.snapshot();
+            if reset.unwrap_or(false) {
+                stats.reset();
+            }
+            Ok(json!({"queries": queries, "dropped": dropped}))
+        })?;
+        json_to_py(py, &value)
+    }
+
+    /// Get timeslice metrics of current harvest interval: count, total, min, max and
+    /// sum_of_squares of durations by transaction name, external host, database operation and
+    /// target, and cache operation.
+    ///
+    /// :param bool reset: Start new interval, metrics are not harvested then. Default is False.
+    /// :return: Dict with type ('metrics'), start_time, end_time, transactions, externals,
+    ///          databases, caches (lists of dicts with name fields and stats) and dropped (count
+    ///          of calls over the limit of distinct names).
+    /// :rtype: dict
+    ///
+    #[pyfn(m, "get_metrics")]
+    fn get_metrics_py(py: Python, reset: Option<bool>) -> PyResult<PyObject> {
+        let value = checked(|| {
+            if reset.unwrap_or(false) {
+                return Ok(metrics::take());
+            }
+            Ok(recover(metrics::METRICS.lock()).payload(core::now()))
+        })?;
+        json_to_py(py, &value)
+    }
+
+    /// Queue timeslice metrics of current interval for output as payload of 'metrics' type and
+    /// start new interval. Harvest thread does it every metrics_harvest_interval seconds while
+    /// output worker is running.
+    ///
+    /// :return: False if there is nothing to send.
+    /// :rtype: bool
+    ///
+    #[pyfn(m, "harvest_metrics")]
+    fn harvest_metrics_py() -> PyResult<bool> {
+        checked(|| Ok(metrics::harvest()))
+    }
+
+    /// Configure agent. Settings are merged with settings of previous calls and take precedence
+    /// over config file and PAMAGENT_* environment variables.
+    ///
+    /// :param dict options: Settings, see pamcore/src/config.rs for names. None values are skipped.
+    /// :param str config_file: Path to TOML (.toml) or INI config file. Remembered for next calls,
+    ///                         PAMAGENT_CONFIG_FILE by default.
+    /// :return: Effective config, secrets are masked.
+    /// :rtype: dict
+    /// :raises ConfigurationError: if setting is unknown or its value is invalid. Config is left
+    ///                             untouched.
+    ///
+    #[pyfn(m, "configure")]
+    fn configure_py(
+        py: Python,
+        options: Option<&PyDict>,
+        config_file: Option<String>,
+    ) -> PyResult<PyObject> {
+        let mut values: BTreeMap<String, String> = BTreeMap::new();
+        if let Some(options) = options {
+            for (key, value) in options.items_vec() {
+                let key: String = key.extract(py)?;
+                if let Some(v) = py_to_setting(py, &key, &value)? {
+                    values.insert(key, v);
+                }
+            }
+        }
+        let value = checked(|| {
+            let config = config::configure(config_file, values)?;
+            serde_json::to_value(config.masked()).map_err(|e| PamError::Internal(e.to_string()))
+        })?;
+        json_to_py(py, &value)
+    }
+
+    /// Forget settings and config file passed to configure, and reload config from
+    /// PAMAGENT_CONFIG_FILE and PAMAGENT_* environment variables.
+    ///
+    /// :return: Effective config, secrets are masked.
+    /// :rtype: dict
+    /// :raises ConfigurationError: if config file or environment variable is invalid. Config is
+    ///                             left untouched.
+    ///
+    #[pyfn(m, "reset_config")]
+    fn reset_config_py(py: Python) -> PyResult<PyObject> {
+        let value = checked(|| {
+            let config = config::reset()?;
+            serde_json::to_value(config.masked()).map_err(|e| PamError::Internal(e.to_string()))
+        })?;
+        json_to_py(py, &value)
+    }
+
+    /// Get effective agent config
+    ///
+    /// :param bool include_secrets: Return token and passwords as is. Default is False.
+    /// :return: Dict with all settings.
+    /// :rtype: dict
+    ///
+    #[pyfn(m, "get_config")]
+    fn get_config_py(py: Python, include_secrets: Option<bool>) -> PyResult<PyObject> {
+        let value = checked(|| {
+            let config = recover(config::CONFIG.read()).clone();
+            let config = match include_secrets.unwrap_or(false) {
+                true => config,
+                false => config.masked(),
+            };
+            serde_json::to_value(config).map_err(|e| PamError::Internal(e.to_string()))
+        })?;
+        json_to_py(py, &value)
     }
     Ok(())
 }
@@ -1,26 +1,10 @@
 use std::io;
-use std::env;
 
 use chrono;
 use fern;
 use log;
 
-const DEBUG_LEVEL_KEY: &str = "PAMAGENT_LEVEL_LOG";
-
-fn setup_logging(verbosity: u8) -> Result<(), fern::InitError> {
-    let mut base_config = fern::Dispatch::new();
-
-    base_config = match verbosity {
-        0 => base_config
-            .level(log::LevelFilter::Warn)
-            .level_for("overly-verbose-target", log::LevelFilter::Error),
-        1 => base_config
-            .level(log::LevelFilter::Info)
-            .level_for("overly-verbose-target", log::LevelFilter::Debug),
-        2 => base_config.level(log::LevelFilter::Debug),
-        _3_or_more => base_config.level(log::LevelFilter::Trace),
-    };
-
+fn setup_logging() -> Result<(), fern::InitError> {
     let stdout_config = fern::Dispatch::new()
         .format(|out, message, record| {
             out.finish(format_args!(
@@ -34,18 +18,32 @@ fn setup_logging(verbosity: u8) -> Result<(), fern::InitError> {
         })
         .chain(io::stdout());
 
-    base_config.chain(stdout_config).apply()?;
+    fern::Dispatch::new()
+        .level(log::LevelFilter::Trace)
+        .chain(stdout_config)
+        .apply()?;
 
     Ok(())
 }
 
+/// Change logging level at runtime. Verbosity is a value from 0 to 3, see `log_level` setting
+/// in pamcore/src/config.rs.
+pub fn set_verbosity(verbosity: u8) {
+    let level = match verbosity {
+        0 => log::LevelFilter::Warn,
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _3_or_more => log::LevelFilter::Trace,
+    };
+    log::set_max_level(level);
+}
+
 pub fn configure_logging() {
-    let debug_level: u8 = env::var(DEBUG_LEVEL_KEY)
-        .unwrap_or("0".to_string())
-        .parse()
-        .unwrap_or(0);
-    match setup_logging(debug_level) {
-        Ok(_) => info!(target:"overly-verbose-target", "Logger successfully configured."),
+    match setup_logging() {
+        Ok(_) => {
+            set_verbosity(0);
+            info!(target:"overly-verbose-target", "Logger successfully configured.")
+        }
         Err(e) => error!("Unable to configure logging. Error: {}", e),
     };
 }
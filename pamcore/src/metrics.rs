@@ -0,0 +1,314 @@
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::thread;
+
+use serde_json;
+
+use core;
+use error::recover;
+use output;
+use worker::secs_to_duration;
+
+pub const DEFAULT_METRICS_HARVEST_INTERVAL: f64 = 60.0;
+/// Pause of harvest thread while harvesting is disabled, in seconds.
+const DISABLED_POLL_INTERVAL: f64 = 1.0;
+/// Max count of distinct names per kind of metric, calls of other names are only counted.
+const MAX_METRIC_NAMES: usize = 2000;
+
+lazy_static! {
+    /// Seconds between harvests of timeslice metrics, 0 disables harvest thread.
+    pub static ref METRICS_HARVEST_INTERVAL: RwLock<f64> =
+        RwLock::new(DEFAULT_METRICS_HARVEST_INTERVAL);
+    pub static ref METRICS: Mutex<MetricTable> = { Mutex::new(MetricTable::new(core::now())) };
+    static ref STARTED: AtomicBool = AtomicBool::new(false);
+}
+
+/// Calls of one metric in harvest interval. Sum of squares gives standard deviation.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct TimeStats {
+    count: u64,
+    total: f64,
+    min: f64,
+    max: f64,
+    sum_of_squares: f64,
+}
+
+impl TimeStats {
+    fn new(duration: f64) -> TimeStats {
+        TimeStats {
+            count: 1,
+            total: duration,
+            min: duration,
+            max: duration,
+            sum_of_squares: duration * duration,
+        }
+    }
+
+    fn record(&mut self, duration: f64) {
+        self.count += 1;
+        self.total += duration;
+        self.min = self.min.min(duration);
+        self.max = self.max.max(duration);
+        self.sum_of_squares += duration * duration;
+    }
+
+    fn merge(&mut self, other: &TimeStats) {
+        self.count += other.count;
+        self.total += other.total;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum_of_squares += other.sum_of_squares;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+struct TransactionKey {
+    name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+struct ExternalKey {
+    host: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+struct DatabaseKey {
+    database_product: String,
+    operation: String,
+    target: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+struct CacheKey {
+    database_product: String,
+    operation: String,
+}
+
+/// External, database or cache call of unsampled transaction, timed for metrics only.
+#[derive(Debug, Clone)]
+pub enum Call {
+    External {
+        host: String,
+    },
+    Database {
+        database_product: String,
+        operation: String,
+        target: String,
+    },
+    Cache {
+        database_product: String,
+        operation: String,
+    },
+}
+
+/// Metric of payload, its name fields followed by its stats.
+#[derive(Serialize)]
+struct Row<'a, K: 'a> {
+    #[serde(flatten)]
+    key: &'a K,
+    #[serde(flatten)]
+    stats: &'a TimeStats,
+}
+
+fn rows<K>(metrics: &BTreeMap<K, TimeStats>) -> Vec<Row<K>> {
+    metrics.iter().map(|(key, stats)| Row { key, stats }).collect()
+}
+
+/// Payload sent to output apart from transactions, told apart by `type`.
+#[derive(Serialize)]
+struct Payload<'a> {
+    #[serde(rename = "type")]
+    payload_type: &'static str,
+    start_time: f64,
+    end_time: f64,
+    transactions: Vec<Row<'a, TransactionKey>>,
+    externals: Vec<Row<'a, ExternalKey>>,
+    databases: Vec<Row<'a, DatabaseKey>>,
+    caches: Vec<Row<'a, CacheKey>>,
+    dropped: u64,
+}
+
+/// Timeslice metrics of one harvest interval: duration of transactions by name and of external,
+/// database and cache calls by what they call. Unsampled transactions and their calls are counted
+/// too, though nodes of the calls are not recorded.
+#[derive(Debug)]
+pub struct MetricTable {
+    start_time: f64,
+    transactions: BTreeMap<TransactionKey, TimeStats>,
+    externals: BTreeMap<ExternalKey, TimeStats>,
+    databases: BTreeMap<DatabaseKey, TimeStats>,
+    caches: BTreeMap<CacheKey, TimeStats>,
+    /// Calls not aggregated, as `MAX_METRIC_NAMES` names of their kind were already seen.
+    dropped: u64,
+}
+
+fn record<K: Ord>(metrics: &mut BTreeMap<K, TimeStats>, dropped: &mut u64, key: K, duration: f64) {
+    if let Some(stats) = metrics.get_mut(&key) {
+        stats.record(duration);
+        return;
+    }
+    if metrics.len() >= MAX_METRIC_NAMES {
+        *dropped += 1;
+        return;
+    }
+    metrics.insert(key, TimeStats::new(duration));
+}
+
+fn merge<K: Ord>(
+    metrics: &mut BTreeMap<K, TimeStats>,
+    dropped: &mut u64,
+    other: BTreeMap<K, TimeStats>,
+) {
+    for (key, stats) in other {
+        if let Some(v) = metrics.get_mut(&key) {
+            v.merge(&stats);
+            continue;
+        }
+        if metrics.len() >= MAX_METRIC_NAMES {
+            *dropped += stats.count;
+            continue;
+        }
+        metrics.insert(key, stats);
+    }
+}
+
+impl MetricTable {
+    pub fn new(start_time: f64) -> MetricTable {
+        MetricTable {
+            start_time,
+            transactions: BTreeMap::new(),
+            externals: BTreeMap::new(),
+            databases: BTreeMap::new(),
+            caches: BTreeMap::new(),
+            dropped: 0,
+        }
+    }
+
+    pub fn record_transaction(&mut self, name: &str, duration: f64) {
+        let key = TransactionKey {
+            name: name.to_owned(),
+        };
+        record(&mut self.transactions, &mut self.dropped, key, duration);
+    }
+
+    pub fn record_external(&mut self, host: &str, duration: f64) {
+        let key = ExternalKey {
+            host: host.to_owned(),
+        };
+        record(&mut self.externals, &mut self.dropped, key, duration);
+    }
+
+    pub fn record_database(&mut self, product: &str, operation: &str, target: &str, duration: f64) {
+        let key = DatabaseKey {
+            database_product: product.to_owned(),
+            operation: operation.to_owned(),
+            target: target.to_owned(),
+        };
+        record(&mut self.databases, &mut self.dropped, key, duration);
+    }
+
+    pub fn record_cache(&mut self, product: &str, operation: &str, duration: f64) {
+        let key = CacheKey {
+            database_product: product.to_owned(),
+            operation: operation.to_owned(),
+        };
+        record(&mut self.caches, &mut self.dropped, key, duration);
+    }
+
+    pub fn record_call(&mut self, call: &Call, duration: f64) {
+        match *call {
+            Call::External { ref host } => self.record_external(host, duration),
+            Call::Database {
+                ref database_product,
+                ref operation,
+                ref target,
+            } => self.record_database(database_product, operation, target, duration),
+            Call::Cache {
+                ref database_product,
+                ref operation,
+            } => self.record_cache(database_product, operation, duration),
+        }
+    }
+
+    /// Add metrics of `other`, e.g. of one transaction, keeping start time of this table.
+    pub fn merge(&mut self, other: MetricTable) {
+        self.dropped += other.dropped;
+        merge(&mut self.transactions, &mut self.dropped, other.transactions);
+        merge(&mut self.externals, &mut self.dropped, other.externals);
+        merge(&mut self.databases, &mut self.dropped, other.databases);
+        merge(&mut self.caches, &mut self.dropped, other.caches);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+            && self.externals.is_empty()
+            && self.databases.is_empty()
+            && self.caches.is_empty()
+            && self.dropped == 0
+    }
+
+    /// Metrics payload of interval that ends at `end_time`.
+    pub fn payload(&self, end_time: f64) -> serde_json::Value {
+        let payload = Payload {
+            payload_type: "metrics",
+            start_time: self.start_time,
+            end_time,
+            transactions: rows(&self.transactions),
+            externals: rows(&self.externals),
+            databases: rows(&self.databases),
+            caches: rows(&self.caches),
+            dropped: self.dropped,
+        };
+        serde_json::to_value(&payload).unwrap_or(serde_json::Value::Null)
+    }
+}
+
+/// Start harvest thread, once per process. It runs until the process exits.
+pub fn start() {
+    if STARTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let spawned = thread::Builder::new()
+        .name("pamagent-harvest".to_owned())
+        .spawn(run);
+    if let Err(e) = spawned {
+        STARTED.store(false, Ordering::SeqCst);
+        error!("Unable to start metrics harvest: {}", e);
+    }
+}
+
+fn run() {
+    loop {
+        let interval = *recover(METRICS_HARVEST_INTERVAL.read());
+        if interval <= 0.0 {
+            thread::sleep(secs_to_duration(DISABLED_POLL_INTERVAL));
+            continue;
+        }
+        thread::sleep(secs_to_duration(interval));
+        harvest();
+    }
+}
+
+/// Take metrics of the interval that ends now, the next interval starts empty.
+pub fn take() -> serde_json::Value {
+    let now = core::now();
+    let table = mem::replace(&mut *recover(METRICS.lock()), MetricTable::new(now));
+    table.payload(now)
+}
+
+/// Queue metrics of the interval that ends now as payload of `metrics` type. Nothing is queued
+/// for interval without calls.
+pub fn harvest() -> bool {
+    if recover(METRICS.lock()).is_empty() {
+        return false;
+    }
+    match serde_json::to_string(&take()) {
+        Ok(payload) => output::enqueue(payload),
+        Err(e) => {
+            error!("Unable to serialize metrics. Error: {}", e);
+            false
+        }
+    }
+}
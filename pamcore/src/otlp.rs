@@ -0,0 +1,272 @@
+use std::io;
+
+use serde_json::{self, Map, Value};
+
+use error::recover;
+use export::{self, db_system, port_field, str_field, walk_nodes};
+use http::{check_status, HttpClient};
+use output::{Output, BATCH_CONFIG};
+
+const SCOPE_NAME: &str = "pamagent";
+const SCOPE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Span kinds of OTLP.
+const SPAN_KIND_INTERNAL: u8 = 1;
+const SPAN_KIND_SERVER: u8 = 2;
+const SPAN_KIND_CLIENT: u8 = 3;
+const STATUS_CODE_ERROR: u8 = 2;
+
+/// Export transactions as OpenTelemetry spans to OTLP/HTTP endpoint with JSON encoding, e.g.
+/// `http://localhost:4318/v1/traces` of OpenTelemetry Collector.
+pub struct OtlpOutput {
+    client: HttpClient,
+    service_name: String,
+}
+
+impl OtlpOutput {
+    pub fn new(client: HttpClient, service_name: String) -> OtlpOutput {
+        OtlpOutput {
+            client,
+            service_name,
+        }
+    }
+}
+
+impl Output for OtlpOutput {
+    fn name(&self) -> &str {
+        "OtlpOutput"
+    }
+
+    fn open(&mut self) -> Result<(), io::Error> {
+        self.client.connect()
+    }
+
+    fn send_batch(&mut self, batch: &mut Vec<String>) -> Result<(), io::Error> {
+        let mut spans: Vec<Value> = vec![];
+        for payload in batch.iter() {
+            match serde_json::from_str::<Value>(payload) {
+                Ok(ref v) if export::is_metrics(v) => {}
+                Ok(tr) => transaction_to_spans(&tr, &mut spans),
+                Err(e) => error!("Unable to parse transaction for {}: {}", self.name(), e),
+            }
+        }
+        if spans.is_empty() {
+            return Ok(());
+        }
+        let request = export_request(&self.service_name, spans);
+        let body = serde_json::to_vec(&request)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        let compression = recover(BATCH_CONFIG.read()).compression;
+        let body = compression.compress(body)?;
+        let encoding = compression.content_encoding();
+        let status = self.client.post("application/json", encoding, &body)?;
+        check_status(self.client.url(), status)
+    }
+}
+
+fn export_request(service_name: &str, spans: Vec<Value>) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [key_value("service.name", &json!(service_name))],
+            },
+            "scopeSpans": [{
+                "scope": {"name": SCOPE_NAME, "version": SCOPE_VERSION},
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+/// OTLP `AnyValue` of JSON scalar. Integers are strings in JSON encoding of OTLP.
+fn any_value(value: &Value) -> Value {
+    match *value {
+        Value::Bool(v) => json!({ "boolValue": v }),
+        Value::Number(ref v) => match v.as_i64() {
+            Some(i) => json!({ "intValue": i.to_string() }),
+            None => json!({ "doubleValue": v.as_f64().unwrap_or(0.0) }),
+        },
+        Value::String(ref v) => json!({ "stringValue": v }),
+        ref v => json!({ "stringValue": v.to_string() }),
+    }
+}
+
+fn key_value(key: &str, value: &Value) -> Value {
+    json!({"key": key, "value": any_value(value)})
+}
+
+fn time_nanos(node: &Value, key: &str) -> String {
+    let secs = node.get(key).and_then(|v| v.as_f64()).unwrap_or(0.0);
+    format!("{}", (secs.max(0.0) * 1e9) as u64)
+}
+
+/// Attributes of semantic conventions for node and its custom attributes.
+struct SpanAttributes(Vec<Value>);
+
+impl SpanAttributes {
+    /// Skip empty strings and nulls, they mean unknown.
+    fn add(&mut self, key: &str, value: Value) {
+        let empty = match value {
+            Value::String(ref v) => v.is_empty(),
+            Value::Null => true,
+            _ => false,
+        };
+        if !empty {
+            self.0.push(key_value(key, &value));
+        }
+    }
+
+    fn add_custom(&mut self, node: &Value) {
+        if let Some(attributes) = node.get("attributes").and_then(|v| v.as_object()) {
+            for (key, value) in attributes {
+                self.0.push(key_value(key, value));
+            }
+        }
+    }
+}
+
+/// Name, kind and attributes of span for trace node.
+fn describe_node(node: &Value) -> (String, u8, SpanAttributes) {
+    let mut attrs = SpanAttributes(vec![]);
+    let host = json!(str_field(node, "host"));
+    let port = port_field(node).map_or(Value::Null, |v| json!(v));
+    match str_field(node, "type") {
+        "External" => {
+            let method = str_field(node, "method").to_uppercase();
+            attrs.add("http.method", json!(method));
+            attrs.add("http.target", json!(str_field(node, "path")));
+            attrs.add("net.peer.name", host);
+            attrs.add("net.peer.port", port);
+            attrs.add("pamagent.library", json!(str_field(node, "library")));
+            let name = format!("{} {}", method, str_field(node, "host"));
+            (name.trim().to_owned(), SPAN_KIND_CLIENT, attrs)
+        }
+        "Database" => {
+            let operation = str_field(node, "operation");
+            let target = str_field(node, "target");
+            attrs.add("db.system", json!(db_system(str_field(node, "database_product"))));
+            attrs.add("db.name", json!(str_field(node, "database_name")));
+            attrs.add("db.operation", json!(operation));
+            attrs.add("db.sql.table", json!(target));
+            attrs.add("db.statement", json!(str_field(node, "sql")));
+            attrs.add("net.peer.name", host);
+            attrs.add("net.peer.port", port);
+            let name = match (operation, target) {
+                ("", _) => str_field(node, "database_product").to_owned(),
+                (op, "") => op.to_owned(),
+                (op, t) => format!("{} {}", op, t),
+            };
+            (name, SPAN_KIND_CLIENT, attrs)
+        }
+        "Cache" => {
+            let operation = str_field(node, "operation");
+            let system = db_system(str_field(node, "database_product"));
+            attrs.add("db.system", json!(system));
+            attrs.add("db.operation", json!(operation));
+            if system == "redis" {
+                if let Ok(index) = str_field(node, "database_name").parse::<u64>() {
+                    attrs.add("db.redis.database_index", json!(index));
+                }
+            } else {
+                attrs.add("db.name", json!(str_field(node, "database_name")));
+            }
+            attrs.add("net.peer.name", host);
+            attrs.add("net.peer.port", port);
+            (format!("{} {}", system, operation).trim().to_owned(), SPAN_KIND_CLIENT, attrs)
+        }
+        _ => {
+            let func_name = str_field(node, "func_name");
+            attrs.add("code.function", json!(func_name));
+            (func_name.to_owned(), SPAN_KIND_INTERNAL, attrs)
+        }
+    }
+}
+
+/// Error of node or transaction as span status and `exception` event.
+fn add_error(span: &mut Map<String, Value>, error: &Value, time: &str) {
+    let class = str_field(error, "class");
+    let message = str_field(error, "message");
+    span.insert(
+        "status".to_owned(),
+        json!({"code": STATUS_CODE_ERROR, "message": message}),
+    );
+    let mut attributes = vec![
+        key_value("exception.type", &json!(class)),
+        key_value("exception.message", &json!(message)),
+    ];
+    if let Some(frames) = error.get("frames").and_then(|v| v.as_array()) {
+        let frames: Vec<&str> = frames.iter().filter_map(|v| v.as_str()).collect();
+        attributes.push(key_value("exception.stacktrace", &json!(frames.join("\n"))));
+    }
+    span.insert(
+        "events".to_owned(),
+        json!([{"name": "exception", "timeUnixNano": time, "attributes": attributes}]),
+    );
+}
+
+fn node_to_span(trace_id: &str, parent_span_id: Option<&str>, node: &Value) -> Option<Value> {
+    let span_id = str_field(node, "span_id");
+    if span_id.is_empty() {
+        return None;
+    }
+    let (name, kind, mut attrs) = describe_node(node);
+    attrs.add_custom(node);
+    let end_time = time_nanos(node, "end_time");
+    let mut span = Map::new();
+    span.insert("traceId".to_owned(), json!(trace_id));
+    span.insert("spanId".to_owned(), json!(span_id));
+    if let Some(v) = parent_span_id {
+        span.insert("parentSpanId".to_owned(), json!(v));
+    }
+    span.insert("name".to_owned(), json!(name));
+    span.insert("kind".to_owned(), json!(kind));
+    span.insert("startTimeUnixNano".to_owned(), json!(time_nanos(node, "start_time")));
+    span.insert("endTimeUnixNano".to_owned(), json!(end_time));
+    span.insert("attributes".to_owned(), Value::Array(attrs.0));
+    if let Some(error) = node.get("error") {
+        add_error(&mut span, error, &end_time);
+    }
+    Some(Value::Object(span))
+}
+
+/// Convert dump of finished transaction into OTLP spans. Root node becomes the server span with
+/// transaction name, path, custom attributes and error.
+pub fn transaction_to_spans(tr: &Value, spans: &mut Vec<Value>) {
+    let trace_id = export::trace_id(tr);
+    if trace_id.is_empty() {
+        warn!("Transaction without trace id is not exported");
+        return;
+    }
+    let first = spans.len();
+    walk_nodes(tr, |node, parent| {
+        if let Some(span) = node_to_span(trace_id, parent, node) {
+            spans.push(span);
+        }
+    });
+    if let Some(&mut Value::Object(ref mut root)) = spans.get_mut(first) {
+        let base_name = str_field(tr, "base_name");
+        let path = str_field(tr, "path");
+        root.insert("kind".to_owned(), json!(SPAN_KIND_SERVER));
+        if !base_name.is_empty() {
+            root.insert("name".to_owned(), json!(base_name));
+        }
+        let mut attrs = SpanAttributes(vec![]);
+        attrs.add("http.target", json!(path));
+        attrs.add("pamagent.guid", json!(str_field(tr, "guid")));
+        attrs.add_custom(tr);
+        if let Some(&mut Value::Array(ref mut v)) = root.get_mut("attributes") {
+            v.extend(attrs.0);
+        }
+        if let Some(error) = tr.get("error") {
+            let end_time = root
+                .get("endTimeUnixNano")
+                .and_then(|v| v.as_str())
+                .unwrap_or("0")
+                .to_owned();
+            add_error(root, error, &end_time);
+        } else if tr.get("errored").and_then(|v| v.as_bool()) == Some(true) {
+            root.entry("status".to_owned())
+                .or_insert_with(|| json!({ "code": STATUS_CODE_ERROR }));
+        }
+    }
+}
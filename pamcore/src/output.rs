@@ -1,162 +1,546 @@
 use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
 use std::io::Error;
-use std::net::TcpStream;
-use std::sync::{Arc, Mutex};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
 use std::time::Duration;
-use std::thread;
 
-use std::cell::RefCell;
-use std::rc::Rc;
-use backoff::{ExponentialBackoff, Operation};
+use rand;
 use std::fmt::Display;
 use std::io;
-use backoff;
-use native_tls::{TlsConnector, TlsStream};
+use flate2;
+use flate2::write::{DeflateEncoder, GzEncoder};
+use tls::{self, Transport};
 use std::io::{Read, Write};
+use error::recover;
+
+pub const DEFAULT_QUEUE_MAX_ITEMS: usize = 10_000;
+pub const DEFAULT_QUEUE_MAX_BYTES: usize = 64 * 1024 * 1024;
+const CONNECT_TIMEOUT: u64 = 10;
+pub const DEFAULT_SOCKET_TIMEOUT: f64 = 30.0;
 
 lazy_static! {
-    pub static ref OUTPUT_QUEUE: Arc<Mutex<VecDeque<String>>> = {
-        let vector: VecDeque<String> = VecDeque::new();
-        Arc::new(Mutex::new(vector))
+    /// Signalled on enqueue, on finished batch and on worker shutdown.
+    /// Always wait on it with `OUTPUT_QUEUE` lock.
+    pub static ref OUTPUT_SIGNAL: Condvar = Condvar::new();
+    pub static ref OUTPUT_QUEUE: Arc<Mutex<OutputQueue>> = {
+        let queue: OutputQueue = OutputQueue::new(
+            DEFAULT_QUEUE_MAX_ITEMS,
+            DEFAULT_QUEUE_MAX_BYTES,
+            OverflowPolicy::DropOldest,
+        );
+        Arc::new(Mutex::new(queue))
     };
 }
 
+/// Enqueue serialized transaction and wake up output worker.
+pub fn enqueue(payload: String) -> bool {
+    let res = recover(OUTPUT_QUEUE.lock()).push(payload);
+    OUTPUT_SIGNAL.notify_all();
+    res
+}
+
+/// What to do with a transaction when `OUTPUT_QUEUE` is full.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverflowPolicy {
+    /// Evict the oldest queued transactions to make room for the new one.
+    DropOldest,
+    /// Keep the queue as is and discard the new transaction.
+    DropNewest,
+    /// Replace a randomly chosen queued transaction, so the queue keeps a spread of the whole
+    /// outage instead of only its beginning or its end.
+    Sample,
+}
+
+impl OverflowPolicy {
+    pub fn parse(val: &str) -> Option<OverflowPolicy> {
+        match val {
+            "drop_oldest" => Some(OverflowPolicy::DropOldest),
+            "drop_newest" => Some(OverflowPolicy::DropNewest),
+            "sample" => Some(OverflowPolicy::Sample),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QueueStats {
+    pub size: usize,
+    pub bytes: usize,
+    pub enqueued: u64,
+    pub dropped: u64,
+}
+
+/// Queue of serialized transactions waiting for output.
+///
+/// Capacity is limited both by item count and by total payload size, `0` means no limit.
+pub struct OutputQueue {
+    items: VecDeque<String>,
+    bytes: usize,
+    max_items: usize,
+    max_bytes: usize,
+    policy: OverflowPolicy,
+    enqueued: u64,
+    dropped: u64,
+    in_flight: usize,
+}
+
+impl OutputQueue {
+    pub fn new(max_items: usize, max_bytes: usize, policy: OverflowPolicy) -> OutputQueue {
+        OutputQueue {
+            items: VecDeque::new(),
+            bytes: 0,
+            max_items,
+            max_bytes,
+            policy,
+            enqueued: 0,
+            dropped: 0,
+            in_flight: 0,
+        }
+    }
+
+    pub fn configure(&mut self, max_items: usize, max_bytes: usize, policy: OverflowPolicy) {
+        self.max_items = max_items;
+        self.max_bytes = max_bytes;
+        self.policy = policy;
+        while self.is_over(0, 0) {
+            self.evict_front();
+        }
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            size: self.items.len(),
+            bytes: self.bytes,
+            enqueued: self.enqueued,
+            dropped: self.dropped,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// True if queue is empty and no batch taken from it is being sent.
+    pub fn is_drained(&self) -> bool {
+        self.items.is_empty() && self.in_flight == 0
+    }
+
+    pub fn begin_batch(&mut self, size: usize) {
+        self.in_flight = size;
+    }
+
+    pub fn end_batch(&mut self) {
+        self.in_flight = 0;
+    }
+
+    fn is_over(&self, extra_items: usize, extra_bytes: usize) -> bool {
+        (self.max_items > 0 && self.items.len() + extra_items > self.max_items)
+            || (self.max_bytes > 0 && self.bytes + extra_bytes > self.max_bytes)
+    }
+
+    fn evict_front(&mut self) {
+        if let Some(v) = self.items.pop_front() {
+            self.bytes -= v.len();
+            self.dropped += 1;
+        }
+    }
+
+    /// Enqueue transaction. Return false if the transaction itself was dropped.
+    pub fn push(&mut self, item: String) -> bool {
+        let size = item.len();
+        if self.max_bytes > 0 && size > self.max_bytes {
+            warn!("Transaction of {} bytes exceeds output queue capacity. Dropped", size);
+            self.dropped += 1;
+            return false;
+        }
+        if self.is_over(1, size) {
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    while self.is_over(1, size) && !self.items.is_empty() {
+                        self.evict_front();
+                    }
+                }
+                OverflowPolicy::DropNewest => {
+                    debug!("Output queue is full. Drop newest transaction");
+                    self.dropped += 1;
+                    return false;
+                }
+                OverflowPolicy::Sample => {
+                    let index = rand::random::<usize>() % self.items.len().max(1);
+                    if let Some(v) = self.items.remove(index) {
+                        self.bytes -= v.len();
+                        self.dropped += 1;
+                    }
+                    while self.is_over(1, size) && !self.items.is_empty() {
+                        self.evict_front();
+                    }
+                }
+            }
+        }
+        self.bytes += size;
+        self.enqueued += 1;
+        self.items.push_back(item);
+        true
+    }
+
+    pub fn front(&self) -> Option<&String> {
+        self.items.front()
+    }
+
+    pub fn pop_front(&mut self) -> Option<String> {
+        let val = self.items.pop_front();
+        if let Some(ref v) = val {
+            self.bytes -= v.len();
+        }
+        val
+    }
+
+    /// Return transaction that failed to be sent back to the head of the queue.
+    /// It is dropped if the queue was filled up in the meantime.
+    pub fn push_front(&mut self, item: String) {
+        if self.is_over(1, item.len()) {
+            self.dropped += 1;
+            return;
+        }
+        self.bytes += item.len();
+        self.items.push_front(item);
+    }
+}
+
+/// Compression of batch body sent to PAMCollector.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Deflate,
+}
+
+impl Compression {
+    pub fn parse(val: &str) -> Option<Compression> {
+        match val {
+            "none" | "" => Some(Compression::None),
+            "gzip" => Some(Compression::Gzip),
+            "deflate" => Some(Compression::Deflate),
+            _ => None,
+        }
+    }
+
+    fn code(&self) -> u8 {
+        match *self {
+            Compression::None => 0,
+            Compression::Gzip => 1,
+            Compression::Deflate => 2,
+        }
+    }
+
+    /// Value of `Content-Encoding` header of HTTP exporters.
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        match *self {
+            Compression::None => None,
+            Compression::Gzip => Some("gzip"),
+            Compression::Deflate => Some("deflate"),
+        }
+    }
+
+    pub fn compress(&self, body: Vec<u8>) -> Result<Vec<u8>, io::Error> {
+        match *self {
+            Compression::None => Ok(body),
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&body)?;
+                encoder.finish()
+            }
+            Compression::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Limits for grouping queued transactions into one batch. A batch is sent as soon as it has
+/// `max_items` transactions, would exceed `max_bytes`, or its first transaction waited `max_delay`.
+#[derive(Clone, Copy, Debug)]
+pub struct BatchConfig {
+    pub max_items: usize,
+    pub max_bytes: usize,
+    pub max_delay: Duration,
+    pub compression: Compression,
+}
+
+impl Default for BatchConfig {
+    fn default() -> BatchConfig {
+        BatchConfig {
+            max_items: 100,
+            max_bytes: 1024 * 1024,
+            max_delay: Duration::from_millis(1000),
+            compression: Compression::None,
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref BATCH_CONFIG: RwLock<BatchConfig> = { RwLock::new(BatchConfig::default()) };
+    /// Read and write timeout of sockets opened by `get_connection`.
+    pub static ref SOCKET_TIMEOUT: RwLock<Duration> =
+        RwLock::new(Duration::from_secs(DEFAULT_SOCKET_TIMEOUT as u64));
+}
+
+/// Sink for transactions drained from `OUTPUT_QUEUE`.
+///
+/// How the payload reaches its destination (TLS socket, local file, ...) is up to the
+/// implementation. The output worker only asks a sink to `open` itself and to `send_batch`
+/// payloads, and reopens it whenever a send fails. Payloads left in the batch of a failed send
+/// are queued again, so a sink that writes them one by one removes the written ones.
+pub trait Output {
+    fn name(&self) -> &str;
+    fn open(&mut self) -> Result<(), io::Error>;
+    fn send_batch(&mut self, batch: &mut Vec<String>) -> Result<(), io::Error>;
+}
+
+fn new_io_err<E: Display>(err: E) -> io::Error {
+    warn!("{}", err.to_string());
+    io::Error::new(io::ErrorKind::Other, err.to_string())
+}
+
+/// Frame batch for PAMCollector.
+///
+/// Frame is a 12 bytes header followed by body:
+///
+/// | bytes | value                                              |
+/// |-------|----------------------------------------------------|
+/// | 0..2  | magic `PB`                                         |
+/// | 2     | frame version, `1`                                 |
+/// | 3     | compression: `0` none, `1` gzip, `2` deflate       |
+/// | 4..8  | count of transactions, u32 big endian              |
+/// | 8..12 | length of body, u32 big endian                     |
+///
+/// Body is newline-delimited JSON transactions compressed as a whole.
+pub fn encode_batch(batch: &[String], compression: Compression) -> Result<Vec<u8>, io::Error> {
+    let mut body: Vec<u8> = Vec::with_capacity(batch.iter().map(|v| v.len() + 1).sum());
+    for v in batch {
+        body.extend_from_slice(v.as_bytes());
+        body.push(b'\n');
+    }
+    let body = compression.compress(body)?;
+    let mut frame: Vec<u8> = Vec::with_capacity(body.len() + 12);
+    frame.extend_from_slice(b"PB");
+    frame.push(1);
+    frame.push(compression.code());
+    frame.extend_from_slice(&u32_be(batch.len() as u32));
+    frame.extend_from_slice(&u32_be(body.len() as u32));
+    frame.extend_from_slice(&body);
+    Ok(frame)
+}
 
-fn get_connection(addr: &str) -> Result<TlsStream<TcpStream>, Error> {
+fn u32_be(val: u32) -> [u8; 4] {
+    [(val >> 24) as u8, (val >> 16) as u8, (val >> 8) as u8, val as u8]
+}
+
+pub trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+pub fn get_connection(addr: &str, transport: &Transport) -> Result<Box<Stream>, Error> {
     trace!("Try to connect to remote server.");
-    let connector = TlsConnector::builder().unwrap().build().unwrap();
-    trace!("TLSConnector is builded");
-    let stream = TcpStream::connect(addr).map_err(new_io_err)?;
+    let socket_addr = match addr.to_socket_addrs()?.next() {
+        Some(v) => v,
+        None => return Err(new_io_err(format!("Unable to resolve {}", addr))),
+    };
+    let stream = TcpStream::connect_timeout(&socket_addr, Duration::from_secs(CONNECT_TIMEOUT))
+        .map_err(new_io_err)?;
+    // Server that accepts but never answers must not block worker forever
+    let timeout = *recover(SOCKET_TIMEOUT.read());
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
     info!("TCPStream is connect");
-    let stream = connector.connect("pushamp.com", stream).map_err(new_io_err);
-    trace!("Return TLS STREAM");
-    stream
+    match *transport {
+        Transport::Plaintext => {
+            warn!("Connection to {} is not encrypted", addr);
+            Ok(Box::new(stream))
+        }
+        Transport::Tls {
+            ref connector,
+            ref hostname,
+        } => {
+            let stream = tls::handshake(connector, hostname, stream)?;
+            trace!("Return TLS STREAM");
+            Ok(Box::new(stream))
+        }
+    }
 }
-#[derive(Clone)]
+
 pub struct PamCollectorOutput {
     addr: String,
     token: String,
-}
-
-pub trait Output {
-    fn start(&self);
-    fn recreate_stream(&self) -> Result<TlsStream<TcpStream>, backoff::Error<io::Error>>;
-    fn consume_events(&self, shared_stream: Rc<RefCell<TlsStream<TcpStream>>>);
+    transport: Transport,
+    stream: Option<Box<Stream>>,
 }
 
 impl PamCollectorOutput {
-    pub fn new(mut token: String, addr: String) -> PamCollectorOutput {
+    pub fn new(mut token: String, addr: String, transport: Transport) -> PamCollectorOutput {
         token.push_str("\r\n");
-        PamCollectorOutput { addr, token }
+        PamCollectorOutput {
+            addr,
+            token,
+            transport,
+            stream: None,
+        }
     }
-}
 
-fn new_io_err<E: Display>(err: E) -> io::Error {
-    warn!("{}", err.to_string());
-    io::Error::new(io::ErrorKind::Other, err.to_string())
+    fn recreate_stream(&self) -> Result<Box<Stream>, io::Error> {
+        let mut stream = get_connection(&self.addr, &self.transport).map_err(new_io_err)?;
+        info!("Prepare write token");
+        let status_w: Result<usize, Error> = stream.write(self.token.as_bytes());
+        trace!("Write token payload to server. Write bytes: {:?}", status_w);
+        status_w.map_err(new_io_err)?;
+        let mut buffer: [u8; 10] = [0; 10];
+        let stat: usize = stream.read(&mut buffer).map_err(new_io_err)?;
+        match stat {
+            0 => {
+                warn!("Token invalid. Connection Close");
+                Err(Error::new(
+                    io::ErrorKind::Other,
+                    "Token invalid. Connection Close",
+                ))
+            }
+            _ => {
+                info!("Token Valid");
+                Ok(stream)
+            }
+        }
+    }
 }
 
 impl Output for PamCollectorOutput {
-    fn start(&self) {
-        trace!("Handle PamCollectorOutput::start");
-        match self.recreate_stream() {
-            Ok(v) => {
-                let shared_stream: Rc<RefCell<TlsStream<TcpStream>>> = Rc::new(RefCell::new(v));
-                info!("Start consume events");
-                self.consume_events(shared_stream);
-            }
-            Err(e) => warn!("Error while creating TCP Stream. Error: {}", e),
+    fn name(&self) -> &str {
+        "PamCollectorOutput"
+    }
+
+    fn open(&mut self) -> Result<(), io::Error> {
+        self.stream = None;
+        self.stream = Some(self.recreate_stream()?);
+        Ok(())
+    }
+
+    fn send_batch(&mut self, batch: &mut Vec<String>) -> Result<(), io::Error> {
+        let compression = recover(BATCH_CONFIG.read()).compression;
+        let frame = encode_batch(batch, compression)?;
+        let stream = match self.stream {
+            Some(ref mut s) => s,
+            None => return Err(new_io_err("Stream is not opened")),
         };
+        debug!("Start write batch. Frame size is {}", frame.len());
+        stream.write_all(&frame)?;
+        stream.flush()?;
+        info!("Start read ack after write batch");
+        match stream.read(&mut [0; 128])? {
+            0 => Err(new_io_err("Remote server close connect")),
+            _ => {
+                trace!("Success write trace batch");
+                Ok(())
+            }
+        }
     }
+}
 
-    fn recreate_stream(&self) -> Result<TlsStream<TcpStream>, backoff::Error<io::Error>> {
-        let mut backoff = ExponentialBackoff::default();
-        info!("BackOff configured");
-        let mut op = || {
-            let mut stream = get_connection(&self.addr).map_err(new_io_err)?;
-            info!("Prepare write token");
-            let status_w: Result<usize, Error> = stream.write(self.token.as_bytes());
-            trace!("Write token payload to server. Write bytes: {:?}", status_w);
-            status_w.map_err(new_io_err)?;
-            let mut buffer: [u8; 10] = [0; 10];
-            let stat: usize = stream.read(&mut buffer).map_err(new_io_err)?;
-            fn check_stat(stat: usize) -> Result<(), io::Error> {
-                match stat {
-                    0 => {
-                        warn!("Token invalid. Connection Close");
-                        Err(Error::new(
-                            io::ErrorKind::Other,
-                            "Token invalid. Connection Close",
-                        ))
-                    }
-                    _ => {
-                        info!("Token Valid");
-                        Ok(())
-                    }
+/// Append transactions as newline-delimited JSON to a local file.
+///
+/// The file is rotated once it grows past `max_bytes`: `path` becomes `path.1`, `path.1` becomes
+/// `path.2` and so on, keeping at most `max_files` rotated files. `max_bytes == 0` disables rotation.
+pub struct FileOutput {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: u32,
+    file: Option<File>,
+    written: u64,
+}
+
+impl FileOutput {
+    pub fn new(path: String, max_bytes: u64, max_files: u32) -> FileOutput {
+        FileOutput {
+            path: PathBuf::from(path),
+            max_bytes,
+            max_files,
+            file: None,
+            written: 0,
+        }
+    }
+
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> Result<(), io::Error> {
+        info!("Rotate output file {:?}", self.path);
+        self.file = None;
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                let src = self.rotated_path(index);
+                if src.exists() {
+                    fs::rename(&src, self.rotated_path(index + 1))?;
                 }
-            };
-            check_stat(stat)
-                .map_err(new_io_err)
-                .map_err(backoff::Error::Permanent)?;
-            Ok(stream)
-        };
-        op.retry(&mut backoff)
-    }
-
-    fn consume_events(&self, shared_stream: Rc<RefCell<TlsStream<TcpStream>>>) {
-        info!("Consume event output loop started");
-        let mut need_recreate: bool = true;
-        let mut new_stream: TlsStream<TcpStream>;
-        loop {
-            trace!("In Loop");
-            if need_recreate {
-                trace!("TCP Stream need to recreate");
-                thread::sleep(Duration::from_secs(10));
-                match self.recreate_stream() {
-                    Ok(v) => new_stream = v,
-                    Err(e) => {
-                        warn!("Error recreate stream. Error: {}", e);
-                        need_recreate = true;
-                        continue;
-                    }
-                };
-                shared_stream.replace(new_stream);
-                need_recreate = false;
             }
-                    debug!("Get OUTPUT_QUEUE");
-                    let val: Option<String> = OUTPUT_QUEUE.lock().unwrap().pop_front();
-                    match val {
-                        Some(mut v) => {
-                            debug!("Start write bytes with payload");
-                            v.push_str("\r\n");
-                            debug!("Prepare send payload");
-                            debug!("Payload size is {}", v.len());
-                            trace!("Value is {:?}", &v);
-                            let _ = shared_stream.borrow_mut().write_all(v.as_bytes());
-                            info!("Start read bytes after write payload");
-                            let read_bytes: Result<usize, Error> = shared_stream.borrow_mut().read(&mut [0; 128]);
-                            match read_bytes {
-                                Ok(v) => {
-                                    match v {
-                                        0 => {
-                                            warn!("Remote server close connect");
-                                            need_recreate = true;
-                                        }
-                                        _ => trace!("Success write trace payload"),
-                                    };
-                                }
-                                Err(e) => {
-                                    error!("Error while read payload response {}", e);
-                                    need_recreate = true;
-                                }
-                            }
-                        }
-                        None => {
-                            trace!("Not val");
-                            thread::sleep(Duration::from_millis(400));
-                            ()
-                        }
-                    }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.open()
+    }
+
+    /// Append `batch`, counting appended payloads in `sent`.
+    fn write_batch(&mut self, batch: &[String], sent: &mut usize) -> Result<(), io::Error> {
+        for payload in batch {
+            let size = payload.len() as u64 + 1;
+            if self.max_bytes > 0 && self.written > 0 && self.written + size > self.max_bytes {
+                self.rotate()?;
+            }
+            let file = match self.file {
+                Some(ref mut f) => f,
+                None => return Err(new_io_err("Output file is not opened")),
+            };
+            file.write_all(payload.as_bytes())?;
+            file.write_all(b"\n")?;
+            self.written += size;
+            *sent += 1;
+        }
+        if let Some(ref mut f) = self.file {
+            f.flush()?;
         }
+        Ok(())
+    }
+}
+
+impl Output for FileOutput {
+    fn name(&self) -> &str {
+        "FileOutput"
+    }
+
+    fn open(&mut self) -> Result<(), io::Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.written = file.metadata()?.len();
+        self.file = Some(file);
+        info!("Output file {:?} is opened", self.path);
+        Ok(())
+    }
+
+    fn send_batch(&mut self, batch: &mut Vec<String>) -> Result<(), io::Error> {
+        // Appended payloads are not sent again if a later one fails
+        let mut sent = 0;
+        let result = self.write_batch(batch, &mut sent);
+        batch.drain(..sent);
+        result
     }
 }
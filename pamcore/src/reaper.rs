@@ -0,0 +1,53 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::RwLock;
+use std::thread;
+
+use core::TRANSACTION_CACHE;
+use error::recover;
+use worker::secs_to_duration;
+
+pub const DEFAULT_TRANSACTION_MAX_AGE: f64 = 600.0;
+/// Bounds of pause between runs of reaper, in seconds.
+const MIN_REAP_INTERVAL: f64 = 1.0;
+const MAX_REAP_INTERVAL: f64 = 60.0;
+
+lazy_static! {
+    /// Seconds since last activity after which transaction that was not dropped, e.g. by killed
+    /// thread, is closed and sent. 0 disables reaper.
+    pub static ref TRANSACTION_MAX_AGE: RwLock<f64> = RwLock::new(DEFAULT_TRANSACTION_MAX_AGE);
+    /// Count of transactions closed by reaper since start.
+    pub static ref REAPED: AtomicUsize = AtomicUsize::new(0);
+    static ref STARTED: AtomicBool = AtomicBool::new(false);
+}
+
+/// Start reaper thread, once per process. It runs until the process exits.
+pub fn start() {
+    if STARTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let spawned = thread::Builder::new()
+        .name("pamagent-reaper".to_owned())
+        .spawn(run);
+    if let Err(e) = spawned {
+        STARTED.store(false, Ordering::SeqCst);
+        error!("Unable to start transaction reaper: {}", e);
+    }
+}
+
+fn run() {
+    loop {
+        let max_age = *recover(TRANSACTION_MAX_AGE.read());
+        let interval = (max_age / 2.0).clamp(MIN_REAP_INTERVAL, MAX_REAP_INTERVAL);
+        thread::sleep(secs_to_duration(interval));
+        reap(*recover(TRANSACTION_MAX_AGE.read()));
+    }
+}
+
+/// Close and send transactions inactive for more than `max_age` seconds. Their open nodes are
+/// closed at time of last activity. Returns count of them.
+pub fn reap(max_age: f64) -> usize {
+    if max_age <= 0.0 {
+        return 0;
+    }
+    TRANSACTION_CACHE.reap(max_age)
+}
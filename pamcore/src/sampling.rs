@@ -0,0 +1,309 @@
+use std::sync::Mutex;
+
+use rand;
+
+use core;
+use error::recover;
+use tracecontext::TraceContext;
+
+/// Transactions per second kept by rate_limited and adaptive modes.
+pub const DEFAULT_SAMPLING_TARGET: f64 = 10.0;
+/// Duration in seconds from which unsampled transactions are still sent in adaptive mode.
+pub const DEFAULT_SLOW_TRANSACTION_THRESHOLD: f64 = 1.0;
+/// Duration in seconds from which finished transaction is kept by tail sampling.
+pub const DEFAULT_KEEP_MIN_DURATION: f64 = 1.0;
+/// Seconds over which adaptive mode measures throughput before it corrects probability.
+const ADAPTIVE_WINDOW: f64 = 5.0;
+/// Weight of the last window in smoothed throughput.
+const ADAPTIVE_SMOOTHING: f64 = 0.5;
+
+lazy_static! {
+    pub static ref SAMPLER: Mutex<Sampler> = { Mutex::new(Sampler::new()) };
+}
+
+/// How head sampling decides whether nodes of a new transaction are recorded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SamplingMode {
+    /// Share of transactions given by `sample_rate`.
+    Fixed,
+    /// At most `sampling_target` transactions per second.
+    RateLimited,
+    /// Probability follows throughput to keep about `sampling_target` transactions per second.
+    /// Unsampled transactions that errored or are slow are still sent, with the root node only.
+    Adaptive,
+}
+
+impl SamplingMode {
+    pub fn parse(val: &str) -> Option<SamplingMode> {
+        match val {
+            "fixed" => Some(SamplingMode::Fixed),
+            "rate_limited" => Some(SamplingMode::RateLimited),
+            "adaptive" => Some(SamplingMode::Adaptive),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SamplingMode::Fixed => "fixed",
+            SamplingMode::RateLimited => "rate_limited",
+            SamplingMode::Adaptive => "adaptive",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SamplingStats {
+    pub sampled: u64,
+    pub not_sampled: u64,
+    /// Unsampled transactions sent because they errored or were slow.
+    pub kept: u64,
+    /// Sampled transactions sent and discarded by keep rules of tail sampling.
+    pub tail_kept: u64,
+    pub tail_discarded: u64,
+}
+
+/// Rules of tail sampling, applied to finished tree of sampled transaction when it is dropped.
+/// Transaction matching none of them is not sent, though its database calls are still counted
+/// in query stats.
+#[derive(Clone, Debug, Default)]
+pub struct KeepRules {
+    pub enabled: bool,
+    /// Duration in seconds, 0 disables the rule.
+    pub min_duration: f64,
+    pub errored: bool,
+    /// Glob patterns of transaction path, `*` matches any characters.
+    pub path_patterns: Vec<String>,
+    /// Count of database calls, including folded ones, 0 disables the rule.
+    pub min_database_calls: usize,
+}
+
+impl KeepRules {
+    /// Name of the first rule matched by transaction, None if it is discarded.
+    pub fn matched(
+        &self,
+        path: &str,
+        duration: f64,
+        errored: bool,
+        database_calls: usize,
+    ) -> Option<&'static str> {
+        if errored && self.errored {
+            return Some("error");
+        }
+        if self.min_duration > 0.0 && duration >= self.min_duration {
+            return Some("duration");
+        }
+        if self.min_database_calls > 0 && database_calls >= self.min_database_calls {
+            return Some("database_calls");
+        }
+        if self.path_patterns.iter().any(|p| glob_match(p, path)) {
+            return Some("path");
+        }
+        None
+    }
+}
+
+/// Match `text` against pattern where `*` stands for any characters, possibly none.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == text;
+    }
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if text.len() < first.len() + last.len() || !text.starts_with(first) || !text.ends_with(last)
+    {
+        return false;
+    }
+    let mut rest = &text[first.len()..text.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+pub struct Sampler {
+    mode: SamplingMode,
+    rate: f64,
+    target: f64,
+    slow_threshold: f64,
+    /// Token bucket of rate_limited mode, holds at most one second of `target`.
+    tokens: f64,
+    last_refill: f64,
+    /// Transactions seen and sampled by adaptive mode since `window_start`.
+    window_start: f64,
+    window_seen: u64,
+    window_sampled: u64,
+    throughput: Option<f64>,
+    probability: f64,
+    rules: KeepRules,
+    stats: SamplingStats,
+}
+
+impl Sampler {
+    pub fn new() -> Sampler {
+        let now = core::now();
+        Sampler {
+            mode: SamplingMode::Fixed,
+            rate: 1.0,
+            target: DEFAULT_SAMPLING_TARGET,
+            slow_threshold: DEFAULT_SLOW_TRANSACTION_THRESHOLD,
+            tokens: DEFAULT_SAMPLING_TARGET,
+            last_refill: now,
+            window_start: now,
+            window_seen: 0,
+            window_sampled: 0,
+            throughput: None,
+            probability: 1.0,
+            rules: KeepRules::default(),
+            stats: SamplingStats::default(),
+        }
+    }
+
+    /// Change settings. State of previous mode is reset, counters are kept.
+    pub fn configure(&mut self, mode: SamplingMode, rate: f64, target: f64, slow_threshold: f64) {
+        let now = core::now();
+        self.mode = mode;
+        self.rate = rate;
+        self.target = target;
+        self.slow_threshold = slow_threshold;
+        self.tokens = target;
+        self.last_refill = now;
+        self.window_start = now;
+        self.window_seen = 0;
+        self.window_sampled = 0;
+        self.throughput = None;
+        self.probability = 1.0;
+    }
+
+    /// Head sampling decision for transaction started at `now`.
+    pub fn sample(&mut self, now: f64) -> bool {
+        let sampled = match self.mode {
+            SamplingMode::Fixed => chance(self.rate),
+            SamplingMode::RateLimited => self.take_token(now),
+            SamplingMode::Adaptive => {
+                self.adapt(now);
+                let sampled = self.window_sampled < self.window_quota() && chance(self.probability);
+                if sampled {
+                    self.window_sampled += 1;
+                }
+                sampled
+            }
+        };
+        self.count(sampled);
+        sampled
+    }
+
+    fn count(&mut self, sampled: bool) {
+        if sampled {
+            self.stats.sampled += 1;
+        } else {
+            self.stats.not_sampled += 1;
+        }
+    }
+
+    fn take_token(&mut self, now: f64) -> bool {
+        let elapsed = (now - self.last_refill).max(0.0);
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * self.target).min(self.target.max(1.0));
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Most transactions sampled in one window, it bounds bursts and the first window, before
+    /// throughput is known.
+    fn window_quota(&self) -> u64 {
+        (self.target * ADAPTIVE_WINDOW).ceil() as u64
+    }
+
+    /// Probability is corrected once per `ADAPTIVE_WINDOW` from smoothed throughput.
+    fn adapt(&mut self, now: f64) {
+        self.window_seen += 1;
+        let elapsed = now - self.window_start;
+        if elapsed < ADAPTIVE_WINDOW {
+            return;
+        }
+        let rate = self.window_seen as f64 / elapsed;
+        let throughput = match self.throughput {
+            Some(v) => v + (rate - v) * ADAPTIVE_SMOOTHING,
+            None => rate,
+        };
+        self.throughput = Some(throughput);
+        self.probability = (self.target / throughput).min(1.0);
+        self.window_start = now;
+        self.window_seen = 0;
+        self.window_sampled = 0;
+    }
+
+    /// Whether unsampled transaction is sent anyway, with the root node only.
+    pub fn keep_unsampled(&mut self, errored: bool, duration: f64) -> bool {
+        let slow = self.slow_threshold > 0.0 && duration >= self.slow_threshold;
+        if self.mode != SamplingMode::Adaptive || !(errored || slow) {
+            return false;
+        }
+        self.stats.kept += 1;
+        true
+    }
+
+    pub fn set_keep_rules(&mut self, rules: KeepRules) {
+        self.rules = rules;
+    }
+
+    pub fn tail_sampling(&self) -> bool {
+        self.rules.enabled
+    }
+
+    /// Keep rule matched by finished sampled transaction, see `KeepRules::matched`.
+    pub fn keep_rule(
+        &mut self,
+        path: &str,
+        duration: f64,
+        errored: bool,
+        database_calls: usize,
+    ) -> Option<&'static str> {
+        let rule = self.rules.matched(path, duration, errored, database_calls);
+        if rule.is_some() {
+            self.stats.tail_kept += 1;
+        } else {
+            self.stats.tail_discarded += 1;
+        }
+        rule
+    }
+
+    /// Current probability of head sampling, None in rate_limited mode.
+    pub fn probability(&self) -> Option<f64> {
+        match self.mode {
+            SamplingMode::Fixed => Some(self.rate),
+            SamplingMode::RateLimited => None,
+            SamplingMode::Adaptive => Some(self.probability),
+        }
+    }
+
+    pub fn mode(&self) -> SamplingMode {
+        self.mode
+    }
+
+    pub fn stats(&self) -> SamplingStats {
+        self.stats
+    }
+}
+
+fn chance(probability: f64) -> bool {
+    probability >= 1.0 || rand::random::<f64>() < probability
+}
+
+/// Sampling decision of new transaction. Trace continued from caller keeps decision of caller,
+/// so the whole distributed trace is either recorded or not.
+pub fn decide(trace: &TraceContext) -> bool {
+    let mut sampler = recover(SAMPLER.lock());
+    if trace.parent_span_id.is_some() {
+        sampler.count(trace.sampled);
+        return trace.sampled;
+    }
+    sampler.sample(core::now())
+}
@@ -0,0 +1,626 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+pub const DEFAULT_SQL_CACHE_SIZE: usize = 1000;
+/// Longer statements are parsed on every call, so cache memory stays bounded.
+const MAX_CACHED_SQL_LENGTH: usize = 16 * 1024;
+/// Max count of distinct queries in `QUERY_STATS`, calls of other queries are only counted.
+const MAX_QUERY_STATS: usize = 1000;
+
+lazy_static! {
+    pub static ref SQL_CACHE: Mutex<SqlCache> =
+        { Mutex::new(SqlCache::new(DEFAULT_SQL_CACHE_SIZE)) };
+    pub static ref QUERY_STATS: Mutex<QueryStats> = { Mutex::new(QueryStats::new()) };
+}
+
+/// Which quotes delimit string literals, set by `register_database_client`. Other quotes delimit
+/// identifiers. Dollar quoting of PostgreSQL is always a literal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum QuotingStyle {
+    Single,
+    Double,
+    SingleDouble,
+}
+
+impl QuotingStyle {
+    /// Unknown style is `single`, the standard one.
+    pub fn parse(val: &str) -> QuotingStyle {
+        match val {
+            "double" => QuotingStyle::Double,
+            "single+double" => QuotingStyle::SingleDouble,
+            _ => QuotingStyle::Single,
+        }
+    }
+
+    fn is_literal(&self, quote: u8) -> bool {
+        match (*self, quote) {
+            (_, b'$') => true,
+            (QuotingStyle::Single, b'\'') | (QuotingStyle::SingleDouble, b'\'') => true,
+            (QuotingStyle::Double, b'"') | (QuotingStyle::SingleDouble, b'"') => true,
+            _ => false,
+        }
+    }
+
+    /// Backslash escapes quote in literals of MySQL, in standard SQL it is an ordinary character.
+    fn backslash_escapes(&self) -> bool {
+        *self == QuotingStyle::SingleDouble
+    }
+}
+
+/// Obfuscated text of statement with its operation and target table, both lowercase.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SqlStatement {
+    pub obfuscated: String,
+    pub operation: String,
+    pub target: String,
+    pub normalized: String,
+    pub fingerprint: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Space,
+    Comment,
+    Word,
+    /// Quoted with `"`, `` ` `` or `[]`, a literal when quoting style says so.
+    Quoted(u8),
+    Literal,
+    Placeholder,
+    Punct,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Token<'a> {
+    kind: Kind,
+    text: &'a str,
+}
+
+fn is_word_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b >= 0x80
+}
+
+/// End of quoted text started at `start`, doubled quote is an escaped one. With `backslash`,
+/// as in literals of MySQL and `E'...'` strings of PostgreSQL, backslash escapes the next char.
+fn quoted_end(sql: &[u8], start: usize, close: u8, backslash: bool) -> usize {
+    let mut i = start + 1;
+    while i < sql.len() {
+        if backslash && sql[i] == b'\\' {
+            i += 2;
+            continue;
+        }
+        if sql[i] == close {
+            if i + 1 < sql.len() && sql[i + 1] == close && close != b']' {
+                i += 2;
+                continue;
+            }
+            return i + 1;
+        }
+        i += 1;
+    }
+    sql.len()
+}
+
+/// Tag of dollar quote `$tag$` at `start`, `$1` placeholders are not quotes.
+fn dollar_tag(sql: &str, start: usize) -> Option<&str> {
+    let bytes = sql.as_bytes();
+    let mut i = start + 1;
+    while i < bytes.len() && is_word_byte(bytes[i]) {
+        i += 1;
+    }
+    if i < bytes.len() && bytes[i] == b'$' && !(i > start + 1 && bytes[start + 1].is_ascii_digit())
+    {
+        Some(&sql[start..i + 1])
+    } else {
+        None
+    }
+}
+
+fn number_end(sql: &[u8], start: usize) -> usize {
+    let mut i = start;
+    if sql[i] == b'0' && i + 1 < sql.len() && (sql[i + 1] == b'x' || sql[i + 1] == b'X') {
+        i += 2;
+        while i < sql.len() && sql[i].is_ascii_hexdigit() {
+            i += 1;
+        }
+        return i;
+    }
+    while i < sql.len() && (sql[i].is_ascii_digit() || sql[i] == b'.') {
+        i += 1;
+    }
+    if i < sql.len() && (sql[i] == b'e' || sql[i] == b'E') {
+        let mut j = i + 1;
+        if j < sql.len() && (sql[j] == b'+' || sql[j] == b'-') {
+            j += 1;
+        }
+        if j < sql.len() && sql[j].is_ascii_digit() {
+            i = j;
+            while i < sql.len() && sql[i].is_ascii_digit() {
+                i += 1;
+            }
+        }
+    }
+    i
+}
+
+fn tokenize(sql: &str, quoting: QuotingStyle) -> Vec<Token> {
+    let bytes = sql.as_bytes();
+    let mut tokens: Vec<Token> = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let b = bytes[i];
+        let next = bytes.get(i + 1).cloned().unwrap_or(0);
+        let (kind, end) = if b.is_ascii_whitespace() {
+            let mut j = i;
+            while j < bytes.len() && bytes[j].is_ascii_whitespace() {
+                j += 1;
+            }
+            (Kind::Space, j)
+        } else if b == b'-' && next == b'-' {
+            let end = sql[i..].find('\n').map_or(bytes.len(), |v| i + v);
+            (Kind::Comment, end)
+        } else if b == b'/' && next == b'*' {
+            let end = sql[i + 2..].find("*/").map_or(bytes.len(), |v| i + 2 + v + 2);
+            (Kind::Comment, end)
+        } else if b == b'\'' || b == b'"' || b == b'`' {
+            let kind = if quoting.is_literal(b) { Kind::Literal } else { Kind::Quoted(b) };
+            let backslash = kind == Kind::Literal && quoting.backslash_escapes();
+            (kind, quoted_end(bytes, i, b, backslash))
+        } else if b == b'[' {
+            (Kind::Quoted(b), quoted_end(bytes, i, b']', false))
+        } else if b == b'$' {
+            match dollar_tag(sql, i) {
+                Some(tag) => {
+                    let body = i + tag.len();
+                    let end = sql[body..].find(tag).map_or(bytes.len(), |v| body + v + tag.len());
+                    (Kind::Literal, end)
+                }
+                None => {
+                    let mut j = i + 1;
+                    while j < bytes.len() && is_word_byte(bytes[j]) {
+                        j += 1;
+                    }
+                    (Kind::Placeholder, j)
+                }
+            }
+        } else if b"xXbBnNeE".contains(&b) && next == b'\'' {
+            // Hex, bit, national and escape string literals
+            let backslash = b == b'e' || b == b'E' || quoting.backslash_escapes();
+            (Kind::Literal, quoted_end(bytes, i + 1, b'\'', backslash))
+        } else if b.is_ascii_digit() || (b == b'.' && next.is_ascii_digit()) {
+            (Kind::Literal, number_end(bytes, i))
+        } else if is_word_byte(b) {
+            let mut j = i;
+            while j < bytes.len() && is_word_byte(bytes[j]) {
+                j += 1;
+            }
+            let word = &sql[i..j];
+            if word.eq_ignore_ascii_case("true") || word.eq_ignore_ascii_case("false") {
+                (Kind::Literal, j)
+            } else {
+                (Kind::Word, j)
+            }
+        } else if b == b'?' {
+            (Kind::Placeholder, i + 1)
+        } else if b == b'%' && (next == b's' || next == b'(') {
+            // Format and pyformat paramstyles: %s and %(name)s
+            let end = match next {
+                b's' => i + 2,
+                _ => sql[i..].find(")s").map_or(i + 1, |v| i + v + 2),
+            };
+            (Kind::Placeholder, end)
+        } else if b == b':' && is_word_byte(next) && !tokens.last().map_or(false, |t| t.text == ":")
+        {
+            // Numeric and named paramstyles, not `::` casts of PostgreSQL
+            let mut j = i + 1;
+            while j < bytes.len() && is_word_byte(bytes[j]) {
+                j += 1;
+            }
+            (Kind::Placeholder, j)
+        } else {
+            let mut j = i + 1;
+            while j < bytes.len() && !sql.is_char_boundary(j) {
+                j += 1;
+            }
+            (Kind::Punct, j)
+        };
+        let end = end.max(i + 1).min(bytes.len());
+        tokens.push(Token {
+            kind,
+            text: &sql[i..end],
+        });
+        i = end;
+    }
+    tokens
+}
+
+fn is_value(token: &Token) -> bool {
+    token.kind == Kind::Literal || token.kind == Kind::Placeholder
+}
+
+/// Index after `(?, ?, ...)` list of values starting at `start`, None if list has anything else.
+fn value_list_end(tokens: &[Token], start: usize) -> Option<usize> {
+    let mut expect_value = true;
+    let mut values = 0;
+    for (i, token) in tokens.iter().enumerate().skip(start + 1) {
+        match token.kind {
+            Kind::Space | Kind::Comment => continue,
+            Kind::Punct if token.text == ")" && !expect_value && values > 0 => return Some(i + 1),
+            Kind::Punct if token.text == "," && !expect_value => expect_value = true,
+            _ if expect_value && is_value(token) => {
+                expect_value = false;
+                values += 1;
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// Comments are removed, literals are replaced with `?` and `IN (...)` lists of values are
+/// collapsed into `IN (?)`, so statements that differ only in values are the same.
+fn obfuscate(tokens: &[Token]) -> String {
+    let mut result = String::with_capacity(tokens.iter().map(|t| t.text.len()).sum());
+    let mut after_in = false;
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = tokens[i];
+        match token.kind {
+            Kind::Space | Kind::Comment => {}
+            Kind::Word => after_in = token.text.eq_ignore_ascii_case("in"),
+            Kind::Punct if token.text == "(" && after_in => {
+                after_in = false;
+                if let Some(end) = value_list_end(tokens, i) {
+                    result.push_str("(?)");
+                    i = end;
+                    continue;
+                }
+            }
+            _ => after_in = false,
+        }
+        match token.kind {
+            Kind::Comment => {}
+            Kind::Literal => result.push('?'),
+            _ => result.push_str(token.text),
+        }
+        i += 1;
+    }
+    result
+}
+
+const OPERATOR_CHARS: &str = "<>=!|&:+-*/%^~@#";
+
+/// Separator before `next` in normalized form. Operators like `>=` and `::` stay glued.
+fn separator(prev: &str, next: &str, glued: bool) -> &'static str {
+    let operator = |v: &str| v.len() == 1 && OPERATOR_CHARS.contains(v);
+    let after_open = prev.is_empty() || prev == "(" || prev == ".";
+    let before_close = next == ")" || next == "," || next == ".";
+    if after_open || before_close || (glued && operator(prev) && operator(next)) {
+        ""
+    } else {
+        " "
+    }
+}
+
+/// Index of first token after `start` that is not a space or comment.
+fn next_significant(tokens: &[Token], start: usize) -> Option<usize> {
+    (start..tokens.len())
+        .find(|&i| tokens[i].kind != Kind::Space && tokens[i].kind != Kind::Comment)
+}
+
+/// Normalized form of statement: obfuscated, without comments, words in lowercase and single
+/// spaces between tokens. Rows of multi-row `VALUES` are collapsed into one, so the same query
+/// written with different whitespace, casing, `IN` lists or batch sizes is the same string.
+fn normalize(tokens: &[Token]) -> String {
+    let mut result = String::new();
+    let mut prev = String::new();
+    let mut glued = false;
+    // Last token is a row of VALUES, next rows are skipped
+    let mut values_row = false;
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = tokens[i];
+        i += 1;
+        let mut row = false;
+        let text = match token.kind {
+            Kind::Space | Kind::Comment => {
+                glued = false;
+                continue;
+            }
+            Kind::Word => token.text.to_lowercase(),
+            Kind::Punct if token.text == "," && values_row => {
+                let next = next_significant(tokens, i).filter(|&j| tokens[j].text == "(");
+                match next.and_then(|j| value_list_end(tokens, j)) {
+                    Some(end) => {
+                        i = end;
+                        continue;
+                    }
+                    None => ",".to_owned(),
+                }
+            }
+            Kind::Punct if token.text == "(" && (prev == "in" || prev == "values") => {
+                match value_list_end(tokens, i - 1) {
+                    Some(end) if prev == "in" => {
+                        i = end;
+                        "(?)".to_owned()
+                    }
+                    Some(end) => {
+                        let count = tokens[i..end].iter().filter(|t| is_value(t)).count();
+                        i = end;
+                        row = true;
+                        format!("({})", vec!["?"; count].join(", "))
+                    }
+                    None => "(".to_owned(),
+                }
+            }
+            _ if is_value(&token) => "?".to_owned(),
+            _ => token.text.to_owned(),
+        };
+        values_row = row;
+        result.push_str(separator(&prev, &text, glued));
+        result.push_str(&text);
+        prev = text;
+        glued = true;
+    }
+    result
+}
+
+/// Stable 64-bit FNV-1a hash of normalized statement. Unlike `DefaultHasher` it does not change
+/// between processes and Rust versions, so collector can group queries of all agents.
+pub fn fingerprint(normalized: &str) -> u64 {
+    normalized.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Operations which are reported, other statements have empty operation.
+const OPERATIONS: &[&str] = &[
+    "select", "delete", "insert", "update", "create", "drop", "call", "show", "set", "exec",
+    "execute", "alter", "commit", "rollback",
+];
+
+fn identifier_part(token: &Token) -> Option<String> {
+    let text = match token.kind {
+        Kind::Word => token.text,
+        Kind::Quoted(quote) => {
+            // Unterminated identifier has no closing quote
+            let close = if quote == b'[' { ']' } else { char::from(quote) };
+            let text = &token.text[1..];
+            if text.ends_with(close) {
+                &text[..text.len() - 1]
+            } else {
+                text
+            }
+        }
+        _ => return None,
+    };
+    Some(text.to_lowercase())
+}
+
+/// Possibly qualified name starting at `start`, e.g. `"public"."orders"` is `public.orders`.
+fn identifier(tokens: &[&Token], start: usize) -> String {
+    let mut parts: Vec<String> = vec![];
+    let mut i = start;
+    while let Some(part) = tokens.get(i).and_then(|t| identifier_part(t)) {
+        parts.push(part);
+        match tokens.get(i + 1) {
+            Some(t) if t.text == "." => i += 2,
+            _ => break,
+        }
+    }
+    parts.join(".")
+}
+
+/// Target of the first `keyword name` pair of statement.
+fn target_after(tokens: &[&Token], keyword: &str) -> String {
+    for (i, token) in tokens.iter().enumerate() {
+        if token.kind == Kind::Word && token.text.eq_ignore_ascii_case(keyword) {
+            let target = identifier(tokens, i + 1);
+            if !target.is_empty() {
+                return target;
+            }
+        }
+    }
+    "".to_owned()
+}
+
+fn operation_and_target(tokens: &[Token]) -> (String, String) {
+    let tokens: Vec<&Token> = tokens
+        .iter()
+        .filter(|t| t.kind != Kind::Space && t.kind != Kind::Comment)
+        .collect();
+    let operation = match tokens.iter().find(|t| t.kind == Kind::Word) {
+        Some(t) => t.text.to_lowercase(),
+        None => return ("".to_owned(), "".to_owned()),
+    };
+    if !OPERATIONS.contains(&operation.as_ref()) {
+        return ("".to_owned(), "".to_owned());
+    }
+    let target = match operation.as_ref() {
+        "select" | "delete" => target_after(&tokens, "from"),
+        "insert" => target_after(&tokens, "into"),
+        "update" => target_after(&tokens, "update"),
+        "call" => target_after(&tokens, "call"),
+        _ => "".to_owned(),
+    };
+    (operation, target)
+}
+
+pub fn parse(sql: &str, quoting: QuotingStyle) -> SqlStatement {
+    let tokens = tokenize(sql, quoting);
+    let (operation, target) = operation_and_target(&tokens);
+    let normalized = normalize(&tokens);
+    SqlStatement {
+        obfuscated: obfuscate(&tokens),
+        operation,
+        target,
+        fingerprint: fingerprint(&normalized),
+        normalized,
+    }
+}
+
+/// LRU cache of parsed statements. ORMs send the same statements over and over, so most calls
+/// are a hash lookup.
+pub struct SqlCache {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<(QuotingStyle, String), (SqlStatement, u64)>,
+    /// Last use tick of every entry, the first one is evicted.
+    order: BTreeMap<u64, (QuotingStyle, String)>,
+}
+
+impl SqlCache {
+    pub fn new(capacity: usize) -> SqlCache {
+        SqlCache {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    /// Change capacity, evicting least recently used statements. 0 disables cache.
+    pub fn resize(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity {
+            let tick = match self.order.keys().next() {
+                Some(v) => *v,
+                None => break,
+            };
+            if let Some(key) = self.order.remove(&tick) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    pub fn get(&mut self, sql: &str, quoting: QuotingStyle) -> SqlStatement {
+        if self.capacity == 0 || sql.len() > MAX_CACHED_SQL_LENGTH {
+            return parse(sql, quoting);
+        }
+        self.tick += 1;
+        let key = (quoting, sql.to_owned());
+        if let Some(&mut (ref statement, ref mut tick)) = self.entries.get_mut(&key) {
+            let key = self.order.remove(tick).unwrap_or_else(|| key.clone());
+            *tick = self.tick;
+            self.order.insert(self.tick, key);
+            return statement.clone();
+        }
+        let statement = parse(sql, quoting);
+        self.entries.insert(key.clone(), (statement.clone(), self.tick));
+        self.order.insert(self.tick, key);
+        self.evict();
+        statement
+    }
+}
+
+/// Format fingerprint as 16 hex chars. JSON numbers lose precision above 2^53.
+pub fn fingerprint_hex(fingerprint: u64) -> String {
+    format!("{:016x}", fingerprint)
+}
+
+/// Calls of one normalized query. Times are in seconds.
+#[derive(Clone, Debug, Serialize)]
+pub struct QueryAggregate {
+    pub fingerprint: String,
+    pub normalized: String,
+    pub database_product: String,
+    pub operation: String,
+    pub target: String,
+    pub count: u64,
+    pub total_time: f64,
+    pub min_time: f64,
+    pub max_time: f64,
+}
+
+/// Aggregates of finished database calls by fingerprint, for top queries of collector.
+#[derive(Debug)]
+pub struct QueryStats {
+    queries: HashMap<u64, QueryAggregate>,
+    /// Calls not aggregated, as `MAX_QUERY_STATS` distinct queries were already seen.
+    dropped: u64,
+}
+
+impl QueryStats {
+    pub fn new() -> QueryStats {
+        QueryStats {
+            queries: HashMap::new(),
+            dropped: 0,
+        }
+    }
+
+    pub fn record(
+        &mut self,
+        fingerprint: u64,
+        normalized: &str,
+        database_product: &str,
+        operation: &str,
+        target: &str,
+        duration: f64,
+    ) {
+        let duration = duration.max(0.0);
+        if let Some(query) = self.queries.get_mut(&fingerprint) {
+            query.count += 1;
+            query.total_time += duration;
+            query.min_time = query.min_time.min(duration);
+            query.max_time = query.max_time.max(duration);
+            return;
+        }
+        if self.queries.len() >= MAX_QUERY_STATS {
+            self.dropped += 1;
+            return;
+        }
+        self.queries.insert(
+            fingerprint,
+            QueryAggregate {
+                fingerprint: fingerprint_hex(fingerprint),
+                normalized: normalized.to_owned(),
+                database_product: database_product.to_owned(),
+                operation: operation.to_owned(),
+                target: target.to_owned(),
+                count: 1,
+                total_time: duration,
+                min_time: duration,
+                max_time: duration,
+            },
+        );
+    }
+
+    /// Add aggregates of `other`, e.g. of one transaction.
+    pub fn merge(&mut self, other: QueryStats) {
+        self.dropped += other.dropped;
+        for (fingerprint, query) in other.queries {
+            if let Some(v) = self.queries.get_mut(&fingerprint) {
+                v.count += query.count;
+                v.total_time += query.total_time;
+                v.min_time = v.min_time.min(query.min_time);
+                v.max_time = v.max_time.max(query.max_time);
+                continue;
+            }
+            if self.queries.len() >= MAX_QUERY_STATS {
+                self.dropped += query.count;
+                continue;
+            }
+            self.queries.insert(fingerprint, query);
+        }
+    }
+
+    /// Aggregates sorted by total time, slowest first, and count of dropped calls.
+    pub fn snapshot(&self) -> (Vec<QueryAggregate>, u64) {
+        let mut queries: Vec<QueryAggregate> = self.queries.values().cloned().collect();
+        queries.sort_by(|a, b| {
+            b.total_time
+                .partial_cmp(&a.total_time)
+                .unwrap_or(Ordering::Equal)
+        });
+        (queries, self.dropped)
+    }
+
+    pub fn reset(&mut self) {
+        self.queries.clear();
+        self.dropped = 0;
+    }
+}
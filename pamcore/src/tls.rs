@@ -0,0 +1,149 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+
+use native_tls::{Certificate, HandshakeError, Identity, Protocol, TlsConnector, TlsStream};
+
+const PEM_CERT_END: &str = "-----END CERTIFICATE-----";
+
+/// Settings of connection to PAMCollector.
+#[derive(Clone, Debug, Default)]
+pub struct TlsConfig {
+    /// Connect without TLS. Allowed only for loopback addresses.
+    pub plaintext: bool,
+    /// Hostname for SNI and certificate verification. Host of collector address by default.
+    pub hostname: Option<String>,
+    /// Path to PEM bundle with CA certificates trusted in addition to system ones.
+    pub ca_file: Option<String>,
+    /// Path to PKCS #12 archive with client certificate and key for mutual TLS.
+    pub client_cert: Option<String>,
+    pub client_cert_password: Option<String>,
+    /// One of "tls1.0", "tls1.1", "tls1.2".
+    pub min_version: Option<String>,
+}
+
+/// How to wrap TCP connection to PAMCollector.
+#[derive(Clone)]
+pub enum Transport {
+    Plaintext,
+    Tls {
+        connector: TlsConnector,
+        hostname: String,
+    },
+}
+
+fn config_err<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err.to_string())
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, io::Error> {
+    let mut buf: Vec<u8> = vec![];
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut buf))
+        .map_err(|e| config_err(format!("Unable to read {}: {}", path, e)))?;
+    Ok(buf)
+}
+
+pub fn parse_protocol(val: &str) -> Result<Protocol, io::Error> {
+    match val {
+        "tls1.0" => Ok(Protocol::Tlsv10),
+        "tls1.1" => Ok(Protocol::Tlsv11),
+        "tls1.2" => Ok(Protocol::Tlsv12),
+        _ => Err(config_err(format!("Unknown TLS version {}", val))),
+    }
+}
+
+/// Host part of `host:port` address.
+fn addr_host(addr: &str) -> &str {
+    let host = match addr.rfind(':') {
+        Some(pos) => &addr[..pos],
+        None => addr,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn is_loopback(addr: &str) -> bool {
+    match addr.to_socket_addrs() {
+        Ok(addrs) => {
+            let addrs: Vec<SocketAddr> = addrs.collect();
+            !addrs.is_empty() && addrs.iter().all(|a| a.ip().is_loopback())
+        }
+        Err(_) => false,
+    }
+}
+
+impl TlsConfig {
+    /// Validate settings and build transport for `addr`. Certificates are loaded here, so
+    /// configuration errors are reported at activation and not on every reconnect.
+    pub fn transport(&self, addr: &str) -> Result<Transport, io::Error> {
+        if self.plaintext {
+            if !is_loopback(addr) {
+                return Err(config_err(format!(
+                    "Plaintext connection is allowed only to localhost, got {}",
+                    addr
+                )));
+            }
+            return Ok(Transport::Plaintext);
+        }
+        let mut builder = TlsConnector::builder();
+        if let Some(ref path) = self.ca_file {
+            let bundle = read_file(path)?;
+            let bundle = String::from_utf8_lossy(&bundle);
+            let mut count: usize = 0;
+            for pem in bundle.split_terminator(PEM_CERT_END) {
+                if pem.trim().is_empty() {
+                    continue;
+                }
+                let pem = format!("{}{}\n", pem.trim(), PEM_CERT_END);
+                let cert = Certificate::from_pem(pem.as_bytes())
+                    .map_err(|e| config_err(format!("Invalid certificate in {}: {}", path, e)))?;
+                builder.add_root_certificate(cert);
+                count += 1;
+            }
+            if count == 0 {
+                return Err(config_err(format!("No certificates found in {}", path)));
+            }
+            info!("Loaded {} CA certificates from {}", count, path);
+        }
+        if let Some(ref path) = self.client_cert {
+            let der = read_file(path)?;
+            let password = self.client_cert_password.clone().unwrap_or_default();
+            let identity = Identity::from_pkcs12(&der, &password)
+                .map_err(|e| config_err(format!("Invalid client certificate {}: {}", path, e)))?;
+            builder.identity(identity);
+        }
+        if let Some(ref val) = self.min_version {
+            builder.min_protocol_version(Some(parse_protocol(val)?));
+        }
+        let connector = builder
+            .build()
+            .map_err(|e| config_err(format!("Unable to build TLS connector: {}", e)))?;
+        let hostname = match self.hostname {
+            Some(ref v) => v.clone(),
+            None => addr_host(addr).to_owned(),
+        };
+        Ok(Transport::Tls {
+            connector,
+            hostname,
+        })
+    }
+}
+
+/// Run TLS handshake over connected TCP stream.
+pub fn handshake(
+    connector: &TlsConnector,
+    hostname: &str,
+    stream: TcpStream,
+) -> Result<TlsStream<TcpStream>, io::Error> {
+    connector.connect(hostname, stream).map_err(|e| {
+        let reason = match e {
+            HandshakeError::Failure(e) => e.to_string(),
+            HandshakeError::WouldBlock(_) => "handshake interrupted".to_owned(),
+        };
+        error!("TLS handshake with {} failed: {}", hostname, reason);
+        io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("TLS handshake with {} failed: {}", hostname, reason),
+        )
+    })
+}
@@ -0,0 +1,110 @@
+use rand;
+
+/// Only version of W3C Trace Context that is generated.
+const VERSION: &str = "00";
+const FLAG_SAMPLED: u8 = 0x01;
+/// Max length of tracestate header, longer value is dropped.
+const MAX_TRACESTATE_LENGTH: usize = 512;
+
+/// Position of transaction in distributed trace, see https://www.w3.org/TR/trace-context/
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceContext {
+    /// 32 lowercase hex chars.
+    pub trace_id: String,
+    /// Span of calling service, None for the first service of trace.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_span_id: Option<String>,
+    pub sampled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tracestate: Option<String>,
+}
+
+fn is_hex(val: &str, len: usize) -> bool {
+    val.len() == len && val.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+fn is_zero(val: &str) -> bool {
+    val.bytes().all(|b| b == b'0')
+}
+
+fn random_id(bytes: usize) -> String {
+    loop {
+        let id: String = (0..bytes)
+            .map(|_| format!("{:02x}", rand::random::<u8>()))
+            .collect();
+        if !is_zero(&id) {
+            return id;
+        }
+    }
+}
+
+pub fn new_trace_id() -> String {
+    random_id(16)
+}
+
+pub fn new_span_id() -> String {
+    random_id(8)
+}
+
+impl TraceContext {
+    /// Context of trace started by this service.
+    pub fn new() -> TraceContext {
+        TraceContext {
+            trace_id: new_trace_id(),
+            parent_span_id: None,
+            sampled: true,
+            tracestate: None,
+        }
+    }
+
+    /// Continue trace of inbound request. Invalid `traceparent` starts new trace, and its
+    /// `tracestate` is dropped.
+    pub fn from_headers(traceparent: Option<&str>, tracestate: Option<&str>) -> TraceContext {
+        let traceparent = match traceparent {
+            Some(v) => v,
+            None => return TraceContext::new(),
+        };
+        match parse_traceparent(traceparent) {
+            Some((trace_id, parent_span_id, flags)) => TraceContext {
+                trace_id,
+                parent_span_id: Some(parent_span_id),
+                sampled: flags & FLAG_SAMPLED != 0,
+                tracestate: tracestate
+                    .map(|v| v.trim())
+                    .filter(|v| !v.is_empty() && v.len() <= MAX_TRACESTATE_LENGTH)
+                    .map(|v| v.to_owned()),
+            },
+            None => {
+                debug!("Invalid traceparent {:?}. New trace is started", traceparent);
+                TraceContext::new()
+            }
+        }
+    }
+
+    /// Value of `traceparent` header for outbound request made by span `span_id`.
+    pub fn traceparent(&self, span_id: &str) -> String {
+        let flags = if self.sampled { FLAG_SAMPLED } else { 0 };
+        format!("{}-{}-{}-{:02x}", VERSION, self.trace_id, span_id, flags)
+    }
+}
+
+/// Parse `traceparent` into trace id, parent span id and flags. Versions after 00 are parsed as
+/// 00 as required by the spec.
+fn parse_traceparent(val: &str) -> Option<(String, String, u8)> {
+    let parts: Vec<&str> = val.trim().split('-').collect();
+    if parts.len() < 4 {
+        return None;
+    }
+    let (version, trace_id, span_id, flags) = (parts[0], parts[1], parts[2], parts[3]);
+    if !is_hex(version, 2) || version == "ff" || (version == VERSION && parts.len() != 4) {
+        return None;
+    }
+    if !is_hex(trace_id, 32) || is_zero(trace_id) || !is_hex(span_id, 16) || is_zero(span_id) {
+        return None;
+    }
+    if !is_hex(flags, 2) {
+        return None;
+    }
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    Some((trace_id.to_owned(), span_id.to_owned(), flags))
+}
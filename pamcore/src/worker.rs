@@ -0,0 +1,288 @@
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use backoff::backoff::Backoff;
+use backoff::ExponentialBackoff;
+
+use output::{BatchConfig, Output, BATCH_CONFIG, OUTPUT_QUEUE, OUTPUT_SIGNAL};
+use error::recover;
+use metrics;
+use reaper;
+
+pub type BoxedOutput = Box<Output + Send>;
+
+/// How long `activate` waits for the previous worker to drain the queue.
+const ACTIVATE_SHUTDOWN_TIMEOUT: u64 = 5;
+
+lazy_static! {
+    static ref WORKER: Mutex<WorkerState> = Mutex::new(WorkerState::Stopped(None));
+}
+
+/// Flags shared between worker thread and lifecycle functions. Changed only with
+/// `OUTPUT_QUEUE` lock held, so waiters on `OUTPUT_SIGNAL` never miss them.
+struct WorkerFlags {
+    shutdown: AtomicBool,
+    running: AtomicBool,
+}
+
+struct WorkerHandle {
+    flags: Arc<WorkerFlags>,
+    thread: JoinHandle<BoxedOutput>,
+}
+
+enum WorkerState {
+    Stopped(Option<BoxedOutput>),
+    Running(WorkerHandle),
+}
+
+pub fn secs_to_duration(secs: f64) -> Duration {
+    if secs <= 0.0 {
+        return Duration::from_secs(0);
+    }
+    Duration::new(secs.trunc() as u64, (secs.fract() * 1e9) as u32)
+}
+
+/// Replace output of worker and start it. Running worker is shut down first.
+pub fn activate(output: BoxedOutput) -> bool {
+    if !shutdown(Duration::from_secs(ACTIVATE_SHUTDOWN_TIMEOUT)) {
+        warn!("Previous output worker is detached");
+    }
+    *recover(WORKER.lock()) = WorkerState::Stopped(Some(output));
+    start()
+}
+
+/// Start worker with activated output. Return false if worker is already running
+/// or there is no output.
+pub fn start() -> bool {
+    let mut worker = recover(WORKER.lock());
+    let output: BoxedOutput = match *worker {
+        WorkerState::Running(_) => {
+            warn!("Output worker is already running");
+            return false;
+        }
+        WorkerState::Stopped(ref mut output) => match output.take() {
+            Some(v) => v,
+            None => {
+                warn!("Output is not activated");
+                return false;
+            }
+        },
+    };
+    let flags = Arc::new(WorkerFlags {
+        shutdown: AtomicBool::new(false),
+        running: AtomicBool::new(true),
+    });
+    let worker_flags = flags.clone();
+    let thread = thread::spawn(move || run(output, &worker_flags));
+    *worker = WorkerState::Running(WorkerHandle { flags, thread });
+    info!("Output worker started");
+    reaper::start();
+    metrics::start();
+    true
+}
+
+/// Wait until every queued transaction is sent. Return false on timeout or if worker is not running.
+pub fn flush(timeout: Duration) -> bool {
+    let flags: Arc<WorkerFlags> = match *recover(WORKER.lock()) {
+        WorkerState::Running(ref handle) => handle.flags.clone(),
+        WorkerState::Stopped(_) => return recover(OUTPUT_QUEUE.lock()).is_drained(),
+    };
+    let deadline = Instant::now() + timeout;
+    let mut queue = recover(OUTPUT_QUEUE.lock());
+    loop {
+        if queue.is_drained() {
+            return true;
+        }
+        if !flags.running.load(Ordering::SeqCst) {
+            return false;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return false;
+        }
+        queue = recover(OUTPUT_SIGNAL.wait_timeout(queue, deadline - now)).0;
+    }
+}
+
+/// Ask worker to send what is left in queue and stop. Return false if worker did not stop in
+/// `timeout`, in that case the worker is detached and its output is dropped when it stops.
+pub fn shutdown(timeout: Duration) -> bool {
+    let running = match *recover(WORKER.lock()) {
+        WorkerState::Running(_) => true,
+        WorkerState::Stopped(_) => false,
+    };
+    // Metrics of the last, partial interval are sent with the rest of queue. They are harvested
+    // before WORKER is locked again, so shutdown does not wait on METRICS while holding it.
+    if running {
+        metrics::harvest();
+    }
+    let mut worker = recover(WORKER.lock());
+    let flags: Arc<WorkerFlags> = match *worker {
+        WorkerState::Running(ref handle) => handle.flags.clone(),
+        WorkerState::Stopped(_) => return true,
+    };
+    {
+        let _queue = recover(OUTPUT_QUEUE.lock());
+        flags.shutdown.store(true, Ordering::SeqCst);
+        OUTPUT_SIGNAL.notify_all();
+    }
+    let deadline = Instant::now() + timeout;
+    let mut queue = recover(OUTPUT_QUEUE.lock());
+    while flags.running.load(Ordering::SeqCst) {
+        let now = Instant::now();
+        if now >= deadline {
+            warn!(
+                "Output worker did not stop in {:?}. {} transactions left in queue",
+                timeout,
+                queue.len()
+            );
+            *worker = WorkerState::Stopped(None);
+            return false;
+        }
+        queue = recover(OUTPUT_SIGNAL.wait_timeout(queue, deadline - now)).0;
+    }
+    drop(queue);
+    if let WorkerState::Running(handle) = mem::replace(&mut *worker, WorkerState::Stopped(None)) {
+        match handle.thread.join() {
+            Ok(output) => *worker = WorkerState::Stopped(Some(output)),
+            Err(_) => error!("Output worker panicked"),
+        }
+    }
+    info!("Output worker stopped");
+    true
+}
+
+/// Mark worker as stopped when `run` returns or panics.
+struct RunningGuard<'a>(&'a WorkerFlags);
+
+impl<'a> Drop for RunningGuard<'a> {
+    fn drop(&mut self) {
+        let queue = recover(OUTPUT_QUEUE.lock());
+        if !queue.is_empty() {
+            warn!("Output worker stopped with {} transactions left in queue", queue.len());
+        }
+        self.0.running.store(false, Ordering::SeqCst);
+        OUTPUT_SIGNAL.notify_all();
+    }
+}
+
+fn run(mut output: BoxedOutput, flags: &WorkerFlags) -> BoxedOutput {
+    info!("Consume event output loop started");
+    let _running = RunningGuard(flags);
+    let mut backoff = ExponentialBackoff {
+        max_elapsed_time: None,
+        ..Default::default()
+    };
+    let mut opened: bool = false;
+    loop {
+        if !opened {
+            trace!("{} need to reopen", output.name());
+            match output.open() {
+                Ok(_) => {
+                    opened = true;
+                    backoff.reset();
+                }
+                Err(e) => {
+                    warn!("Error while opening {}. Error: {}", output.name(), e);
+                    if flags.shutdown.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let wait = backoff.next_backoff().unwrap_or(backoff.max_interval);
+                    wait_for_shutdown(flags, wait);
+                    continue;
+                }
+            }
+        }
+        let config: BatchConfig = *recover(BATCH_CONFIG.read());
+        let mut batch: Vec<String> = match collect_batch(flags, &config) {
+            Some(v) => v,
+            None => break,
+        };
+        debug!("Batch size is {}", batch.len());
+        match output.send_batch(&mut batch) {
+            Ok(_) => finish_batch(None),
+            Err(e) => {
+                error!("Error while send batch to {}. Error: {}", output.name(), e);
+                finish_batch(Some(batch));
+                opened = false;
+                if flags.shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+            }
+        }
+    }
+    output
+}
+
+fn wait_for_shutdown(flags: &WorkerFlags, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    let mut queue = recover(OUTPUT_QUEUE.lock());
+    while !flags.shutdown.load(Ordering::SeqCst) {
+        let now = Instant::now();
+        if now >= deadline {
+            return;
+        }
+        queue = recover(OUTPUT_SIGNAL.wait_timeout(queue, deadline - now)).0;
+    }
+}
+
+/// Take batch limited by `config` from `OUTPUT_QUEUE`. Sleep until the first transaction is
+/// queued, then wait at most `config.max_delay` for the batch to fill up. Taken transactions are
+/// in flight from the moment they leave the queue, so `flush` waits for them. On shutdown the
+/// batch is returned at once. Return None on shutdown with empty queue.
+fn collect_batch(flags: &WorkerFlags, config: &BatchConfig) -> Option<Vec<String>> {
+    let max_items = config.max_items.max(1);
+    let mut batch: Vec<String> = vec![];
+    let mut bytes: usize = 0;
+    let mut deadline: Option<Instant> = None;
+    let mut queue = recover(OUTPUT_QUEUE.lock());
+    loop {
+        let mut full: bool = batch.len() >= max_items;
+        while !full {
+            let size = match queue.front() {
+                Some(v) => v.len(),
+                None => break,
+            };
+            if !batch.is_empty() && bytes + size > config.max_bytes {
+                full = true;
+                break;
+            }
+            bytes += size;
+            batch.push(queue.pop_front().unwrap());
+            queue.begin_batch(batch.len());
+            full = batch.len() >= max_items;
+        }
+        if full || flags.shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        if batch.is_empty() {
+            trace!("Not val");
+            queue = recover(OUTPUT_SIGNAL.wait(queue));
+            continue;
+        }
+        let batch_deadline = *deadline.get_or_insert_with(|| Instant::now() + config.max_delay);
+        let now = Instant::now();
+        if now >= batch_deadline {
+            break;
+        }
+        queue = recover(OUTPUT_SIGNAL.wait_timeout(queue, batch_deadline - now)).0;
+    }
+    if batch.is_empty() {
+        return None;
+    }
+    Some(batch)
+}
+
+fn finish_batch(failed: Option<Vec<String>>) {
+    let mut queue = recover(OUTPUT_QUEUE.lock());
+    if let Some(batch) = failed {
+        for v in batch.into_iter().rev() {
+            queue.push_front(v);
+        }
+    }
+    queue.end_batch();
+    OUTPUT_SIGNAL.notify_all();
+}
@@ -0,0 +1,244 @@
+use std::io;
+use std::net::IpAddr;
+
+use serde_json::{self, Map, Value};
+
+use error::recover;
+use export::{self, db_system, port_field, str_field, walk_nodes};
+use http::{check_status, HttpClient};
+use output::{Compression, Output, BATCH_CONFIG};
+
+/// Export transactions as Zipkin v2 JSON spans, e.g. to `http://localhost:9411/api/v2/spans` of
+/// Zipkin or of Jaeger collector with Zipkin endpoint enabled.
+pub struct ZipkinOutput {
+    client: HttpClient,
+    service_name: String,
+}
+
+impl ZipkinOutput {
+    pub fn new(client: HttpClient, service_name: String) -> ZipkinOutput {
+        ZipkinOutput {
+            client,
+            service_name,
+        }
+    }
+}
+
+impl Output for ZipkinOutput {
+    fn name(&self) -> &str {
+        "ZipkinOutput"
+    }
+
+    fn open(&mut self) -> Result<(), io::Error> {
+        self.client.connect()
+    }
+
+    fn send_batch(&mut self, batch: &mut Vec<String>) -> Result<(), io::Error> {
+        let mut spans: Vec<Value> = vec![];
+        for payload in batch.iter() {
+            match serde_json::from_str::<Value>(payload) {
+                Ok(ref v) if export::is_metrics(v) => {}
+                Ok(tr) => transaction_to_spans(&tr, &self.service_name, &mut spans),
+                Err(e) => error!("Unable to parse transaction for {}: {}", self.name(), e),
+            }
+        }
+        if spans.is_empty() {
+            return Ok(());
+        }
+        let body = serde_json::to_vec(&spans)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        // Zipkin accepts only gzip encoded bodies
+        let compression = match recover(BATCH_CONFIG.read()).compression {
+            Compression::Gzip => Compression::Gzip,
+            _ => Compression::None,
+        };
+        let body = compression.compress(body)?;
+        let encoding = compression.content_encoding();
+        let status = self.client.post("application/json", encoding, &body)?;
+        check_status(self.client.url(), status)
+    }
+}
+
+fn time_micros(node: &Value, key: &str) -> u64 {
+    let secs = node.get(key).and_then(|v| v.as_f64()).unwrap_or(0.0);
+    (secs.max(0.0) * 1e6) as u64
+}
+
+/// Tags are string values in Zipkin, empty values are skipped.
+struct Tags(Map<String, Value>);
+
+impl Tags {
+    fn add(&mut self, key: &str, value: &str) {
+        if !value.is_empty() {
+            self.0.insert(key.to_owned(), json!(value));
+        }
+    }
+
+    fn add_custom(&mut self, node: &Value) {
+        if let Some(attributes) = node.get("attributes").and_then(|v| v.as_object()) {
+            for (key, value) in attributes {
+                let value = match *value {
+                    Value::String(ref v) => v.clone(),
+                    ref v => v.to_string(),
+                };
+                self.0.insert(key.clone(), json!(value));
+            }
+        }
+    }
+
+    fn add_error(&mut self, error: &Value) {
+        let class = str_field(error, "class");
+        let message = str_field(error, "message");
+        let value = match message {
+            "" => class.to_owned(),
+            m => m.to_owned(),
+        };
+        self.0.insert("error".to_owned(), json!(value));
+        self.add("exception.type", class);
+    }
+}
+
+/// Remote endpoint of external, database or cache call. Host is IP address or service name.
+fn remote_endpoint(node: &Value) -> Option<Value> {
+    let host = str_field(node, "host");
+    let port = port_field(node);
+    if host.is_empty() && port.is_none() {
+        return None;
+    }
+    let mut endpoint = Map::new();
+    match host.trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => endpoint.insert("ipv4".to_owned(), json!(ip.to_string())),
+        Ok(IpAddr::V6(ip)) => endpoint.insert("ipv6".to_owned(), json!(ip.to_string())),
+        Err(_) if !host.is_empty() => {
+            endpoint.insert("serviceName".to_owned(), json!(host.to_lowercase()))
+        }
+        Err(_) => None,
+    };
+    if let Some(port) = port {
+        endpoint.insert("port".to_owned(), json!(port));
+    }
+    Some(Value::Object(endpoint))
+}
+
+/// Name, kind and tags of span for trace node. Kind is None for local spans.
+fn describe_node(node: &Value) -> (String, Option<&'static str>, Tags) {
+    let mut tags = Tags(Map::new());
+    let port = port_field(node).map(|v| v.to_string()).unwrap_or_default();
+    match str_field(node, "type") {
+        "External" => {
+            let method = str_field(node, "method").to_uppercase();
+            tags.add("http.method", &method);
+            tags.add("http.path", str_field(node, "path"));
+            tags.add("peer.hostname", str_field(node, "host"));
+            tags.add("peer.port", &port);
+            tags.add("pamagent.library", str_field(node, "library"));
+            let name = format!("{} {}", method, str_field(node, "host"));
+            (name.trim().to_owned(), Some("CLIENT"), tags)
+        }
+        "Database" => {
+            let operation = str_field(node, "operation");
+            let target = str_field(node, "target");
+            tags.add("db.type", &db_system(str_field(node, "database_product")));
+            tags.add("db.instance", str_field(node, "database_name"));
+            tags.add("db.operation", operation);
+            tags.add("db.table", target);
+            tags.add("sql.query", str_field(node, "sql"));
+            tags.add("peer.hostname", str_field(node, "host"));
+            tags.add("peer.port", &port);
+            let name = match (operation, target) {
+                ("", _) => str_field(node, "database_product").to_owned(),
+                (op, "") => op.to_owned(),
+                (op, t) => format!("{} {}", op, t),
+            };
+            (name, Some("CLIENT"), tags)
+        }
+        "Cache" => {
+            let operation = str_field(node, "operation");
+            let system = db_system(str_field(node, "database_product"));
+            tags.add("db.type", &system);
+            tags.add("db.instance", str_field(node, "database_name"));
+            tags.add("db.operation", operation);
+            tags.add("peer.hostname", str_field(node, "host"));
+            tags.add("peer.port", &port);
+            (format!("{} {}", system, operation).trim().to_owned(), Some("CLIENT"), tags)
+        }
+        _ => {
+            let func_name = str_field(node, "func_name");
+            tags.add("code.function", func_name);
+            (func_name.to_owned(), None, tags)
+        }
+    }
+}
+
+fn node_to_span(
+    trace_id: &str,
+    service_name: &str,
+    parent_span_id: Option<&str>,
+    node: &Value,
+) -> Option<Value> {
+    let span_id = str_field(node, "span_id");
+    if span_id.is_empty() {
+        return None;
+    }
+    let (name, kind, mut tags) = describe_node(node);
+    tags.add_custom(node);
+    if let Some(error) = node.get("error") {
+        tags.add_error(error);
+    }
+    let timestamp = time_micros(node, "start_time");
+    let duration = time_micros(node, "end_time").saturating_sub(timestamp).max(1);
+    let mut span = Map::new();
+    span.insert("traceId".to_owned(), json!(trace_id));
+    span.insert("id".to_owned(), json!(span_id));
+    if let Some(v) = parent_span_id {
+        span.insert("parentId".to_owned(), json!(v));
+    }
+    span.insert("name".to_owned(), json!(name.to_lowercase()));
+    if let Some(kind) = kind {
+        span.insert("kind".to_owned(), json!(kind));
+        if let Some(endpoint) = remote_endpoint(node) {
+            span.insert("remoteEndpoint".to_owned(), endpoint);
+        }
+    }
+    span.insert("timestamp".to_owned(), json!(timestamp));
+    span.insert("duration".to_owned(), json!(duration));
+    span.insert("localEndpoint".to_owned(), json!({ "serviceName": service_name }));
+    span.insert("tags".to_owned(), Value::Object(tags.0));
+    Some(Value::Object(span))
+}
+
+/// Flatten dump of finished transaction into Zipkin v2 spans. Root node becomes the server span
+/// with transaction name, path, custom attributes and error.
+pub fn transaction_to_spans(tr: &Value, service_name: &str, spans: &mut Vec<Value>) {
+    let trace_id = export::trace_id(tr);
+    if trace_id.is_empty() {
+        warn!("Transaction without trace id is not exported");
+        return;
+    }
+    let first = spans.len();
+    walk_nodes(tr, |node, parent| {
+        if let Some(span) = node_to_span(trace_id, service_name, parent, node) {
+            spans.push(span);
+        }
+    });
+    if let Some(&mut Value::Object(ref mut root)) = spans.get_mut(first) {
+        let base_name = str_field(tr, "base_name");
+        root.insert("kind".to_owned(), json!("SERVER"));
+        root.remove("remoteEndpoint");
+        if !base_name.is_empty() {
+            root.insert("name".to_owned(), json!(base_name.to_lowercase()));
+        }
+        if let Some(&mut Value::Object(ref mut root_tags)) = root.get_mut("tags") {
+            let mut tags = Tags(Map::new());
+            tags.add("http.path", str_field(tr, "path"));
+            tags.add("pamagent.guid", str_field(tr, "guid"));
+            tags.add_custom(tr);
+            if let Some(error) = tr.get("error") {
+                tags.add_error(error);
+            } else if tr.get("errored").and_then(|v| v.as_bool()) == Some(true) {
+                tags.add("error", "true");
+            }
+            root_tags.extend(tags.0);
+        }
+    }
+}
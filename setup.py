@@ -64,7 +64,8 @@ setup(
         'test': Tox,
     },
     platforms='Posix; MacOS X; Windows',
-    rust_extensions=[RustExtension('pamagent.pamagent_core', 'pamcore/Cargo.toml', binding=Binding.PyO3)],
+    rust_extensions=[RustExtension('pamagent.pamagent_core', 'pamcore/Cargo.toml', binding=Binding.PyO3,
+                                   features=['bench'] if os.environ.get('PAMAGENT_BENCH') else [])],
     packages=['pamagent', 'pamagent.hooks', 'pamagent.utils'],
     install_requires=read('requirements.txt').splitlines(),
     zip_safe=False,